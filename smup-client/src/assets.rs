@@ -0,0 +1,548 @@
+extern crate specs;
+
+use crate::audio::{AudioConfig, SfxTable};
+use crate::boss::BossDef;
+use crate::item::ItemConfig;
+use crate::menu::Menu;
+use crate::prefab::Prefabs;
+use crate::scene::{Scene, SceneControl, Transition};
+use crate::score::ScoreConfig;
+use image::RgbaImage;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use specs::prelude::*;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+/// Why an asset could not be loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssetError {
+    Missing(PathBuf),
+    Unreadable(PathBuf, String),
+    /// The file is there but does not hold what it should.
+    Corrupt(PathBuf, String),
+}
+
+impl fmt::Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssetError::Missing(path) => write!(f, "{}: no such file", path.display()),
+            AssetError::Unreadable(path, err) => {
+                write!(f, "{}: could not be read: {}", path.display(), err)
+            }
+            AssetError::Corrupt(path, err) => write!(f, "{}: {}", path.display(), err),
+        }
+    }
+}
+
+impl Error for AssetError {}
+
+/// Something loaded from a file.
+pub trait Asset: Sized + Send + Sync + 'static {
+    /// Makes the asset out of the whole file.
+    fn decode(bytes: Vec<u8>) -> Result<Self, Box<dyn Error>>;
+}
+
+fn from_ron<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Box<dyn Error>> {
+    Ok(ron::de::from_bytes(bytes)?)
+}
+
+/// A TrueType or OpenType font, made into glyphs by the renderer.
+#[derive(Debug)]
+pub struct FontData {
+    pub bytes: Vec<u8>,
+}
+
+impl Asset for FontData {
+    fn decode(bytes: Vec<u8>) -> Result<FontData, Box<dyn Error>> {
+        let magic = bytes.get(..4).unwrap_or(&[]);
+        if ![&[0, 1, 0, 0][..], b"OTTO", b"true", b"ttcf"].contains(&magic) {
+            return Err("not a TrueType or OpenType font".into());
+        }
+        Ok(FontData { bytes })
+    }
+}
+
+/// An encoded sound, decoded by the audio backend as it plays.
+#[derive(Debug)]
+pub struct SoundData {
+    pub bytes: Arc<[u8]>,
+}
+
+impl Asset for SoundData {
+    fn decode(bytes: Vec<u8>) -> Result<SoundData, Box<dyn Error>> {
+        let wav = bytes.get(..4) == Some(b"RIFF") && bytes.get(8..12) == Some(b"WAVE");
+        if !wav && bytes.get(..4) != Some(b"OggS") {
+            return Err("not a WAV or Ogg Vorbis file".into());
+        }
+        Ok(SoundData {
+            bytes: bytes.into(),
+        })
+    }
+}
+
+/// The pixels of a PNG, made into a texture by the renderer.
+#[derive(Debug)]
+pub struct ImageData {
+    pub image: RgbaImage,
+}
+
+impl Asset for ImageData {
+    fn decode(bytes: Vec<u8>) -> Result<ImageData, Box<dyn Error>> {
+        let image = image::load_from_memory(&bytes)?.to_rgba8();
+        Ok(ImageData { image })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+/// Named parts of an image, for sprites sharing one texture.
+#[derive(Debug, Deserialize)]
+pub struct Atlas {
+    pub image: PathBuf,
+    pub regions: HashMap<String, Region>,
+}
+
+impl Asset for Atlas {
+    fn decode(bytes: Vec<u8>) -> Result<Atlas, Box<dyn Error>> {
+        from_ron(&bytes)
+    }
+}
+
+impl Asset for BossDef {
+    fn decode(bytes: Vec<u8>) -> Result<BossDef, Box<dyn Error>> {
+        BossDef::parse(std::str::from_utf8(&bytes)?)
+    }
+}
+
+impl Asset for ItemConfig {
+    fn decode(bytes: Vec<u8>) -> Result<ItemConfig, Box<dyn Error>> {
+        from_ron(&bytes)
+    }
+}
+
+impl Asset for Prefabs {
+    fn decode(bytes: Vec<u8>) -> Result<Prefabs, Box<dyn Error>> {
+        Prefabs::parse(std::str::from_utf8(&bytes)?)
+    }
+}
+
+impl Asset for ScoreConfig {
+    fn decode(bytes: Vec<u8>) -> Result<ScoreConfig, Box<dyn Error>> {
+        from_ron(&bytes)
+    }
+}
+
+impl Asset for AudioConfig {
+    fn decode(bytes: Vec<u8>) -> Result<AudioConfig, Box<dyn Error>> {
+        from_ron(&bytes)
+    }
+}
+
+impl Asset for SfxTable {
+    fn decode(bytes: Vec<u8>) -> Result<SfxTable, Box<dyn Error>> {
+        from_ron(&bytes)
+    }
+}
+
+fn read<T: Asset>(path: &Path) -> Result<T, AssetError> {
+    let bytes = fs::read(path).map_err(|err| match err.kind() {
+        ErrorKind::NotFound => AssetError::Missing(path.to_path_buf()),
+        _ => AssetError::Unreadable(path.to_path_buf(), err.to_string()),
+    })?;
+    T::decode(bytes).map_err(|err| AssetError::Corrupt(path.to_path_buf(), err.to_string()))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Loading,
+    Loaded,
+    Failed,
+}
+
+struct State<T> {
+    asset: Option<Arc<T>>,
+    /// Why the last load failed, cleared by the next one that works.
+    error: Option<AssetError>,
+    loading: bool,
+    /// Loads that worked, so users can tell when the asset was reloaded.
+    version: u32,
+    /// When the file was last changed, as of the last load.
+    modified: Option<SystemTime>,
+}
+
+/// Where a loaded asset lands, shared by the cache and its handles.
+struct Slot<T> {
+    path: PathBuf,
+    state: Mutex<State<T>>,
+    done: Condvar,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl<T: Asset> Slot<T> {
+    /// Reads the file into the slot. A load that fails keeps the asset of
+    /// the last one that worked.
+    fn load(&self) {
+        let changed = modified(&self.path);
+        let result = read::<T>(&self.path);
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(asset) => {
+                state.asset = Some(Arc::new(asset));
+                state.error = None;
+                state.version += 1;
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                state.error = Some(err);
+            }
+        }
+        state.modified = changed;
+        state.loading = false;
+        self.done.notify_all();
+    }
+}
+
+/// A cache entry, whatever its type.
+trait Entry: Send + Sync {
+    fn status(&self) -> Status;
+    fn error(&self) -> Option<AssetError>;
+    /// Whether the file changed since it was last loaded.
+    fn changed(&self) -> bool;
+    /// Sets the entry loading and returns what loads it.
+    fn loader(self: Arc<Self>) -> Job;
+    fn any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+}
+
+impl<T: Asset> Entry for Slot<T> {
+    fn status(&self) -> Status {
+        let state = self.state.lock().unwrap();
+        match (&state.asset, state.loading) {
+            (Some(_), _) => Status::Loaded,
+            (None, true) => Status::Loading,
+            (None, false) => Status::Failed,
+        }
+    }
+
+    fn error(&self) -> Option<AssetError> {
+        self.state.lock().unwrap().error.clone()
+    }
+
+    fn changed(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        !state.loading && modified(&self.path) != state.modified
+    }
+
+    fn loader(self: Arc<Self>) -> Job {
+        self.state.lock().unwrap().loading = true;
+        Box::new(move || self.load())
+    }
+
+    fn any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+/// An asset that is loaded, or will be. Clones share the asset, which
+/// stays cached for as long as one of them is around.
+pub struct Handle<T> {
+    slot: Arc<Slot<T>>,
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Handle<T> {
+        Handle {
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+impl<T: Asset> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Handle({}, {:?})", self.path().display(), self.status())
+    }
+}
+
+impl<T: Asset> Handle<T> {
+    pub fn path(&self) -> &Path {
+        &self.slot.path
+    }
+
+    pub fn status(&self) -> Status {
+        self.slot.status()
+    }
+
+    /// The asset, once loaded.
+    pub fn get(&self) -> Option<Arc<T>> {
+        self.slot.state.lock().unwrap().asset.clone()
+    }
+
+    /// Why the last load failed, if it did.
+    pub fn error(&self) -> Option<AssetError> {
+        self.slot.error()
+    }
+
+    /// Goes up every time the asset is loaded again.
+    pub fn version(&self) -> u32 {
+        self.slot.state.lock().unwrap().version
+    }
+
+    /// Blocks until the asset is loaded or failed to.
+    pub fn wait(&self) -> Result<Arc<T>, AssetError> {
+        let mut state = self.slot.state.lock().unwrap();
+        while state.loading {
+            state = self.slot.done.wait(state).unwrap();
+        }
+        match (&state.asset, &state.error) {
+            (Some(asset), _) => Ok(asset.clone()),
+            (None, Some(err)) => Err(err.clone()),
+            (None, None) => unreachable!("a load either works or fails"),
+        }
+    }
+}
+
+/// How far the assets asked for are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub loaded: usize,
+    pub failed: usize,
+    pub total: usize,
+}
+
+impl Progress {
+    pub fn done(&self) -> bool {
+        self.loaded + self.failed == self.total
+    }
+
+    /// Share of the assets done, from 0 to 1.
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 {
+            return 1.;
+        }
+        (self.loaded + self.failed) as f32 / self.total as f32
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Loads assets by path and keeps them cached. The default one loads
+/// right away on the thread asking, `Assets::background` in a thread of
+/// its own.
+#[derive(Default)]
+pub struct Assets {
+    entries: HashMap<(TypeId, PathBuf), Arc<dyn Entry>>,
+    jobs: Option<Sender<Job>>,
+}
+
+impl Assets {
+    pub fn background() -> Assets {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        thread::Builder::new()
+            .name("assets".to_string())
+            .spawn(move || {
+                for job in queue {
+                    job();
+                }
+            })
+            .expect("could not start the asset loader");
+        Assets {
+            entries: HashMap::new(),
+            jobs: Some(jobs),
+        }
+    }
+
+    /// Starts loading `path` unless it is already cached.
+    pub fn load<T: Asset>(&mut self, path: impl AsRef<Path>) -> Handle<T> {
+        let path = path.as_ref().to_path_buf();
+        let key = (TypeId::of::<T>(), path.clone());
+        if let Some(entry) = self.entries.get(&key) {
+            let slot = entry.clone().any().downcast::<Slot<T>>();
+            return Handle {
+                slot: slot.expect("cached under its own type"),
+            };
+        }
+        let slot = Arc::new(Slot {
+            path,
+            state: Mutex::new(State {
+                asset: None,
+                error: None,
+                loading: true,
+                version: 0,
+                modified: None,
+            }),
+            done: Condvar::new(),
+        });
+        self.entries.insert(key, slot.clone());
+        self.run(slot.clone().loader());
+        Handle { slot }
+    }
+
+    fn run(&self, job: Job) {
+        match &self.jobs {
+            Some(jobs) => {
+                if let Err(mpsc::SendError(job)) = jobs.send(job) {
+                    // the loader is gone, load here instead
+                    job();
+                }
+            }
+            None => job(),
+        }
+    }
+
+    /// Loads again the files among `paths` changed since they were last
+    /// loaded and returns them. Only those files are looked at.
+    pub fn reload_changed(&mut self, paths: &[PathBuf]) -> Vec<PathBuf> {
+        let changed: Vec<(PathBuf, Arc<dyn Entry>)> = self
+            .entries
+            .iter()
+            .filter(|((_, path), entry)| paths.contains(path) && entry.changed())
+            .map(|((_, path), entry)| (path.clone(), entry.clone()))
+            .collect();
+        changed
+            .into_iter()
+            .map(|(path, entry)| {
+                self.run(entry.loader());
+                path
+            })
+            .collect()
+    }
+
+    /// Assets asked for since they were last dropped.
+    pub fn progress(&self) -> Progress {
+        let mut progress = Progress {
+            total: self.entries.len(),
+            ..Default::default()
+        };
+        for entry in self.entries.values() {
+            match entry.status() {
+                Status::Loading => {}
+                Status::Loaded => progress.loaded += 1,
+                Status::Failed => progress.failed += 1,
+            }
+        }
+        progress
+    }
+
+    /// Every asset that failed to load.
+    pub fn errors(&self) -> Vec<AssetError> {
+        self.entries.values().filter_map(|e| e.error()).collect()
+    }
+
+    /// Drops the cached assets no handle uses anymore, returning how many.
+    pub fn collect(&mut self) -> usize {
+        let before = self.entries.len();
+        self.entries
+            .retain(|_, entry| entry.status() == Status::Loading || Arc::strong_count(entry) > 1);
+        before - self.entries.len()
+    }
+}
+
+/// Shows how far the assets are and moves on to the title once they are
+/// all done, loaded or not.
+pub struct LoadingSystem {}
+
+impl<'a> System<'a> for LoadingSystem {
+    type SystemData = (Read<'a, Assets>, Write<'a, Menu>, Write<'a, SceneControl>);
+
+    fn run(&mut self, (assets, mut menu, mut ctl): Self::SystemData) {
+        let progress = assets.progress();
+        menu.title = "Loading".to_string();
+        menu.lines = vec![format!(
+            "{} of {} ({:.0}%)",
+            progress.loaded + progress.failed,
+            progress.total,
+            progress.fraction() * 100.
+        )];
+        if progress.done() {
+            ctl.fade_to(Transition::Replace(Scene::Title));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("smup-assets-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn missing_file_fails_as_missing() {
+        let mut assets = Assets::default();
+        let handle = assets.load::<ScoreConfig>("assets/no-such-file.ron");
+        assert_eq!(
+            handle.wait().unwrap_err(),
+            AssetError::Missing(PathBuf::from("assets/no-such-file.ron"))
+        );
+        assert_eq!(handle.status(), Status::Failed);
+        assert_eq!(assets.errors().len(), 1);
+    }
+
+    #[test]
+    fn bad_ron_fails_as_corrupt() {
+        let dir = temp_dir("corrupt");
+        let path = dir.join("score.ron");
+        fs::write(&path, "(chain_window: ").unwrap();
+        let mut assets = Assets::default();
+        let handle = assets.load::<ScoreConfig>(&path);
+        match handle.wait() {
+            Err(AssetError::Corrupt(at, _)) => assert_eq!(at, path),
+            other => panic!("loaded as {:?}", other.map(|_| ())),
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_reload_keeps_the_last_good_asset() {
+        let dir = temp_dir("reload");
+        let path = dir.join("score.ron");
+        fs::copy("assets/score.ron", &path).unwrap();
+        let mut assets = Assets::default();
+        let handle = assets.load::<ScoreConfig>(&path);
+        let good = handle.wait().unwrap();
+        assert_eq!(handle.version(), 1);
+
+        // past the modification time the file was loaded at
+        std::thread::sleep(Duration::from_millis(1100));
+        fs::write(&path, "not ron").unwrap();
+        let paths = vec![path.clone()];
+        assert_eq!(assets.reload_changed(&paths), paths);
+        assert!(Arc::ptr_eq(&handle.get().unwrap(), &good));
+        assert_eq!(handle.version(), 1);
+        assert_eq!(handle.status(), Status::Loaded);
+        assert!(matches!(handle.error(), Some(AssetError::Corrupt(..))));
+        assert_eq!(assets.errors(), vec![handle.error().unwrap()]);
+        // nothing changed since the failed load
+        assert!(assets.reload_changed(&paths).is_empty());
+
+        std::thread::sleep(Duration::from_millis(1100));
+        fs::copy("assets/score.ron", &path).unwrap();
+        assets.reload_changed(&paths);
+        assert_eq!(handle.version(), 2);
+        assert!(handle.error().is_none());
+        assert!(assets.errors().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
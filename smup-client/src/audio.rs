@@ -0,0 +1,651 @@
+extern crate specs;
+
+use crate::assets::{Assets, Handle, SoundData};
+use crate::boss::Boss;
+use crate::scene::{Scene, SceneControl};
+use crate::settings::Settings;
+use crate::types::{GameState, PlayField, Position};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use specs::shrev::{EventChannel, ReaderId};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// A sound file loaded by a backend.
+pub type ClipId = usize;
+/// One playing instance of a clip.
+pub type VoiceId = usize;
+
+/// Plays clips. Volumes go from 0 to 1 and pan from -1, fully left, to 1,
+/// fully right.
+pub trait AudioBackend {
+    /// Makes `sound`, loaded from `path`, ready to play.
+    fn load(&mut self, path: &Path, sound: &SoundData) -> Result<ClipId, Box<dyn Error>>;
+    /// Starts `clip`, returning nothing when it could not be played.
+    fn play(&mut self, clip: ClipId, looping: bool, volume: f32, pan: f32) -> Option<VoiceId>;
+    fn set_volume(&mut self, voice: VoiceId, volume: f32);
+    fn stop(&mut self, voice: VoiceId);
+    fn is_playing(&mut self, voice: VoiceId) -> bool;
+}
+
+/// A voice started on `NullAudio`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NullVoice {
+    pub clip: ClipId,
+    pub looping: bool,
+    pub volume: f32,
+    pub pan: f32,
+}
+
+/// Plays nothing, for headless runs, tests and machines without sound.
+/// Loads always succeed and voices keep playing until stopped, so it can be
+/// checked what would be heard.
+#[derive(Debug, Default)]
+pub struct NullAudio {
+    pub clips: Vec<PathBuf>,
+    pub voices: HashMap<VoiceId, NullVoice>,
+    next: VoiceId,
+}
+
+impl AudioBackend for NullAudio {
+    fn load(&mut self, path: &Path, _sound: &SoundData) -> Result<ClipId, Box<dyn Error>> {
+        self.clips.push(path.to_path_buf());
+        Ok(self.clips.len() - 1)
+    }
+
+    fn play(&mut self, clip: ClipId, looping: bool, volume: f32, pan: f32) -> Option<VoiceId> {
+        self.next += 1;
+        let voice = NullVoice {
+            clip,
+            looping,
+            volume,
+            pan,
+        };
+        self.voices.insert(self.next, voice);
+        Some(self.next)
+    }
+
+    fn set_volume(&mut self, voice: VoiceId, volume: f32) {
+        if let Some(voice) = self.voices.get_mut(&voice) {
+            voice.volume = volume;
+        }
+    }
+
+    fn stop(&mut self, voice: VoiceId) {
+        self.voices.remove(&voice);
+    }
+
+    fn is_playing(&mut self, voice: VoiceId) -> bool {
+        self.voices.contains_key(&voice)
+    }
+}
+
+#[cfg(feature = "audio")]
+mod native {
+    use super::{AudioBackend, ClipId, VoiceId};
+    use crate::assets::SoundData;
+    use rodio::source::{ChannelVolume, Source};
+    use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink};
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::f32::consts::FRAC_PI_4;
+    use std::io::Cursor;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// The sound card, through rodio.
+    pub struct RodioBackend {
+        // kept alive for as long as sound plays
+        _stream: OutputStream,
+        handle: OutputStreamHandle,
+        /// Sounds as loaded, decoded again for every voice.
+        clips: Vec<Arc<[u8]>>,
+        voices: HashMap<VoiceId, Sink>,
+        next: VoiceId,
+    }
+
+    impl RodioBackend {
+        pub fn new() -> Result<RodioBackend, Box<dyn Error>> {
+            let (stream, handle) = OutputStream::try_default()?;
+            Ok(RodioBackend {
+                _stream: stream,
+                handle,
+                clips: Vec::new(),
+                voices: HashMap::new(),
+                next: 0,
+            })
+        }
+    }
+
+    impl AudioBackend for RodioBackend {
+        fn load(&mut self, _path: &Path, sound: &SoundData) -> Result<ClipId, Box<dyn Error>> {
+            let bytes = sound.bytes.clone();
+            // fail now rather than on every play
+            Decoder::new(Cursor::new(bytes.clone()))?;
+            self.clips.push(bytes);
+            Ok(self.clips.len() - 1)
+        }
+
+        fn play(&mut self, clip: ClipId, looping: bool, volume: f32, pan: f32) -> Option<VoiceId> {
+            let bytes = self.clips.get(clip)?.clone();
+            let source = Decoder::new(Cursor::new(bytes)).ok()?;
+            let sink = Sink::try_new(&self.handle).ok()?;
+            sink.set_volume(volume);
+            if looping {
+                sink.append(source.buffered().repeat_infinite());
+            } else {
+                // equal power, so a centered sound is as loud as a panned one
+                let angle = (pan.clamp(-1., 1.) + 1.) * FRAC_PI_4;
+                sink.append(ChannelVolume::new(source, vec![angle.cos(), angle.sin()]));
+            }
+            self.next += 1;
+            self.voices.insert(self.next, sink);
+            Some(self.next)
+        }
+
+        fn set_volume(&mut self, voice: VoiceId, volume: f32) {
+            if let Some(sink) = self.voices.get(&voice) {
+                sink.set_volume(volume);
+            }
+        }
+
+        fn stop(&mut self, voice: VoiceId) {
+            if let Some(sink) = self.voices.remove(&voice) {
+                sink.stop();
+            }
+        }
+
+        fn is_playing(&mut self, voice: VoiceId) -> bool {
+            let done = self.voices.get(&voice).map(|sink| sink.empty());
+            if done == Some(true) {
+                self.voices.remove(&voice);
+            }
+            done == Some(false)
+        }
+    }
+}
+
+#[cfg(feature = "audio")]
+pub use native::RodioBackend;
+
+/// The sound card of this machine: rodio when built with the `audio`
+/// feature, nothing otherwise or when there is no output device.
+pub fn default_backend() -> Box<dyn AudioBackend> {
+    #[cfg(feature = "audio")]
+    match RodioBackend::new() {
+        Ok(backend) => return Box::new(backend),
+        Err(err) => eprintln!("no sound: {}", err),
+    }
+    Box::new(NullAudio::default())
+}
+
+/// Volume channels, from 0 to 1. Music and sound effects are both scaled
+/// by the master volume.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Volumes {
+    pub master: f32,
+    pub music: f32,
+    pub sfx: f32,
+}
+
+impl Default for Volumes {
+    fn default() -> Volumes {
+        Volumes {
+            master: 0.8,
+            music: 0.7,
+            sfx: 0.8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Master,
+    Music,
+    Sfx,
+}
+
+impl Volumes {
+    pub fn get(&self, channel: Channel) -> f32 {
+        match channel {
+            Channel::Master => self.master,
+            Channel::Music => self.music,
+            Channel::Sfx => self.sfx,
+        }
+    }
+
+    pub fn set(&mut self, channel: Channel, volume: f32) {
+        let volume = volume.clamp(0., 1.);
+        match channel {
+            Channel::Master => self.master = volume,
+            Channel::Music => self.music = volume,
+            Channel::Sfx => self.sfx = volume,
+        }
+    }
+}
+
+/// A sound effect and how it plays.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SoundDef {
+    pub file: PathBuf,
+    /// Most copies of the sound heard at once. Playing one more cuts the
+    /// oldest.
+    pub voices: usize,
+    pub volume: f32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AudioConfig {
+    /// Seconds for one music track to fade into the next.
+    pub crossfade: f64,
+    /// Music files by track name.
+    pub music: HashMap<String, PathBuf>,
+    /// Boss phase from which the boss track replaces the stage track.
+    pub boss_phase: usize,
+}
+
+/// Sounds the game makes, named after what makes them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+pub enum Sfx {
+    PlayerShot,
+    EnemyShot,
+    /// A shot hitting an enemy.
+    Hit,
+    EnemyDeath,
+    /// A life lost, once the death-bomb window ran out.
+    PlayerDeath,
+    Pickup,
+    Graze,
+    Bomb,
+}
+
+/// A sound asked for by the game, heard from `position` when it has one
+/// and from the middle otherwise.
+#[derive(Clone, Debug)]
+pub struct SfxEvent {
+    pub sfx: Sfx,
+    pub position: Option<Position>,
+}
+
+impl SfxEvent {
+    pub fn new(sfx: Sfx) -> SfxEvent {
+        SfxEvent {
+            sfx,
+            position: None,
+        }
+    }
+
+    pub fn at(sfx: Sfx, position: &Position) -> SfxEvent {
+        SfxEvent {
+            sfx,
+            position: Some(position.clone()),
+        }
+    }
+}
+
+/// Where gameplay systems write the sounds they make. Nothing is kept when
+/// nobody listens, as in headless runs and on the server.
+pub type SfxEvents = EventChannel<SfxEvent>;
+
+/// The sound played for every `Sfx`, silent for the ones left out.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SfxTable {
+    pub sounds: HashMap<Sfx, SoundDef>,
+}
+
+/// A music track playing, or fading out.
+#[derive(Debug)]
+struct Track {
+    name: String,
+    voice: VoiceId,
+    /// Where the fade is, from 0 to 1.
+    level: f32,
+}
+
+/// Mixes music and sound effects on a backend.
+pub struct Audio {
+    backend: Box<dyn AudioBackend>,
+    /// Clips by file, none for sounds the backend could not take.
+    clips: HashMap<PathBuf, Option<ClipId>>,
+    music: Option<Track>,
+    fading: Vec<Track>,
+    /// Voices of every sound effect, oldest first.
+    sounds: HashMap<PathBuf, VecDeque<VoiceId>>,
+    volumes: Volumes,
+    /// Where `AudioSystem` is in `SfxEvents`, shared by all of them.
+    events: Option<ReaderId<SfxEvent>>,
+}
+
+impl Audio {
+    pub fn new(backend: Box<dyn AudioBackend>) -> Audio {
+        Audio {
+            backend,
+            clips: HashMap::new(),
+            music: None,
+            fading: Vec::new(),
+            sounds: HashMap::new(),
+            volumes: Volumes::default(),
+            events: None,
+        }
+    }
+
+    /// The clip of `sound`, none while it is still loading or when it
+    /// could not be loaded.
+    fn clip(&mut self, sound: &Handle<SoundData>) -> Option<ClipId> {
+        let path = sound.path();
+        if let Some(clip) = self.clips.get(path) {
+            return *clip;
+        }
+        let data = sound.get()?;
+        let clip = match self.backend.load(path, &data) {
+            Ok(clip) => Some(clip),
+            Err(err) => {
+                eprintln!("could not load {}: {}", path.display(), err);
+                None
+            }
+        };
+        self.clips.insert(path.to_path_buf(), clip);
+        clip
+    }
+
+    /// Name of the music track playing, not counting the ones fading out.
+    pub fn track(&self) -> Option<&str> {
+        self.music.as_ref().map(|track| track.name.as_str())
+    }
+
+    pub fn set_volumes(&mut self, volumes: Volumes) {
+        self.volumes = volumes;
+    }
+
+    /// Fades `music` in as track `name`, fading out the one playing. Does
+    /// nothing when `name` is already playing.
+    pub fn play_music(&mut self, name: &str, music: &Handle<SoundData>) {
+        if self.track() == Some(name) {
+            return;
+        }
+        self.stop_music();
+        let clip = match self.clip(music) {
+            Some(clip) => clip,
+            None => return,
+        };
+        if let Some(voice) = self.backend.play(clip, true, 0., 0.) {
+            self.music = Some(Track {
+                name: name.to_string(),
+                voice,
+                level: 0.,
+            });
+        }
+    }
+
+    /// Fades out the track playing.
+    pub fn stop_music(&mut self) {
+        if let Some(track) = self.music.take() {
+            self.fading.push(track);
+        }
+    }
+
+    /// Plays a sound effect, cutting its oldest voice when it has all of
+    /// them playing.
+    pub fn play_sound(&mut self, sound: &SoundDef, data: &Handle<SoundData>, pan: f32) {
+        let clip = match self.clip(data) {
+            Some(clip) => clip,
+            None => return,
+        };
+        let backend = &mut self.backend;
+        let voices = self.sounds.entry(sound.file.clone()).or_default();
+        voices.retain(|voice| backend.is_playing(*voice));
+        while !voices.is_empty() && voices.len() >= sound.voices {
+            if let Some(oldest) = voices.pop_front() {
+                backend.stop(oldest);
+            }
+        }
+        if sound.voices == 0 {
+            return;
+        }
+        let volume = sound.volume * self.volumes.sfx * self.volumes.master;
+        if let Some(voice) = backend.play(clip, false, volume, pan) {
+            voices.push_back(voice);
+        }
+    }
+
+    /// Moves the music fades `seconds` along, taking `crossfade` seconds
+    /// for a whole fade.
+    pub fn update(&mut self, seconds: f64, crossfade: f64) {
+        let step = if crossfade > 0. {
+            (seconds / crossfade) as f32
+        } else {
+            1.
+        };
+        let music = self.volumes.music * self.volumes.master;
+        if let Some(track) = self.music.as_mut() {
+            track.level = (track.level + step).min(1.);
+            self.backend.set_volume(track.voice, track.level * music);
+        }
+        for track in self.fading.iter_mut() {
+            track.level = (track.level - step).max(0.);
+            self.backend.set_volume(track.voice, track.level * music);
+        }
+        let backend = &mut self.backend;
+        self.fading.retain(|track| {
+            if track.level > 0. {
+                return true;
+            }
+            backend.stop(track.voice);
+            false
+        });
+    }
+}
+
+/// The music a scene calls for.
+fn music_for(scene: Scene, bosses: &ReadStorage<Boss>, cfg: &AudioConfig) -> &'static str {
+    if !scene.shows_world() {
+        return "title";
+    }
+    if bosses.join().any(|boss| boss.phase >= cfg.boss_phase) {
+        "boss"
+    } else {
+        "stage"
+    }
+}
+
+/// Left to right position of `x` in the play field, from -1 to 1.
+fn pan(x: f32, field: &PlayField) -> f32 {
+    (x / field.w * 2. - 1.).clamp(-1., 1.)
+}
+
+/// Plays the music of the current scene and the sounds asked for since the
+/// last frame, at the volumes of the settings. A sound asked for several
+/// times in the same frame plays once, from the middle of where it was
+/// asked.
+pub struct AudioSystem {
+    pub audio: Rc<RefCell<Audio>>,
+}
+
+impl<'a> System<'a> for AudioSystem {
+    type SystemData = (
+        Read<'a, GameState>,
+        Read<'a, SceneControl>,
+        Read<'a, Settings>,
+        Read<'a, AudioConfig>,
+        Read<'a, SfxTable>,
+        Read<'a, SfxEvents>,
+        Read<'a, PlayField>,
+        Write<'a, Assets>,
+        ReadStorage<'a, Boss>,
+    );
+
+    fn setup(&mut self, world: &mut World) {
+        Self::SystemData::setup(world);
+        let mut audio = self.audio.borrow_mut();
+        if audio.events.is_none() {
+            audio.events = Some(world.fetch_mut::<SfxEvents>().register_reader());
+        }
+    }
+
+    fn run(
+        &mut self,
+        (gs, ctl, settings, cfg, table, events, field, mut assets, bosses): Self::SystemData,
+    ) {
+        let mut audio = self.audio.borrow_mut();
+        audio.set_volumes(settings.volumes);
+        let track = music_for(ctl.current, &bosses, &cfg);
+        match cfg.music.get(track) {
+            Some(path) => audio.play_music(track, &assets.load(path)),
+            None => audio.stop_music(),
+        }
+        audio.update(gs.real_delta, cfg.crossfade);
+
+        // every sound of the frame with the sum of its pans and its count
+        let mut heard: Vec<(Sfx, f32, u32)> = vec![];
+        let reader = audio.events.as_mut().expect("registered in setup");
+        for event in events.read(reader) {
+            let side = event.position.as_ref().map(|pos| pan(pos.x, &field));
+            let side = side.unwrap_or(0.);
+            match heard.iter_mut().find(|(sfx, _, _)| *sfx == event.sfx) {
+                Some((_, sum, count)) => {
+                    *sum += side;
+                    *count += 1;
+                }
+                None => heard.push((event.sfx, side, 1)),
+            }
+        }
+        for (sfx, sum, count) in heard {
+            if let Some(sound) = table.sounds.get(&sfx) {
+                audio.play_sound(sound, &assets.load(&sound.file), sum / count as f32);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lets the test look at the voices of the backend `Audio` owns.
+    struct Shared(Rc<RefCell<NullAudio>>);
+
+    impl AudioBackend for Shared {
+        fn load(&mut self, path: &Path, sound: &SoundData) -> Result<ClipId, Box<dyn Error>> {
+            self.0.borrow_mut().load(path, sound)
+        }
+
+        fn play(&mut self, clip: ClipId, looping: bool, volume: f32, pan: f32) -> Option<VoiceId> {
+            self.0.borrow_mut().play(clip, looping, volume, pan)
+        }
+
+        fn set_volume(&mut self, voice: VoiceId, volume: f32) {
+            self.0.borrow_mut().set_volume(voice, volume)
+        }
+
+        fn stop(&mut self, voice: VoiceId) {
+            self.0.borrow_mut().stop(voice)
+        }
+
+        fn is_playing(&mut self, voice: VoiceId) -> bool {
+            self.0.borrow_mut().is_playing(voice)
+        }
+    }
+
+    fn audio() -> (Audio, Rc<RefCell<NullAudio>>) {
+        let null = Rc::new(RefCell::new(NullAudio::default()));
+        let mut audio = Audio::new(Box::new(Shared(null.clone())));
+        audio.set_volumes(Volumes {
+            master: 0.5,
+            music: 0.8,
+            sfx: 1.,
+        });
+        (audio, null)
+    }
+
+    fn hit(voices: usize) -> SoundDef {
+        SoundDef {
+            file: PathBuf::from("assets/audio/hit.wav"),
+            voices,
+            volume: 1.,
+        }
+    }
+
+    #[test]
+    fn sound_cuts_its_oldest_voice() {
+        let (mut audio, null) = audio();
+        let mut assets = Assets::default();
+        let sound = hit(2);
+        let data = assets.load(&sound.file);
+        audio.play_sound(&sound, &data, -1.);
+        audio.play_sound(&sound, &data, 0.);
+        audio.play_sound(&sound, &data, 1.);
+        let mut pans: Vec<f32> = null.borrow().voices.values().map(|v| v.pan).collect();
+        pans.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(pans, vec![0., 1.]);
+        assert!(null.borrow().voices.values().all(|v| v.volume == 0.5));
+    }
+
+    #[test]
+    fn sound_without_voices_is_silent() {
+        let (mut audio, null) = audio();
+        let mut assets = Assets::default();
+        let sound = hit(0);
+        audio.play_sound(&sound, &assets.load(&sound.file), 0.);
+        assert!(null.borrow().voices.is_empty());
+    }
+
+    #[test]
+    fn music_crossfades() {
+        let (mut audio, null) = audio();
+        let mut assets = Assets::default();
+        audio.play_music("stage", &assets.load("assets/audio/stage.wav"));
+        audio.update(1., 1.);
+        audio.play_music("boss", &assets.load("assets/audio/boss.wav"));
+        audio.update(0.5, 1.);
+        {
+            let null = null.borrow();
+            assert_eq!(null.voices.len(), 2);
+            assert!(null.voices.values().all(|v| (v.volume - 0.2).abs() < 1e-6));
+        }
+        audio.update(0.5, 1.);
+        let null = null.borrow();
+        let voices: Vec<&NullVoice> = null.voices.values().collect();
+        assert_eq!(voices.len(), 1);
+        assert_eq!(
+            null.clips[voices[0].clip],
+            Path::new("assets/audio/boss.wav")
+        );
+        assert!(voices[0].looping);
+        assert!((voices[0].volume - 0.4).abs() < 1e-6);
+        assert_eq!(audio.track(), Some("boss"));
+    }
+
+    #[test]
+    fn same_frame_sounds_play_once_from_their_middle() {
+        let mut world = World::new();
+        let sfx = Assets::default().load::<SfxTable>("assets/sfx.ron").wait();
+        world.insert((*sfx.unwrap()).clone());
+        let null = Rc::new(RefCell::new(NullAudio::default()));
+        let audio = Rc::new(RefCell::new(Audio::new(Box::new(Shared(null.clone())))));
+        let mut system = AudioSystem { audio };
+        System::setup(&mut system, &mut world);
+        {
+            let mut events = world.write_resource::<SfxEvents>();
+            events.single_write(SfxEvent::at(Sfx::Hit, &Position { x: 0., y: 0. }));
+            events.single_write(SfxEvent::at(Sfx::Hit, &Position { x: 160., y: 300. }));
+            events.single_write(SfxEvent::new(Sfx::Bomb));
+        }
+        system.run_now(&world);
+
+        let null = null.borrow();
+        let mut heard: Vec<(&Path, f32)> = null
+            .voices
+            .values()
+            .map(|v| (null.clips[v.clip].as_path(), v.pan))
+            .collect();
+        heard.sort_by(|a, b| a.0.cmp(b.0));
+        assert_eq!(heard.len(), 2);
+        assert_eq!(heard[0], (Path::new("assets/audio/bomb.wav"), 0.));
+        assert_eq!(heard[1].0, Path::new("assets/audio/hit.wav"));
+        assert!((heard[1].1 + 0.75).abs() < 1e-6);
+    }
+}
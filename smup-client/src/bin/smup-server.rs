@@ -0,0 +1,110 @@
+//! Hosts a run for `smup-client --connect`.
+//!
+//! `smup-server [--bind ADDR] [--players N] [--seed N] [--ticks N]` waits
+//! for the first client, plays the first stage until it is over, everyone
+//! left or `--ticks` ticks went by, and prints how it went. Progress and
+//! errors go to stderr, the outcome alone to stdout.
+
+use smup_client::boss::BossDef;
+use smup_client::server::Server;
+use smup_client::sim::STEP;
+use std::process;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Time between the statistics printed while playing.
+const REPORT_PERIOD: Duration = Duration::from_secs(10);
+/// Time clients get to see the end of the run before the server quits.
+const LINGER: Duration = Duration::from_secs(2);
+
+/// Value of `flag` on the command line. A value that does not parse stops
+/// the server with what it should have been.
+fn flag<T: FromStr>(args: &[String], flag: &str, expected: &str) -> Option<T> {
+    let value = args
+        .iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            eprintln!("{} takes {}, not {:?}", flag, expected, value);
+            process::exit(2);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let bind = flag(&args, "--bind", "an address like 0.0.0.0:7100")
+        .unwrap_or_else(|| "0.0.0.0:7100".parse().unwrap());
+    let players = flag(&args, "--players", "a number")
+        .unwrap_or(2usize)
+        .clamp(1, 2);
+    let seed = flag(&args, "--seed", "a number").unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0)
+    });
+    let ticks: u32 = flag(&args, "--ticks", "a number of ticks").unwrap_or(u32::MAX);
+
+    let boss = BossDef::load("assets/bosses/guardian.ron").unwrap_or_else(|err| {
+        eprintln!("assets/bosses/guardian.ron: {}", err);
+        process::exit(1);
+    });
+    let mut server = Server::new(bind, &boss, players, seed).unwrap_or_else(|err| {
+        eprintln!("could not listen on {}: {}", bind, err);
+        process::exit(1);
+    });
+    eprintln!(
+        "listening on {} for {} players, seed {}",
+        server.local_addr().expect("the socket is bound"),
+        players,
+        seed
+    );
+    while server.clients() == 0 {
+        server.receive();
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let started = Instant::now();
+    let mut steps: u32 = 0;
+    let mut ended = None;
+    let mut reported = started;
+    loop {
+        server.receive();
+        server.step();
+        steps += 1;
+        if ended.is_none() && (server.outcome().is_some() || server.clients() == 0) {
+            ended = Some(Instant::now());
+        }
+        if ended.map(|at| at.elapsed() > LINGER).unwrap_or(false) || steps >= ticks {
+            break;
+        }
+        if reported.elapsed() >= REPORT_PERIOD {
+            reported = Instant::now();
+            let stats = &server.stats;
+            eprintln!(
+                "tick {} clients {} sent {} KiB/s",
+                server.tick(),
+                server.clients(),
+                stats.bytes_sent / 1024 / started.elapsed().as_secs().max(1)
+            );
+        }
+        let next = started + Duration::from_secs_f64(f64::from(steps) * STEP);
+        if let Some(wait) = next.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+
+    match server.outcome() {
+        Some(outcome) => println!("{}", outcome),
+        None => println!("stopped at tick {}", server.tick()),
+    }
+    let stats = &server.stats;
+    println!(
+        "packets {} bytes {} full states {} dropped states {}",
+        stats.packets_sent, stats.bytes_sent, stats.full_states, stats.dropped_states
+    );
+}
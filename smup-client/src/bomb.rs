@@ -0,0 +1,129 @@
+extern crate specs;
+
+use crate::audio::{Sfx, SfxEvent, SfxEvents};
+use crate::bullet::{cancel_bullets, Bullet};
+use crate::enemy::Enemy;
+use crate::graze::{GrazeConfig, Player};
+use crate::item::ItemConfig;
+use crate::types::{GameState, PerDifficulty, PlayerInputs, Position};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BombConfig {
+    /// Seconds the bomb keeps clearing bullets and hurting enemies.
+    pub duration: f64,
+    /// Seconds of invulnerability granted when bombing.
+    pub invulnerability: f64,
+    /// Damage per second dealt to enemies within `radius` of the player.
+    pub damage: f32,
+    pub radius: f32,
+    /// Bombs available at the start of the run and after losing a life.
+    pub stock: u32,
+    /// Frames after a hit during which bombing still saves the player.
+    pub deathbomb_frames: PerDifficulty<u32>,
+}
+
+impl Default for BombConfig {
+    fn default() -> BombConfig {
+        BombConfig {
+            duration: 3.,
+            invulnerability: 4.,
+            damage: 60.,
+            radius: 240.,
+            stock: 3,
+            deathbomb_frames: PerDifficulty {
+                easy: 15,
+                normal: 8,
+                hard: 5,
+                lunatic: 3,
+            },
+        }
+    }
+}
+
+/// Triggers bombs and resolves the death-bomb window opened by hits.
+pub struct BombSystem {}
+
+impl<'a> System<'a> for BombSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, PlayerInputs>,
+        Read<'a, BombConfig>,
+        Read<'a, GrazeConfig>,
+        Read<'a, ItemConfig>,
+        Write<'a, GameState>,
+        WriteStorage<'a, Player>,
+        WriteStorage<'a, Enemy>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Bullet>,
+        Read<'a, LazyUpdate>,
+        Write<'a, SfxEvents>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            inputs,
+            cfg,
+            graze,
+            items,
+            mut gs,
+            mut players,
+            mut enemies,
+            positions,
+            bullets,
+            lazy,
+            mut sfx,
+        ): Self::SystemData,
+    ) {
+        let mut bombing = vec![];
+        for (player, pos) in (&mut players, &positions).join() {
+            let bomb = inputs.get(player.slot).bomb();
+            let pressed = bomb && !player.bomb_held;
+            player.bomb_held = bomb;
+            player.bombing = (player.bombing - gs.delta).max(0.);
+            if pressed && player.bombing <= 0. && gs.bombs > 0 {
+                gs.bombs -= 1;
+                player.bombing = cfg.duration;
+                player.invulnerable = player.invulnerable.max(cfg.invulnerability);
+                player.dying = None;
+                sfx.single_write(SfxEvent::at(Sfx::Bomb, pos));
+            }
+
+            match player.dying {
+                Some(_) if gs.delta <= 0. => {}
+                Some(0) => {
+                    player.dying = None;
+                    player.invulnerable = graze.hit_invulnerability;
+                    gs.lives = gs.lives.saturating_sub(1);
+                    gs.bombs = gs.bombs.max(cfg.stock);
+                    sfx.single_write(SfxEvent::at(Sfx::PlayerDeath, pos));
+                }
+                Some(frames) => player.dying = Some(frames - 1),
+                None => {}
+            }
+
+            if player.bombing > 0. {
+                bombing.push((pos.x, pos.y));
+            }
+        }
+        if bombing.is_empty() {
+            return;
+        }
+
+        let damage = cfg.damage * gs.delta as f32;
+        for (enemy, pos) in (&mut enemies, &positions).join() {
+            let in_range = bombing.iter().any(|(x, y)| {
+                let (dx, dy) = (pos.x - x, pos.y - y);
+                dx * dx + dy * dy <= cfg.radius * cfg.radius
+            });
+            if in_range {
+                enemy.health -= damage;
+            }
+        }
+
+        cancel_bullets(&entities, &lazy, &items, &positions, &bullets);
+    }
+}
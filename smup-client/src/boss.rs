@@ -0,0 +1,222 @@
+extern crate specs;
+
+use crate::bullet::{cancel_bullets, Bullet, Emitter, Pattern};
+use crate::enemy::Enemy;
+use crate::graze::Player;
+use crate::item::{Drops, ItemConfig, ItemDrop};
+use crate::phy::{Colliders, Sensor};
+use crate::prefab::{spawn_prefab, PrefabComponents};
+use crate::render::{Color, Pivot, Size, Sprite};
+use crate::score::{ScoreEvent, ScoreEvents, ScoreKind};
+use crate::types::{GameState, Position};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use specs::{Component, VecStorage};
+use std::error::Error;
+use std::fs;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PhaseDef {
+    pub name: String,
+    pub health: f32,
+    /// Seconds before the phase ends on its own.
+    pub time_limit: f64,
+    /// Awarded when the phase is cleared without dying or bombing, zero for
+    /// phases that can not be captured.
+    #[serde(default)]
+    pub capture_bonus: u64,
+    /// Waypoints the boss moves through, in a loop.
+    pub path: Vec<(f32, f32)>,
+    pub speed: f32,
+    pub patterns: Vec<Pattern>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BossDef {
+    pub name: String,
+    pub size: f32,
+    pub color: [f32; 4],
+    #[serde(default)]
+    pub drops: Vec<ItemDrop>,
+    pub phases: Vec<PhaseDef>,
+}
+
+impl BossDef {
+    pub fn load(path: &str) -> Result<BossDef, Box<dyn Error>> {
+        BossDef::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<BossDef, Box<dyn Error>> {
+        let def: BossDef = ron::de::from_str(text)?;
+        if def.phases.is_empty() {
+            return Err(format!("boss {} has no phases", def.name).into());
+        }
+        Ok(def)
+    }
+}
+
+#[derive(Clone, Component, Debug, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Boss {
+    pub name: String,
+    pub phases: Vec<PhaseDef>,
+    pub phase: usize,
+    /// Seconds left in the current phase.
+    pub timer: f64,
+    pub waypoint: usize,
+    /// Cleared when a player dies or bombs during the phase.
+    pub capturable: bool,
+}
+
+impl Boss {
+    pub fn current(&self) -> &PhaseDef {
+        &self.phases[self.phase]
+    }
+
+    pub fn phases_left(&self) -> usize {
+        self.phases.len() - self.phase - 1
+    }
+}
+
+/// Spawns the `boss` prefab as the boss of `def`.
+pub fn spawn_boss(world: &mut World, def: &BossDef) -> Entity {
+    let first = &def.phases[0];
+    let (x, y) = first.path.first().cloned().unwrap_or((0., 0.));
+    let [r, g, b, a] = def.color;
+    let overrides = PrefabComponents {
+        position: Some(Position { x, y }),
+        enemy: Some(Enemy {
+            health: first.health,
+        }),
+        emitter: Some(Emitter {
+            patterns: first.patterns.clone(),
+        }),
+        colliders: Some(Colliders::new(Sensor::Enemy, def.size / 2.)),
+        drops: Some(Drops {
+            items: def.drops.clone(),
+        }),
+        sprite: Some(Sprite {
+            color: Color { r, g, b, a },
+            size: Size {
+                w: def.size,
+                h: def.size,
+            },
+            pivot: Pivot {
+                x: def.size / 2.,
+                y: def.size / 2.,
+            },
+            region: None,
+        }),
+        boss: Some(Boss {
+            name: def.name.clone(),
+            phases: def.phases.clone(),
+            phase: 0,
+            timer: first.time_limit,
+            waypoint: 0,
+            capturable: true,
+        }),
+        ..Default::default()
+    };
+    spawn_prefab(world, "boss", &overrides).expect("the boss prefab is checked when loaded")
+}
+
+/// Time scale and real seconds of the slow motion when a boss goes down.
+const DEFEAT_SLOW_MOTION: (f64, f64) = (0.25, 1.5);
+
+/// Moves bosses along their path and switches phases when the phase health
+/// runs out or its time is up. The last phase ending leaves the boss with no
+/// health so `EnemyDeathSystem` takes it from there.
+pub struct BossSystem {}
+impl<'a> System<'a> for BossSystem {
+    type SystemData = (
+        Entities<'a>,
+        Write<'a, GameState>,
+        Read<'a, ItemConfig>,
+        Write<'a, ScoreEvents>,
+        WriteStorage<'a, Boss>,
+        WriteStorage<'a, Enemy>,
+        WriteStorage<'a, Emitter>,
+        WriteStorage<'a, Position>,
+        ReadStorage<'a, Player>,
+        ReadStorage<'a, Bullet>,
+        Read<'a, LazyUpdate>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut gs,
+            items,
+            mut score,
+            mut bosses,
+            mut enemies,
+            mut emitters,
+            mut positions,
+            players,
+            bullets,
+            lazy,
+        ): Self::SystemData,
+    ) {
+        let failed = players.join().any(|p| p.dying.is_some() || p.bombing > 0.);
+        let mut cancel = false;
+
+        for (entity, boss, enemy, pos) in
+            (&entities, &mut bosses, &mut enemies, &mut positions).join()
+        {
+            if failed {
+                boss.capturable = false;
+            }
+            boss.timer -= gs.delta;
+
+            let phase = &boss.phases[boss.phase];
+            if let Some(&(x, y)) = phase.path.get(boss.waypoint) {
+                let (dx, dy) = (x - pos.x, y - pos.y);
+                let dist = (dx * dx + dy * dy).sqrt();
+                let step = phase.speed * gs.delta as f32;
+                if dist <= step.max(f32::EPSILON) {
+                    pos.x = x;
+                    pos.y = y;
+                    boss.waypoint = (boss.waypoint + 1) % phase.path.len();
+                } else {
+                    pos.x += dx / dist * step;
+                    pos.y += dy / dist * step;
+                }
+            }
+
+            let defeated = enemy.health <= 0.;
+            if !defeated && boss.timer > 0. {
+                continue;
+            }
+            if defeated && boss.capturable && phase.capture_bonus > 0 {
+                score
+                    .captures
+                    .push(format!("{}: {}", boss.name, phase.name));
+                score.events.push(ScoreEvent::with_points(
+                    ScoreKind::Capture,
+                    phase.capture_bonus,
+                ));
+            }
+            cancel = true;
+
+            if boss.phases_left() == 0 {
+                enemy.health = 0.;
+                let (scale, seconds) = DEFEAT_SLOW_MOTION;
+                gs.slow_motion(scale, seconds);
+                continue;
+            }
+            boss.phase += 1;
+            boss.timer = boss.current().time_limit;
+            boss.waypoint = 0;
+            boss.capturable = true;
+            enemy.health = boss.current().health;
+            if let Some(emitter) = emitters.get_mut(entity) {
+                emitter.patterns = boss.current().patterns.clone();
+            }
+        }
+
+        if cancel {
+            cancel_bullets(&entities, &lazy, &items, &positions, &bullets);
+        }
+    }
+}
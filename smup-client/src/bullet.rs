@@ -0,0 +1,166 @@
+extern crate specs;
+
+use crate::audio::{Sfx, SfxEvent, SfxEvents};
+use crate::item::{spawn_item, ItemConfig, ItemKind};
+use crate::phy::{Colliders, Sensor};
+use crate::prefab::{PrefabComponents, PrefabSpawner};
+use crate::render::{Pivot, Size, Sprite};
+use crate::types::{GameState, PlayField, Position, Velocity};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use specs::storage::MaskedStorage;
+use specs::{Component, NullStorage, VecStorage};
+use std::ops::Deref;
+
+/// Bullets further than this outside of the play field are removed.
+const OFFSCREEN_MARGIN: f32 = 32.;
+
+#[derive(Clone, Default, Component, Debug, Serialize, Deserialize)]
+#[storage(NullStorage)]
+pub struct Bullet {}
+
+/// A ring of bullets fired every `period` seconds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pattern {
+    pub period: f64,
+    pub count: u32,
+    pub speed: f32,
+    pub radius: f32,
+    /// Angle added to the ring after every shot, in radians.
+    pub spin: f32,
+    #[serde(default)]
+    pub angle: f32,
+    #[serde(default)]
+    pub timer: f64,
+}
+
+/// Fires its patterns from the entity `Position`.
+#[derive(Clone, Component, Debug, Default, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Emitter {
+    pub patterns: Vec<Pattern>,
+}
+
+/// Spawns the `bullet` prefab, sized for `radius`.
+pub fn spawn_bullet(
+    spawner: &PrefabSpawner,
+    at: (f32, f32),
+    velocity: (f32, f32),
+    radius: f32,
+) -> Option<Entity> {
+    let sprite = spawner.prefabs.components("bullet").ok()?.sprite;
+    let overrides = PrefabComponents {
+        position: Some(Position { x: at.0, y: at.1 }),
+        velocity: Some(Velocity {
+            x: velocity.0,
+            y: velocity.1,
+        }),
+        colliders: Some(Colliders::new(Sensor::Bullet, radius)),
+        sprite: Some(Sprite {
+            size: Size {
+                w: radius * 2.,
+                h: radius * 2.,
+            },
+            pivot: Pivot {
+                x: radius,
+                y: radius,
+            },
+            ..sprite.unwrap_or_default()
+        }),
+        ..Default::default()
+    };
+    spawner.spawn_prefab("bullet", &overrides).ok()
+}
+
+/// Turns every bullet on screen into a cancel item.
+pub fn cancel_bullets<D>(
+    entities: &Entities,
+    lazy: &LazyUpdate,
+    items: &ItemConfig,
+    positions: &Storage<Position, D>,
+    bullets: &ReadStorage<Bullet>,
+) where
+    D: Deref<Target = MaskedStorage<Position>>,
+{
+    for (bullet, pos, _) in (entities, positions, bullets).join() {
+        let _ = entities.delete(bullet);
+        spawn_item(
+            entities,
+            lazy,
+            items,
+            ItemKind::Cancel,
+            (pos.x, pos.y),
+            true,
+        );
+    }
+}
+
+pub struct EmitterSystem {}
+impl<'a> System<'a> for EmitterSystem {
+    type SystemData = (
+        PrefabSpawner<'a>,
+        ReadStorage<'a, Position>,
+        WriteStorage<'a, Emitter>,
+        Read<'a, GameState>,
+        Write<'a, SfxEvents>,
+    );
+
+    fn run(&mut self, (spawner, positions, mut emitters, gs, mut sfx): Self::SystemData) {
+        for (pos, emitter) in (&positions, &mut emitters).join() {
+            for pattern in emitter.patterns.iter_mut() {
+                pattern.timer -= gs.delta;
+                while pattern.period > 0. && pattern.timer <= 0. {
+                    pattern.timer += pattern.period;
+                    sfx.single_write(SfxEvent::at(Sfx::EnemyShot, pos));
+                    let step = std::f32::consts::PI * 2. / pattern.count as f32;
+                    for i in 0..pattern.count {
+                        let angle = pattern.angle + step * i as f32;
+                        spawn_bullet(
+                            &spawner,
+                            (pos.x, pos.y),
+                            (angle.cos() * pattern.speed, angle.sin() * pattern.speed),
+                            pattern.radius,
+                        );
+                    }
+                    pattern.angle += pattern.spin;
+                }
+            }
+        }
+    }
+}
+
+pub struct MovementSystem {}
+impl<'a> System<'a> for MovementSystem {
+    type SystemData = (
+        WriteStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+        Read<'a, GameState>,
+    );
+
+    fn run(&mut self, (mut positions, velocities, gs): Self::SystemData) {
+        let dt = gs.delta as f32;
+        for (pos, vel) in (&mut positions, &velocities).join() {
+            pos.x += vel.x * dt;
+            pos.y += vel.y * dt;
+        }
+    }
+}
+
+/// Removes the bullets that left the play field.
+pub struct BulletCleanupSystem {}
+impl<'a> System<'a> for BulletCleanupSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Bullet>,
+        Read<'a, PlayField>,
+    );
+
+    fn run(&mut self, (entities, positions, bullets, field): Self::SystemData) {
+        for (entity, pos, _) in (&entities, &positions, &bullets).join() {
+            if !field.contains(pos, OFFSCREEN_MARGIN) {
+                let _ = entities.delete(entity);
+            }
+        }
+    }
+}
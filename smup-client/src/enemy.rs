@@ -0,0 +1,49 @@
+extern crate specs;
+
+use crate::audio::{Sfx, SfxEvent, SfxEvents};
+use crate::item::{spawn_drops, Drops, ItemConfig};
+use crate::score::{ScoreEvent, ScoreEvents, ScoreKind};
+use crate::sim::Rng;
+use crate::types::Position;
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use specs::{Component, VecStorage};
+
+#[derive(Clone, Component, Debug, Default, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Enemy {
+    pub health: f32,
+}
+
+/// Removes the enemies that ran out of health, leaving their drops behind.
+pub struct EnemyDeathSystem {}
+impl<'a> System<'a> for EnemyDeathSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Enemy>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Drops>,
+        Read<'a, ItemConfig>,
+        Write<'a, ScoreEvents>,
+        Write<'a, Rng>,
+        Read<'a, LazyUpdate>,
+        Write<'a, SfxEvents>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, enemies, positions, drops, items, mut score, mut rng, lazy, mut sfx): Self::SystemData,
+    ) {
+        for (entity, enemy, pos) in (&entities, &enemies, &positions).join() {
+            if enemy.health > 0. {
+                continue;
+            }
+            if let Some(drops) = drops.get(entity) {
+                spawn_drops(&entities, &lazy, &items, drops, pos, &mut rng);
+            }
+            score.events.push(ScoreEvent::new(ScoreKind::EnemyKill));
+            sfx.single_write(SfxEvent::at(Sfx::EnemyDeath, pos));
+            let _ = entities.delete(entity);
+        }
+    }
+}
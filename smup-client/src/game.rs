@@ -0,0 +1,218 @@
+extern crate specs;
+
+use crate::assets::{AssetError, Assets};
+use crate::bomb::{BombConfig, BombSystem};
+use crate::boss::{spawn_boss, BossDef, BossSystem};
+use crate::bullet::{BulletCleanupSystem, EmitterSystem, MovementSystem};
+use crate::enemy::EnemyDeathSystem;
+use crate::gamepad::{GamepadConfig, Gamepads, MAX_PLAYERS};
+use crate::graze::{GrazeConfig, GrazeSystem, Player};
+use crate::item::{ItemConfig, ItemSystem};
+use crate::phy::{PhysicsBodies, PhysicsSystem, SensorEvents};
+use crate::prefab::{spawn_prefab, PrefabComponents, Prefabs};
+use crate::registry::Registry;
+use crate::render::{Color, Sprite};
+use crate::replay::{RecorderSystem, Recording};
+use crate::scene::SceneControl;
+use crate::score::{Score, ScoreConfig, ScoreEvents, ScoreSystem};
+use crate::shot::{ShotConfig, ShotHitSystem, ShotSystem};
+use crate::sim::{Checksum, ChecksumSystem, ClockSystem, Rng};
+use crate::stage::{Stage, StageSystem};
+use crate::types::{GameState, InputFrame, InputState, PlayField, PlayerInputs, Position};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use specs::world::EntitiesRes;
+use specs::Component;
+use std::sync::Arc;
+
+#[derive(Clone, Default, Component, Debug, Serialize, Deserialize)]
+#[storage(NullStorage)]
+pub struct MouseTracker {}
+
+/// Moves the players to where their input points.
+pub struct MouseTrackSystem {}
+impl<'a> System<'a> for MouseTrackSystem {
+    type SystemData = (
+        WriteStorage<'a, Position>,
+        ReadStorage<'a, MouseTracker>,
+        ReadStorage<'a, Player>,
+        Read<'a, PlayerInputs>,
+    );
+    fn run(&mut self, (mut pos_store, track, players, inputs): Self::SystemData) {
+        for (pos, _, player) in (&mut pos_store, &track, &players).join() {
+            let input = inputs.get(player.slot);
+            pos.x = input.x;
+            pos.y = input.y;
+        }
+    }
+}
+
+/// Hands the input of this machine to the players: the keyboard and mouse
+/// to the first one, the other controllers to the others.
+pub struct LocalInputSystem {}
+impl<'a> System<'a> for LocalInputSystem {
+    type SystemData = (
+        Write<'a, PlayerInputs>,
+        Read<'a, InputState>,
+        Read<'a, GameState>,
+        Read<'a, Gamepads>,
+    );
+    fn run(&mut self, (mut inputs, input, gs, pads): Self::SystemData) {
+        inputs.set(0, InputFrame::capture(&input, &gs.mouse_position));
+        for slot in 1..MAX_PLAYERS {
+            if let Some(frame) = pads.frame(slot) {
+                inputs.set(slot, frame);
+            }
+        }
+    }
+}
+
+/// A world with every component registered and the one `Registry`,
+/// shared as `Arc<Registry>` by the tools that need it.
+pub fn create_world() -> World {
+    let mut world = World::new();
+    let registry = Registry::default();
+    registry.register(&mut world);
+    world.insert(Arc::new(registry));
+    world
+}
+
+/// Loads the game data through `assets` and inserts every resource the
+/// gameplay systems need, windowed or not. The loader already reported
+/// what could not be loaded when this fails.
+pub fn insert_resources(world: &mut World, assets: &mut Assets) -> Result<(), AssetError> {
+    let items = assets.load::<ItemConfig>("assets/items.ron");
+    let score = assets.load::<ScoreConfig>("assets/score.ron");
+    let prefabs = assets.load::<Prefabs>("assets/prefabs.ron");
+    let (items, score, prefabs) = (items.wait()?, score.wait()?, prefabs.wait()?);
+
+    world.insert(GameState::default());
+    world.insert(InputState::default());
+    world.insert(PlayerInputs::default());
+    world.insert(Gamepads::default());
+    world.insert(GamepadConfig::default());
+    world.insert(GrazeConfig::default());
+    world.insert(BombConfig::default());
+    world.insert(ShotConfig::default());
+    world.insert(ItemConfig::clone(&items));
+    world.insert(ScoreConfig::clone(&score));
+    world.insert(Prefabs::clone(&prefabs));
+    world.insert(Score::default());
+    world.insert(Stage::default());
+    world.insert(SceneControl::default());
+    world.insert(PlayField::default());
+    world.insert(Rng::default());
+    world.insert(Checksum::default());
+    Ok(())
+}
+
+/// Colors of the players, by slot.
+const PLAYER_COLORS: [(f32, f32, f32); MAX_PLAYERS] = [(1., 1., 0.), (0., 1., 1.)];
+
+/// Clears whatever the previous run left behind and sets up a new one with
+/// `players` players. The same seed and input give the same run.
+pub fn start_run(world: &mut World, boss: &BossDef, seed: u64, players: usize) {
+    world.delete_all();
+    world.maintain();
+    // entity ids decide the iteration order, hand them out from zero again
+    // so a run does not depend on the ones before it
+    *world.write_resource::<EntitiesRes>() = EntitiesRes::default();
+    let bombs = world.fetch::<BombConfig>().stock;
+    let player_colliders = world.fetch::<GrazeConfig>().player_colliders();
+    {
+        let mut gs = world.write_resource::<GameState>();
+        gs.lives = 3;
+        gs.bombs = bombs;
+        gs.bomb_pieces = 0;
+        gs.life_pieces = 0;
+        gs.slow_motion = None;
+        gs.run += 1;
+        gs.tick = 0;
+    }
+    let number = world.fetch::<Stage>().number;
+    world.insert(Stage {
+        number,
+        ..Default::default()
+    });
+    world.insert(Score::default());
+    world.insert(ScoreEvents::default());
+    world.insert(SensorEvents::default());
+    world.insert(PhysicsBodies::default());
+    world.insert(Rng::new(seed));
+    world.insert(Checksum::default());
+    world.insert(Recording::default());
+    world.insert(PlayerInputs::default());
+
+    let sprite = world
+        .fetch::<Prefabs>()
+        .components("player")
+        .ok()
+        .and_then(|components| components.sprite)
+        .unwrap_or_default();
+    for slot in 0..players {
+        let (r, g, b) = PLAYER_COLORS[slot % PLAYER_COLORS.len()];
+        let overrides = PrefabComponents {
+            position: Some(Position { x: 0.0, y: 0.0 }),
+            player: Some(Player {
+                slot,
+                ..Default::default()
+            }),
+            colliders: Some(player_colliders.clone()),
+            sprite: Some(Sprite {
+                color: Color {
+                    r,
+                    g,
+                    b,
+                    ..sprite.color
+                },
+                ..sprite.clone()
+            }),
+            ..Default::default()
+        };
+        spawn_prefab(world, "player", &overrides)
+            .expect("the player prefab is checked when loaded");
+    }
+    spawn_boss(world, boss);
+}
+
+/// Adds the systems that play a run on this machine: the input of the
+/// first player comes from `InputState` and gets recorded, then the run is
+/// simulated. Presentation is left to the caller.
+pub fn gameplay<'a, 'b>(builder: DispatcherBuilder<'a, 'b>) -> DispatcherBuilder<'a, 'b> {
+    let builder = builder
+        .with(LocalInputSystem {}, "local_input", &[])
+        .with(RecorderSystem {}, "recorder", &["local_input"])
+        .with_barrier();
+    simulation(builder)
+}
+
+/// Adds the systems that simulate a run from the `PlayerInputs` of the
+/// tick.
+pub fn simulation<'a, 'b>(builder: DispatcherBuilder<'a, 'b>) -> DispatcherBuilder<'a, 'b> {
+    builder
+        .with(MouseTrackSystem {}, "mouse_tracker", &[])
+        .with(EmitterSystem {}, "emitter", &[])
+        .with(ShotSystem {}, "shots", &["mouse_tracker"])
+        .with(MovementSystem {}, "movement", &[])
+        .with(
+            PhysicsSystem::new(),
+            "physics",
+            &["mouse_tracker", "movement"],
+        )
+        .with(GrazeSystem {}, "graze", &["physics"])
+        .with(ShotHitSystem {}, "shot_hits", &["physics"])
+        .with(BombSystem {}, "bomb", &["graze"])
+        .with(ItemSystem {}, "items", &["bomb"])
+        .with(BossSystem {}, "boss", &["bomb", "shot_hits"])
+        .with(EnemyDeathSystem {}, "enemy_death", &["boss"])
+        .with(BulletCleanupSystem {}, "bullet_cleanup", &["bomb"])
+        .with(StageSystem {}, "stage", &["enemy_death"])
+        .with(
+            ScoreSystem {},
+            "score",
+            &["graze", "items", "enemy_death", "stage"],
+        )
+        .with_barrier()
+        .with(ChecksumSystem {}, "checksum", &[])
+        .with(ClockSystem {}, "clock", &["checksum"])
+}
@@ -0,0 +1,64 @@
+extern crate specs;
+
+use crate::types::GameState;
+use specs::prelude::*;
+
+/// What the loop does next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    /// The game updates, the clocks moving by the given seconds of wall
+    /// clock time.
+    Update(f64),
+    /// A frame is drawn.
+    Render,
+}
+
+/// Where the loop gets its input and timing from: the window, or a script
+/// when headless.
+pub trait Frontend {
+    /// Takes in every pending event up to the next phase, putting the
+    /// input into `world`. `None` once there is nothing left to run.
+    fn next_phase(&mut self, world: &mut World) -> Option<Phase>;
+}
+
+/// Runs the game phase after phase: the update given to `run` or `step`
+/// on update phases, the render dispatcher on render phases. The render
+/// dispatcher is set up once, here.
+pub struct GameLoop<F> {
+    pub frontend: F,
+    render: Dispatcher<'static, 'static>,
+}
+
+impl<F: Frontend> GameLoop<F> {
+    pub fn new(
+        world: &mut World,
+        frontend: F,
+        mut render: Dispatcher<'static, 'static>,
+    ) -> GameLoop<F> {
+        render.setup(world);
+        GameLoop { frontend, render }
+    }
+
+    /// Runs the next phase. `update` tells whether to carry on, false is
+    /// returned once it does not, the frontend is done or the game exits.
+    pub fn step(&mut self, world: &mut World, update: impl FnOnce(&mut World) -> bool) -> bool {
+        let carry_on = match self.frontend.next_phase(world) {
+            Some(Phase::Update(real)) => {
+                world.write_resource::<GameState>().advance(real);
+                update(world)
+            }
+            Some(Phase::Render) => {
+                self.render.dispatch(world);
+                true
+            }
+            None => false,
+        };
+        carry_on && !world.fetch::<GameState>().exit
+    }
+
+    /// Runs until `update` asks to stop, the frontend is done or the game
+    /// exits.
+    pub fn run(&mut self, world: &mut World, mut update: impl FnMut(&mut World) -> bool) {
+        while self.step(world, &mut update) {}
+    }
+}
@@ -0,0 +1,558 @@
+extern crate specs;
+
+use crate::types::{GameState, InputFrame, InputState, PlayField, Position};
+use specs::prelude::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+/// Most players a run can have, one per controller.
+pub const MAX_PLAYERS: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftBumper,
+    RightBumper,
+    Select,
+    Start,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+/// Sticks go from -1 to 1, down and right being positive. Triggers go
+/// from 0 to 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PadAxis {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+    LeftTrigger,
+    RightTrigger,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PadEvent {
+    Connected,
+    Disconnected,
+    Button(PadButton, bool),
+    Axis(PadAxis, f32),
+}
+
+/// Tells controllers apart for as long as they stay plugged in.
+pub type PadId = usize;
+
+/// Where controller events come from.
+pub trait GamepadBackend {
+    /// Events since the last call, oldest first.
+    fn poll(&mut self) -> Vec<(PadId, PadEvent)>;
+}
+
+/// Controllers driven from code, for tests and for builds without
+/// controller support. Clones share the same events.
+#[derive(Clone, Debug, Default)]
+pub struct VirtualGamepad {
+    events: Rc<RefCell<VecDeque<(PadId, PadEvent)>>>,
+}
+
+impl VirtualGamepad {
+    pub fn push(&self, pad: PadId, event: PadEvent) {
+        self.events.borrow_mut().push_back((pad, event));
+    }
+
+    pub fn connect(&self, pad: PadId) {
+        self.push(pad, PadEvent::Connected);
+    }
+
+    pub fn disconnect(&self, pad: PadId) {
+        self.push(pad, PadEvent::Disconnected);
+    }
+
+    pub fn press(&self, pad: PadId, button: PadButton) {
+        self.push(pad, PadEvent::Button(button, true));
+    }
+
+    pub fn release(&self, pad: PadId, button: PadButton) {
+        self.push(pad, PadEvent::Button(button, false));
+    }
+
+    pub fn axis(&self, pad: PadId, axis: PadAxis, value: f32) {
+        self.push(pad, PadEvent::Axis(axis, value));
+    }
+}
+
+impl GamepadBackend for VirtualGamepad {
+    fn poll(&mut self) -> Vec<(PadId, PadEvent)> {
+        self.events.borrow_mut().drain(..).collect()
+    }
+}
+
+#[cfg(feature = "gamepad")]
+mod native {
+    use super::{GamepadBackend, PadAxis, PadButton, PadEvent, PadId};
+    use gilrs::{Axis, Button, EventType, Gilrs};
+    use std::error::Error;
+
+    /// Real controllers, through gilrs.
+    pub struct GilrsBackend {
+        gilrs: Gilrs,
+        /// Controllers plugged in before the start, reported on the first
+        /// poll.
+        present: Vec<PadId>,
+    }
+
+    impl GilrsBackend {
+        pub fn new() -> Result<GilrsBackend, Box<dyn Error>> {
+            let gilrs = Gilrs::new()?;
+            let present = gilrs.gamepads().map(|(id, _)| id.into()).collect();
+            Ok(GilrsBackend { gilrs, present })
+        }
+    }
+
+    fn button(button: Button) -> Option<PadButton> {
+        Some(match button {
+            Button::South => PadButton::South,
+            Button::East => PadButton::East,
+            Button::North => PadButton::North,
+            Button::West => PadButton::West,
+            Button::LeftTrigger => PadButton::LeftBumper,
+            Button::RightTrigger => PadButton::RightBumper,
+            Button::Select => PadButton::Select,
+            Button::Start => PadButton::Start,
+            Button::DPadUp => PadButton::DPadUp,
+            Button::DPadDown => PadButton::DPadDown,
+            Button::DPadLeft => PadButton::DPadLeft,
+            Button::DPadRight => PadButton::DPadRight,
+            _ => return None,
+        })
+    }
+
+    fn axis(axis: Axis, value: f32) -> Option<(PadAxis, f32)> {
+        // gilrs has up as positive
+        Some(match axis {
+            Axis::LeftStickX => (PadAxis::LeftX, value),
+            Axis::LeftStickY => (PadAxis::LeftY, -value),
+            Axis::RightStickX => (PadAxis::RightX, value),
+            Axis::RightStickY => (PadAxis::RightY, -value),
+            Axis::LeftZ => (PadAxis::LeftTrigger, value),
+            Axis::RightZ => (PadAxis::RightTrigger, value),
+            _ => return None,
+        })
+    }
+
+    impl GamepadBackend for GilrsBackend {
+        fn poll(&mut self) -> Vec<(PadId, PadEvent)> {
+            let mut events: Vec<(PadId, PadEvent)> = self
+                .present
+                .drain(..)
+                .map(|pad| (pad, PadEvent::Connected))
+                .collect();
+            while let Some(event) = self.gilrs.next_event() {
+                let pad: PadId = event.id.into();
+                let event = match event.event {
+                    EventType::Connected => Some(PadEvent::Connected),
+                    EventType::Disconnected => Some(PadEvent::Disconnected),
+                    EventType::ButtonPressed(b, _) => button(b).map(|b| PadEvent::Button(b, true)),
+                    EventType::ButtonReleased(b, _) => {
+                        button(b).map(|b| PadEvent::Button(b, false))
+                    }
+                    // analog triggers some controllers report as buttons
+                    EventType::ButtonChanged(Button::LeftTrigger2, value, _) => {
+                        Some(PadEvent::Axis(PadAxis::LeftTrigger, value))
+                    }
+                    EventType::ButtonChanged(Button::RightTrigger2, value, _) => {
+                        Some(PadEvent::Axis(PadAxis::RightTrigger, value))
+                    }
+                    EventType::AxisChanged(a, value, _) => {
+                        axis(a, value).map(|(a, value)| PadEvent::Axis(a, value))
+                    }
+                    _ => None,
+                };
+                if let Some(event) = event {
+                    events.push((pad, event));
+                }
+            }
+            events
+        }
+    }
+}
+
+#[cfg(feature = "gamepad")]
+pub use native::GilrsBackend;
+
+/// The controllers of this machine: gilrs when built with the `gamepad`
+/// feature, none otherwise or when it fails to start.
+pub fn default_backend() -> Box<dyn GamepadBackend> {
+    #[cfg(feature = "gamepad")]
+    match GilrsBackend::new() {
+        Ok(backend) => return Box::new(backend),
+        Err(err) => eprintln!("no controller support: {}", err),
+    }
+    Box::new(VirtualGamepad::default())
+}
+
+#[derive(Debug)]
+pub struct GamepadConfig {
+    /// Stick deflection ignored around the center, from 0 to 1.
+    pub deadzone: f32,
+    /// Trigger pull that counts as pressed.
+    pub trigger_threshold: f32,
+    /// Stick deflection that counts as a direction in menus.
+    pub menu_threshold: f32,
+    /// Pixels per second the player moves at with the stick fully tilted.
+    pub speed: f32,
+    /// Same, while focused.
+    pub focus_speed: f32,
+}
+
+impl Default for GamepadConfig {
+    fn default() -> GamepadConfig {
+        GamepadConfig {
+            deadzone: 0.2,
+            trigger_threshold: 0.5,
+            menu_threshold: 0.5,
+            speed: 360.,
+            focus_speed: 150.,
+        }
+    }
+}
+
+/// What a controller holds.
+#[derive(Debug, Default)]
+struct Pad {
+    /// Player it plays, none when every slot is taken.
+    slot: Option<usize>,
+    buttons: HashSet<PadButton>,
+    axes: HashMap<PadAxis, f32>,
+    /// The buttons as game input, as of the last frame.
+    input: InputState,
+    /// Where it moves its player, for the slots past the first one.
+    cursor: Position,
+}
+
+impl Pad {
+    fn held(&self, button: PadButton) -> bool {
+        self.buttons.contains(&button)
+    }
+
+    fn axis(&self, axis: PadAxis) -> f32 {
+        self.axes.get(&axis).cloned().unwrap_or(0.)
+    }
+
+    /// Left stick past the deadzone, rescaled so it starts from zero.
+    fn stick(&self, deadzone: f32) -> (f32, f32) {
+        let (x, y) = (self.axis(PadAxis::LeftX), self.axis(PadAxis::LeftY));
+        let length = (x * x + y * y).sqrt();
+        if length <= deadzone {
+            return (0., 0.);
+        }
+        let scale = ((length - deadzone) / (1. - deadzone)).min(1.) / length;
+        (x * scale, y * scale)
+    }
+
+    fn read(&self, cfg: &GamepadConfig) -> InputState {
+        let (x, y) = self.stick(cfg.deadzone);
+        let fire = self.held(PadButton::South)
+            || self.axis(PadAxis::RightTrigger) >= cfg.trigger_threshold;
+        let bomb = self.held(PadButton::East);
+        InputState {
+            focus: self.held(PadButton::LeftBumper)
+                || self.axis(PadAxis::LeftTrigger) >= cfg.trigger_threshold,
+            fire,
+            bomb,
+            pause: self.held(PadButton::Start),
+            up: self.held(PadButton::DPadUp) || y <= -cfg.menu_threshold,
+            down: self.held(PadButton::DPadDown) || y >= cfg.menu_threshold,
+            left: self.held(PadButton::DPadLeft) || x <= -cfg.menu_threshold,
+            right: self.held(PadButton::DPadRight) || x >= cfg.menu_threshold,
+            confirm: fire,
+            cancel: bomb || self.held(PadButton::Select),
+            ..Default::default()
+        }
+    }
+
+    /// How far the stick and D-pad move the player in `seconds`.
+    fn movement(&self, cfg: &GamepadConfig, seconds: f64) -> (f32, f32) {
+        let (mut x, mut y) = self.stick(cfg.deadzone);
+        let pad = |minus: PadButton, plus: PadButton| {
+            self.held(plus) as i32 as f32 - self.held(minus) as i32 as f32
+        };
+        if (x, y) == (0., 0.) {
+            x = pad(PadButton::DPadLeft, PadButton::DPadRight);
+            y = pad(PadButton::DPadUp, PadButton::DPadDown);
+        }
+        let speed = if self.input.focus {
+            cfg.focus_speed
+        } else {
+            cfg.speed
+        };
+        let step = speed * seconds as f32;
+        (x * step, y * step)
+    }
+}
+
+/// Plugged in controllers and the player slot each one plays. Slots go to
+/// controllers in the order they are plugged in and come free when they are
+/// unplugged.
+#[derive(Debug, Default)]
+pub struct Gamepads {
+    pads: BTreeMap<PadId, Pad>,
+}
+
+impl Gamepads {
+    fn connect(&mut self, id: PadId, field: &PlayField) {
+        if self.pads.contains_key(&id) {
+            return;
+        }
+        let slot = (0..MAX_PLAYERS).find(|slot| self.pads.values().all(|p| p.slot != Some(*slot)));
+        match slot {
+            Some(slot) => println!("controller {} plays player {}", id, slot + 1),
+            None => println!("controller {} connected, every player has one", id),
+        }
+        let cursor = Position {
+            x: field.w / 2.,
+            y: field.h * 0.8,
+        };
+        self.pads.insert(
+            id,
+            Pad {
+                slot,
+                cursor,
+                ..Default::default()
+            },
+        );
+    }
+
+    fn disconnect(&mut self, id: PadId) {
+        if let Some(pad) = self.pads.remove(&id) {
+            println!("controller {} disconnected", id);
+            // hand the slot to a controller still waiting for one
+            if let Some(slot) = pad.slot {
+                if let Some(waiting) = self.pads.values_mut().find(|p| p.slot.is_none()) {
+                    waiting.slot = Some(slot);
+                }
+            }
+        }
+    }
+
+    /// Players a run needs for every controller to play one, at least one
+    /// for the keyboard.
+    pub fn players(&self) -> usize {
+        self.pads
+            .values()
+            .filter_map(|pad| pad.slot)
+            .map(|slot| slot + 1)
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Input of the controller on `slot`, for the slots past the first one
+    /// that the keyboard and mouse do not play.
+    pub fn frame(&self, slot: usize) -> Option<InputFrame> {
+        self.pads
+            .values()
+            .find(|pad| pad.slot == Some(slot))
+            .map(|pad| InputFrame::capture(&pad.input, &pad.cursor))
+    }
+}
+
+/// Sets `field` to `now` when it changed since `before`, like a key press
+/// or release would, so a held key and a released button do not fight.
+fn edge(field: &mut bool, before: bool, now: bool) {
+    if before != now {
+        *field = now;
+    }
+}
+
+/// Reads the controllers. The one of the first player presses the same
+/// input as the keyboard and moves the cursor like the mouse, the others
+/// keep their input in `Gamepads` for `LocalInputSystem`.
+pub struct GamepadSystem {
+    pub backend: Rc<RefCell<Box<dyn GamepadBackend>>>,
+}
+
+impl<'a> System<'a> for GamepadSystem {
+    type SystemData = (
+        Write<'a, Gamepads>,
+        Write<'a, InputState>,
+        Write<'a, GameState>,
+        Read<'a, GamepadConfig>,
+        Read<'a, PlayField>,
+    );
+
+    fn run(&mut self, (mut pads, mut input, mut gs, cfg, field): Self::SystemData) {
+        for (id, event) in self.backend.borrow_mut().poll() {
+            match event {
+                PadEvent::Connected => pads.connect(id, &field),
+                PadEvent::Disconnected => pads.disconnect(id),
+                _ => {
+                    // some backends never report controllers already there
+                    pads.connect(id, &field);
+                    let pad = pads.pads.get_mut(&id).expect("just connected");
+                    match event {
+                        PadEvent::Button(button, true) => {
+                            pad.buttons.insert(button);
+                        }
+                        PadEvent::Button(button, false) => {
+                            pad.buttons.remove(&button);
+                        }
+                        PadEvent::Axis(axis, value) => {
+                            pad.axes.insert(axis, value);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let (w, h) = (field.w, field.h);
+        for pad in pads.pads.values_mut() {
+            let now = pad.read(&cfg);
+            let before = std::mem::replace(&mut pad.input, now.clone());
+            let (dx, dy) = pad.movement(&cfg, gs.real_delta);
+            match pad.slot {
+                Some(0) => {
+                    edge(&mut input.focus, before.focus, now.focus);
+                    edge(&mut input.fire, before.fire, now.fire);
+                    edge(&mut input.bomb, before.bomb, now.bomb);
+                    edge(&mut input.pause, before.pause, now.pause);
+                    edge(&mut input.up, before.up, now.up);
+                    edge(&mut input.down, before.down, now.down);
+                    edge(&mut input.left, before.left, now.left);
+                    edge(&mut input.right, before.right, now.right);
+                    edge(&mut input.confirm, before.confirm, now.confirm);
+                    edge(&mut input.cancel, before.cancel, now.cancel);
+                    if (dx, dy) != (0., 0.) {
+                        let at = &mut gs.mouse_position;
+                        at.x = (at.x + dx).clamp(0., w);
+                        at.y = (at.y + dy).clamp(0., h);
+                    }
+                }
+                Some(_) => {
+                    pad.cursor.x = (pad.cursor.x + dx).clamp(0., w);
+                    pad.cursor.y = (pad.cursor.y + dy).clamp(0., h);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (World, GamepadSystem, VirtualGamepad) {
+        let mut world = World::new();
+        world.insert(Gamepads::default());
+        world.insert(InputState::default());
+        world.insert(GameState {
+            real_delta: 1.,
+            ..GameState::default()
+        });
+        world.insert(GamepadConfig::default());
+        world.insert(PlayField::default());
+        let pad = VirtualGamepad::default();
+        let backend: Box<dyn GamepadBackend> = Box::new(pad.clone());
+        let system = GamepadSystem {
+            backend: Rc::new(RefCell::new(backend)),
+        };
+        (world, system, pad)
+    }
+
+    fn slots(world: &World) -> Vec<(PadId, Option<usize>)> {
+        let pads = world.fetch::<Gamepads>();
+        pads.pads.iter().map(|(id, pad)| (*id, pad.slot)).collect()
+    }
+
+    #[test]
+    fn stick_deadzone_is_rescaled() {
+        let mut pad = Pad::default();
+        pad.axes.insert(PadAxis::LeftX, 0.15);
+        assert_eq!(pad.stick(0.2), (0., 0.));
+        pad.axes.insert(PadAxis::LeftX, 0.6);
+        let (x, y) = pad.stick(0.2);
+        assert!((x - 0.5).abs() < 1e-6);
+        assert_eq!(y, 0.);
+        pad.axes.insert(PadAxis::LeftX, -1.);
+        pad.axes.insert(PadAxis::LeftY, -1.);
+        let (x, y) = pad.stick(0.2);
+        assert!(((x * x + y * y).sqrt() - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn trigger_past_threshold_fires() {
+        let (world, mut system, pad) = setup();
+        pad.axis(0, PadAxis::RightTrigger, 0.4);
+        system.run_now(&world);
+        assert!(!world.fetch::<InputState>().fire);
+        pad.axis(0, PadAxis::RightTrigger, 0.5);
+        system.run_now(&world);
+        assert!(world.fetch::<InputState>().fire);
+        assert!(world.fetch::<InputState>().confirm);
+        pad.axis(0, PadAxis::RightTrigger, 0.);
+        system.run_now(&world);
+        assert!(!world.fetch::<InputState>().fire);
+    }
+
+    #[test]
+    fn slots_follow_connection_order() {
+        let (world, mut system, pad) = setup();
+        pad.connect(4);
+        pad.connect(2);
+        pad.connect(7);
+        system.run_now(&world);
+        assert_eq!(slots(&world), vec![(2, Some(1)), (4, Some(0)), (7, None)]);
+        assert_eq!(world.fetch::<Gamepads>().players(), 2);
+        // an event from a controller never announced connects it too
+        pad.press(9, PadButton::South);
+        system.run_now(&world);
+        assert_eq!(slots(&world)[3], (9, None));
+    }
+
+    #[test]
+    fn disconnect_hands_the_slot_over() {
+        let (world, mut system, pad) = setup();
+        pad.connect(0);
+        pad.connect(1);
+        pad.connect(2);
+        pad.disconnect(0);
+        system.run_now(&world);
+        assert_eq!(slots(&world), vec![(1, Some(1)), (2, Some(0))]);
+        pad.disconnect(2);
+        system.run_now(&world);
+        assert_eq!(slots(&world), vec![(1, Some(1))]);
+        assert_eq!(world.fetch::<Gamepads>().players(), 2);
+        pad.connect(3);
+        system.run_now(&world);
+        assert_eq!(slots(&world), vec![(1, Some(1)), (3, Some(0))]);
+    }
+
+    #[test]
+    fn dpad_movement_stays_in_the_play_field() {
+        let (world, mut system, pad) = setup();
+        pad.connect(0);
+        pad.connect(1);
+        system.run_now(&world);
+        let field = PlayField::default();
+        world.write_resource::<GameState>().mouse_position = Position {
+            x: field.w - 10.,
+            y: 10.,
+        };
+        pad.press(0, PadButton::DPadRight);
+        pad.press(0, PadButton::DPadUp);
+        pad.press(1, PadButton::DPadLeft);
+        pad.press(1, PadButton::DPadDown);
+        system.run_now(&world);
+        let at = world.fetch::<GameState>().mouse_position.clone();
+        assert_eq!((at.x, at.y), (field.w, 0.));
+        let second = world.fetch::<Gamepads>().frame(1).unwrap();
+        assert_eq!((second.x, second.y), (0., field.h));
+    }
+}
@@ -0,0 +1,110 @@
+extern crate specs;
+
+use crate::audio::{Sfx, SfxEvent, SfxEvents};
+use crate::bomb::BombConfig;
+use crate::phy::{Colliders, Sensor, SensorEvents};
+use crate::score::{ScoreEvent, ScoreEvents, ScoreKind};
+use crate::types::{GameState, Position};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use specs::{Component, VecStorage};
+
+#[derive(Clone, Component, Debug, Default, Serialize, Deserialize)]
+#[storage(VecStorage)]
+#[serde(default)]
+pub struct Player {
+    /// Whose input moves this player, see `PlayerInputs`.
+    pub slot: usize,
+    /// Bullets grazed since the start of the run.
+    pub graze: u32,
+    pub meter: f32,
+    pub power: u32,
+    /// Seconds left before bullets can hit the player again.
+    pub invulnerable: f64,
+    /// Frames left to bomb before a hit turns into a lost life.
+    pub dying: Option<u32>,
+    /// Seconds left on the active bomb.
+    pub bombing: f64,
+    /// Seconds before the next shot can be fired.
+    pub shot_cooldown: f64,
+    /// Whether bomb was held during the last tick, bombs only go off on
+    /// press.
+    pub bomb_held: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GrazeConfig {
+    pub hit_radius: f32,
+    pub graze_radius: f32,
+    pub graze_meter: f32,
+    pub meter_max: f32,
+    /// Seconds of invulnerability after being hit.
+    pub hit_invulnerability: f64,
+}
+
+impl Default for GrazeConfig {
+    fn default() -> GrazeConfig {
+        GrazeConfig {
+            hit_radius: 3.,
+            graze_radius: 24.,
+            graze_meter: 0.5,
+            meter_max: 100.,
+            hit_invulnerability: 2.,
+        }
+    }
+}
+
+impl GrazeConfig {
+    /// The hit and graze sensors carried by every player.
+    pub fn player_colliders(&self) -> Colliders {
+        Colliders::new(Sensor::Hitbox, self.hit_radius).with(Sensor::Grazebox, self.graze_radius)
+    }
+}
+
+/// Rewards bullets entering the grazebox and punishes the ones touching the
+/// hitbox. Hits only start the death-bomb window, `BombSystem` decides if
+/// they cost a life.
+pub struct GrazeSystem {}
+impl<'a> System<'a> for GrazeSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, SensorEvents>,
+        Read<'a, GrazeConfig>,
+        Read<'a, BombConfig>,
+        WriteStorage<'a, Player>,
+        Read<'a, GameState>,
+        Write<'a, ScoreEvents>,
+        ReadStorage<'a, Position>,
+        Write<'a, SfxEvents>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, events, cfg, bomb, mut players, gs, mut score, positions, mut sfx): Self::SystemData,
+    ) {
+        for player in (&mut players).join() {
+            player.invulnerable = (player.invulnerable - gs.delta).max(0.);
+        }
+
+        for ev in events.events.iter().filter(|ev| ev.started) {
+            if let Some((entity, _bullet)) = ev.between(Sensor::Grazebox, Sensor::Bullet) {
+                if let Some(player) = players.get_mut(entity) {
+                    player.graze += 1;
+                    player.meter = (player.meter + cfg.graze_meter).min(cfg.meter_max);
+                    score.events.push(ScoreEvent::new(ScoreKind::Graze));
+                    if let Some(pos) = positions.get(entity) {
+                        sfx.single_write(SfxEvent::at(Sfx::Graze, pos));
+                    }
+                }
+            } else if let Some((entity, bullet)) = ev.between(Sensor::Hitbox, Sensor::Bullet) {
+                if let Some(player) = players.get_mut(entity) {
+                    if player.invulnerable > 0. || player.dying.is_some() {
+                        continue;
+                    }
+                    player.dying = Some(bomb.deathbomb_frames.get(gs.difficulty));
+                    let _ = entities.delete(bullet);
+                }
+            }
+        }
+    }
+}
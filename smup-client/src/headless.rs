@@ -0,0 +1,363 @@
+extern crate specs;
+
+use crate::assets::Assets;
+use crate::boss::BossDef;
+use crate::game;
+use crate::game_loop::{Frontend, GameLoop, Phase};
+use crate::net::{NetConfig, NetSession};
+use crate::online::OnlineClient;
+use crate::replay::{Playback, Recording, Replay};
+use crate::score::Score;
+use crate::sim::{self, Checksum, Rng, STEP};
+use crate::snapshot::Snapshot;
+use crate::stage::Stage;
+use crate::types::{GameState, InputFrame, InputState, Position};
+use serde::Deserialize;
+use specs::prelude::*;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Input held from `tick` on, until the next step.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ScriptStep {
+    pub tick: u64,
+    #[serde(default)]
+    pub input: InputState,
+    /// Where the player moves to, as if the mouse was there.
+    #[serde(default)]
+    pub position: Option<(f32, f32)>,
+}
+
+/// Input for a run without a player, ordered by tick.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct InputScript {
+    pub steps: Vec<ScriptStep>,
+}
+
+impl InputScript {
+    pub fn load(path: &str) -> Result<InputScript, Box<dyn Error>> {
+        let mut script: InputScript = ron::de::from_str(&fs::read_to_string(path)?)?;
+        script.steps.sort_by_key(|step| step.tick);
+        Ok(script)
+    }
+
+    /// Input of one player for `tick`, from the steps up to it.
+    pub fn frame_at(&self, tick: u64) -> InputFrame {
+        let mut input = InputState::default();
+        let mut at = Position::default();
+        for step in self.steps.iter().take_while(|step| step.tick <= tick) {
+            input = step.input.clone();
+            if let Some((x, y)) = step.position {
+                at = Position { x, y };
+            }
+        }
+        InputFrame::capture(&input, &at)
+    }
+}
+
+/// Stands in for the window: every phase is an update of one step, fed the
+/// scripted input.
+pub struct ScriptedFrontend {
+    script: InputScript,
+    next: usize,
+}
+
+impl ScriptedFrontend {
+    pub fn new(script: InputScript) -> ScriptedFrontend {
+        ScriptedFrontend { script, next: 0 }
+    }
+}
+
+impl Frontend for ScriptedFrontend {
+    fn next_phase(&mut self, world: &mut World) -> Option<Phase> {
+        let mut gs = world.write_resource::<GameState>();
+        let mut input = world.write_resource::<InputState>();
+        // the input of a step is held from its own tick on, as in
+        // `InputScript::frame_at`
+        let tick = u64::from(gs.tick);
+        while let Some(step) = self.script.steps.get(self.next) {
+            if step.tick > tick {
+                break;
+            }
+            *input = step.input.clone();
+            if let Some((x, y)) = step.position {
+                gs.mouse_position = Position { x, y };
+            }
+            self.next += 1;
+        }
+        Some(Phase::Update(STEP))
+    }
+}
+
+/// How a headless run ended.
+#[derive(Clone, Debug)]
+pub struct Outcome {
+    pub seed: u64,
+    pub ticks: u32,
+    pub score: u64,
+    pub lives: u32,
+    pub cleared: bool,
+    pub checksum: u64,
+}
+
+impl Outcome {
+    pub fn of(world: &World) -> Outcome {
+        let gs = world.fetch::<GameState>();
+        Outcome {
+            seed: world.fetch::<Rng>().seed,
+            ticks: gs.tick,
+            score: world.fetch::<Score>().total,
+            lives: gs.lives,
+            cleared: world.fetch::<Stage>().cleared,
+            checksum: world.fetch::<Checksum>().value,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "seed {} ticks {} score {} lives {} cleared {} checksum {:016x}",
+            self.seed, self.ticks, self.score, self.lives, self.cleared, self.checksum
+        )
+    }
+}
+
+/// Runs the gameplay systems without a window or GPU, one fixed step per
+/// tick, until the run is over or `max_ticks` is reached.
+pub struct Headless {
+    pub world: World,
+    game_loop: GameLoop<ScriptedFrontend>,
+    dispatcher: Dispatcher<'static, 'static>,
+    ticks: u64,
+}
+
+impl Headless {
+    pub fn new(boss: &BossDef, script: InputScript, seed: u64) -> Result<Headless, Box<dyn Error>> {
+        let mut headless = Headless::build(script)?;
+        game::start_run(&mut headless.world, boss, seed, 1);
+        Ok(headless)
+    }
+
+    /// Carries on the run saved in `snapshot`, the script picking up from
+    /// the tick it was taken at.
+    pub fn from_snapshot(
+        script: InputScript,
+        snapshot: Snapshot,
+    ) -> Result<Headless, Box<dyn Error>> {
+        let mut headless = Headless::build(script)?;
+        snapshot.restore(&mut headless.world);
+        headless.ticks = u64::from(headless.world.fetch::<GameState>().tick);
+        Ok(headless)
+    }
+
+    fn build(script: InputScript) -> Result<Headless, Box<dyn Error>> {
+        let mut world = game::create_world();
+        game::insert_resources(&mut world, &mut Assets::default())?;
+        // nothing is drawn
+        let render = DispatcherBuilder::new().build();
+        let game_loop = GameLoop::new(&mut world, ScriptedFrontend::new(script), render);
+        let mut dispatcher = game::gameplay(DispatcherBuilder::new()).build();
+        dispatcher.setup(&mut world);
+        Ok(Headless {
+            world,
+            game_loop,
+            dispatcher,
+            ticks: 0,
+        })
+    }
+
+    pub fn step(&mut self) {
+        let dispatcher = &mut self.dispatcher;
+        self.game_loop.step(&mut self.world, |world| {
+            dispatcher.dispatch(world);
+            sim::maintain(world);
+            true
+        });
+        self.ticks += 1;
+    }
+
+    /// Checksum of the world after the last tick.
+    pub fn checksum(&self) -> u64 {
+        self.world.fetch::<Checksum>().value
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.world.fetch::<GameState>().lives == 0 || self.world.fetch::<Stage>().cleared
+    }
+
+    pub fn run(&mut self, max_ticks: u64) -> Outcome {
+        while self.ticks < max_ticks && !self.is_over() {
+            self.step();
+        }
+        Outcome::of(&self.world)
+    }
+
+    /// Replay of the run so far.
+    pub fn replay(&self) -> Replay {
+        self.world.fetch::<Recording>().finish(
+            self.world.fetch::<Rng>().seed,
+            self.world.fetch::<Stage>().number,
+            self.world.fetch::<GameState>().difficulty,
+        )
+    }
+}
+
+/// Plays a replay back without a window, as fast as possible.
+pub fn play_replay(boss: &BossDef, replay: Replay) -> Result<Outcome, Box<dyn Error>> {
+    let mut world = game::create_world();
+    game::insert_resources(&mut world, &mut Assets::default())?;
+    let mut playback = Playback::new(&mut world, replay, boss);
+    while playback.tick(&mut world) {}
+    Ok(Outcome::of(&world))
+}
+
+/// Plays a two player run over the network with the scripted input for
+/// this machine, in real time. Returns how it ended and the session, for
+/// its statistics.
+pub fn play_netplay(
+    boss: &BossDef,
+    config: NetConfig,
+    script: &InputScript,
+    seed: u64,
+    max_ticks: u32,
+) -> Result<(Outcome, NetSession), Box<dyn Error>> {
+    let mut world = game::create_world();
+    game::insert_resources(&mut world, &mut Assets::default())?;
+    let mut session = NetSession::connect(&mut world, config, boss, seed)?;
+    session.stop_at = max_ticks;
+    let started = Instant::now();
+    let mut frames = 0;
+    let outcome = loop {
+        let frame = script.frame_at(u64::from(session.input_tick()));
+        session.update(&mut world, Some(frame))?;
+        if let Some(outcome) = session.finished() {
+            break outcome.clone();
+        }
+        frames += 1;
+        let next = started + Duration::from_secs_f64(frames as f64 * STEP);
+        if let Some(wait) = next.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+    };
+    session.linger(&mut world);
+    Ok((outcome, session))
+}
+
+/// Plays on a server with the scripted input, in real time, for
+/// `max_ticks` ticks or until the run is over. Stands in for a player in
+/// load tests.
+pub fn play_online(
+    local: SocketAddr,
+    server: SocketAddr,
+    script: &InputScript,
+    max_ticks: u32,
+) -> Result<OnlineClient, Box<dyn Error>> {
+    let mut world = game::create_world();
+    game::insert_resources(&mut world, &mut Assets::default())?;
+    let mut client = OnlineClient::connect(local, server)?;
+    let started = Instant::now();
+    for tick in 0..max_ticks {
+        let frame = script.frame_at(u64::from(tick));
+        client.update(&mut world, Some(frame), STEP)?;
+        if client.is_over() {
+            break;
+        }
+        let next = started + Duration::from_secs_f64(f64::from(tick + 1) * STEP);
+        if let Some(wait) = next.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+    Ok(client)
+}
+
+/// Plays the same run twice side by side and compares the world checksums
+/// after every tick. With `snapshot_at`, the second run is saved to RON at
+/// that tick and carried on from the loaded snapshot. Returns the number of
+/// ticks that matched, or the first tick where the runs went apart.
+pub fn verify_determinism(
+    boss: &BossDef,
+    script: &InputScript,
+    seed: u64,
+    max_ticks: u64,
+    snapshot_at: Option<u64>,
+) -> Result<u64, Box<dyn Error>> {
+    let mut first = Headless::new(boss, script.clone(), seed)?;
+    let mut second = Headless::new(boss, script.clone(), seed)?;
+    while first.ticks < max_ticks && !first.is_over() {
+        if Some(first.ticks) == snapshot_at {
+            let saved = Snapshot::take(&second.world).to_ron()?;
+            second = Headless::from_snapshot(script.clone(), Snapshot::from_ron(&saved)?)?;
+        }
+        first.step();
+        second.step();
+        if first.checksum() != second.checksum() {
+            return Err(format!(
+                "runs diverged at tick {}: {:016x} != {:016x}",
+                first.ticks,
+                first.checksum(),
+                second.checksum()
+            )
+            .into());
+        }
+    }
+    Ok(first.ticks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PlayerInputs;
+
+    const SEED: u64 = 7;
+
+    fn guardian() -> (BossDef, InputScript) {
+        (
+            BossDef::load("assets/bosses/guardian.ron").unwrap(),
+            InputScript::load("assets/scripts/attack.ron").unwrap(),
+        )
+    }
+
+    #[test]
+    fn guardian_is_deterministic() {
+        let (boss, script) = guardian();
+        let ticks = verify_determinism(&boss, &script, SEED, 2000, None).unwrap();
+        assert!(ticks > 300);
+    }
+
+    #[test]
+    fn guardian_is_deterministic_across_a_snapshot() {
+        let (boss, script) = guardian();
+        for &at in [1, 300].iter() {
+            let ticks = verify_determinism(&boss, &script, SEED, 2000, Some(at)).unwrap();
+            assert!(ticks > at);
+        }
+    }
+
+    #[test]
+    fn frontend_plays_the_script_like_frame_at() {
+        let (boss, _) = guardian();
+        let step = |tick, fire, x| ScriptStep {
+            tick,
+            input: InputState {
+                fire,
+                ..Default::default()
+            },
+            position: Some((x, 100.)),
+        };
+        let script = InputScript {
+            steps: vec![step(0, false, 10.), step(3, true, 20.), step(5, false, 30.)],
+        };
+        let mut headless = Headless::new(&boss, script.clone(), SEED).unwrap();
+        for tick in 0..8 {
+            headless.step();
+            let played = headless.world.fetch::<PlayerInputs>().get(0);
+            assert_eq!(played, script.frame_at(tick), "tick {}", tick);
+        }
+    }
+}
@@ -0,0 +1,377 @@
+extern crate piston;
+extern crate specs;
+
+use crate::registry::{Fields, Registry};
+use crate::render::Sprite;
+use crate::types::Position;
+use piston::input::Key;
+use specs::prelude::*;
+use std::cmp::Ordering;
+use std::error::Error;
+use std::sync::Arc;
+
+/// Distance from an entity without a sprite that still picks it.
+const PICK_RADIUS: f32 = 8.;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum View {
+    #[default]
+    Entities,
+    Resources,
+}
+
+/// What a row shows: a component of the selected entity or a resource,
+/// either whole or one of its fields.
+#[derive(Clone, Debug, PartialEq)]
+enum Target {
+    Component(&'static str, Option<String>),
+    Resource(&'static str, Option<String>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Row {
+    pub label: String,
+    /// The value as ron.
+    pub value: String,
+    /// What editing the row changes, nothing for headers.
+    target: Option<Target>,
+}
+
+impl Row {
+    pub fn editable(&self) -> bool {
+        self.target.is_some()
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Action {
+    Pick(Position),
+    /// Goes to the next entity or resource, or back.
+    Step(isize),
+    Apply(usize, String),
+}
+
+/// Lists the entities with their components, and the resources, letting
+/// their fields be edited as ron. Keys and clicks are given by the window,
+/// `update` carries them out.
+#[derive(Debug, Default)]
+pub struct Inspector {
+    pub shown: bool,
+    pub view: View,
+    pub entity: Option<Entity>,
+    /// Where the selected entity is, to mark it.
+    pub at: Option<Position>,
+    resource: usize,
+    /// The selected row.
+    pub row: usize,
+    pub rows: Vec<Row>,
+    /// Text of the row being edited.
+    pub editing: Option<String>,
+    /// How the last edit went.
+    pub message: Option<String>,
+    actions: Vec<Action>,
+}
+
+impl Inspector {
+    pub fn key(&mut self, key: Key) {
+        if let Some(text) = self.editing.as_mut() {
+            match key {
+                Key::Return => {
+                    let text = self.editing.take().unwrap_or_default();
+                    self.actions.push(Action::Apply(self.row, text));
+                }
+                Key::Escape => self.editing = None,
+                Key::Backspace => {
+                    text.pop();
+                }
+                _ => {}
+            }
+            return;
+        }
+        match key {
+            Key::Tab => {
+                self.view = match self.view {
+                    View::Entities => View::Resources,
+                    View::Resources => View::Entities,
+                };
+                self.row = 0;
+            }
+            Key::Up => self.row = self.row.saturating_sub(1),
+            Key::Down => self.row = (self.row + 1).min(self.rows.len().saturating_sub(1)),
+            Key::Left | Key::PageUp => self.actions.push(Action::Step(-1)),
+            Key::Right | Key::PageDown => self.actions.push(Action::Step(1)),
+            Key::Return => {
+                if let Some(row) = self.rows.get(self.row).filter(|row| row.editable()) {
+                    self.editing = Some(row.value.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Typed text, going to the row being edited.
+    pub fn text(&mut self, text: &str) {
+        if let Some(editing) = self.editing.as_mut() {
+            editing.extend(text.chars().filter(|c| !c.is_control()));
+        }
+    }
+
+    /// Selects the entity under `at`, in play field coordinates.
+    pub fn click(&mut self, at: Position) {
+        self.actions.push(Action::Pick(at));
+    }
+}
+
+/// Carries out what was asked of the inspector and lists the rows again.
+pub fn update(world: &mut World) {
+    if !world.fetch::<Inspector>().shown {
+        return;
+    }
+    // a handle of its own, the world's would stay borrowed while resources
+    // are replaced
+    let registry = Arc::clone(&world.fetch::<Arc<Registry>>());
+    let actions = std::mem::take(&mut world.write_resource::<Inspector>().actions);
+    for action in actions {
+        match action {
+            Action::Pick(at) => {
+                let picked = pick(world, &at);
+                let mut inspector = world.write_resource::<Inspector>();
+                match picked {
+                    Some(entity) => {
+                        inspector.view = View::Entities;
+                        inspector.entity = Some(entity);
+                        inspector.row = 0;
+                        inspector.message = None;
+                    }
+                    None => inspector.message = Some("nothing there".to_string()),
+                }
+            }
+            Action::Step(by) => step(world, &registry, by),
+            Action::Apply(row, text) => {
+                let target = world
+                    .fetch::<Inspector>()
+                    .rows
+                    .get(row)
+                    .and_then(|row| row.target.clone());
+                let message = match target.map(|target| apply(world, &registry, &target, &text)) {
+                    Some(Ok(())) => None,
+                    Some(Err(err)) => Some(err.to_string()),
+                    None => None,
+                };
+                world.write_resource::<Inspector>().message = message;
+            }
+        }
+    }
+    let (view, entity, resource) = {
+        let inspector = world.fetch::<Inspector>();
+        (inspector.view, inspector.entity, inspector.resource)
+    };
+    let (rows, entity) = match view {
+        View::Entities => entity_rows(world, &registry, entity),
+        View::Resources => (resource_rows(world, &registry, resource), entity),
+    };
+    let at = entity.and_then(|entity| world.read_storage::<Position>().get(entity).cloned());
+    let mut inspector = world.write_resource::<Inspector>();
+    inspector.row = inspector.row.min(rows.len().saturating_sub(1));
+    inspector.rows = rows;
+    inspector.entity = entity;
+    inspector.at = at;
+}
+
+/// The entity under `at`: in its sprite, or near it without one, the
+/// closest if there are several.
+fn pick(world: &World, at: &Position) -> Option<Entity> {
+    let (entities, positions, sprites) = (
+        world.entities(),
+        world.read_storage::<Position>(),
+        world.read_storage::<Sprite>(),
+    );
+    let distance = |pos: &Position| ((pos.x - at.x).powi(2) + (pos.y - at.y).powi(2)).sqrt();
+    (&entities, &positions, sprites.maybe())
+        .join()
+        .filter(|(_, pos, sprite)| match sprite {
+            Some(sprite) => {
+                let (x, y) = (pos.x - sprite.pivot.x, pos.y - sprite.pivot.y);
+                (x..=x + sprite.size.w).contains(&at.x) && (y..=y + sprite.size.h).contains(&at.y)
+            }
+            None => distance(pos) <= PICK_RADIUS,
+        })
+        .min_by(|a, b| {
+            distance(a.1)
+                .partial_cmp(&distance(b.1))
+                .unwrap_or(Ordering::Equal)
+        })
+        .map(|(entity, _, _)| entity)
+}
+
+fn step(world: &World, registry: &Registry, by: isize) {
+    let mut inspector = world.write_resource::<Inspector>();
+    inspector.row = 0;
+    match inspector.view {
+        View::Entities => {
+            let entities: Vec<Entity> = world.entities().join().collect();
+            let current = inspector
+                .entity
+                .and_then(|entity| entities.iter().position(|e| *e == entity))
+                .unwrap_or(0);
+            inspector.entity = entities.get(wrap(current, by, entities.len())).cloned();
+        }
+        View::Resources => {
+            inspector.resource = wrap(inspector.resource, by, registry.resources().count());
+        }
+    }
+}
+
+fn wrap(index: usize, by: isize, count: usize) -> usize {
+    if count == 0 {
+        return 0;
+    }
+    (index as isize + by).rem_euclid(count as isize) as usize
+}
+
+fn entity_rows(
+    world: &World,
+    registry: &Registry,
+    selected: Option<Entity>,
+) -> (Vec<Row>, Option<Entity>) {
+    let entities: Vec<Entity> = world.entities().join().collect();
+    let index = selected
+        .and_then(|entity| entities.iter().position(|e| *e == entity))
+        .unwrap_or(0);
+    let entity = match entities.get(index) {
+        Some(entity) => *entity,
+        None => return (vec![header("entity", "none".to_string())], None),
+    };
+    let mut rows = vec![header(
+        "entity",
+        format!("{} ({} of {})", entity.id(), index + 1, entities.len()),
+    )];
+    for component in registry.components() {
+        let name = component.name();
+        let saved = component.save(world, entity).and_then(|ron| match ron {
+            Some(ron) => Ok(Some((ron, component.save_fields(world, entity)?))),
+            None => Ok(None),
+        });
+        match saved {
+            Ok(Some((ron, fields))) => rows.extend(value_rows(name, ron, fields, |field| {
+                Target::Component(name, field)
+            })),
+            Ok(None) => {}
+            Err(err) => rows.push(header(name, err.to_string())),
+        }
+    }
+    (rows, Some(entity))
+}
+
+fn resource_rows(world: &World, registry: &Registry, index: usize) -> Vec<Row> {
+    let count = registry.resources().count();
+    let resource = match registry.resources().nth(index) {
+        Some(resource) => resource,
+        None => return Vec::new(),
+    };
+    let name = resource.name();
+    let mut rows = vec![header(
+        "resource",
+        format!("{} ({} of {})", name, index + 1, count),
+    )];
+    let saved = resource.save(world).and_then(|ron| match ron {
+        Some(ron) => Ok(Some((ron, resource.save_fields(world)?))),
+        None => Ok(None),
+    });
+    match saved {
+        Ok(Some((ron, fields))) => rows.extend(value_rows(name, ron, fields, |field| {
+            Target::Resource(name, field)
+        })),
+        Ok(None) => rows.push(header(name, "missing".to_string())),
+        Err(err) => rows.push(header(name, err.to_string())),
+    }
+    rows
+}
+
+fn header(label: &str, value: String) -> Row {
+    Row {
+        label: label.to_string(),
+        value,
+        target: None,
+    }
+}
+
+/// A row for every one of `fields`, or one for all of `ron` when the value
+/// is not a struct.
+fn value_rows(
+    name: &str,
+    ron: String,
+    fields: Option<Fields>,
+    target: impl Fn(Option<String>) -> Target,
+) -> Vec<Row> {
+    match fields {
+        Some(fields) => fields
+            .into_iter()
+            .map(|(field, value)| Row {
+                label: format!("{}.{}", name, field),
+                value,
+                target: Some(target(Some(field))),
+            })
+            .collect(),
+        None => vec![Row {
+            label: name.to_string(),
+            value: ron,
+            target: Some(target(None)),
+        }],
+    }
+}
+
+/// Replaces what `target` points at with `text`, reading it back through
+/// the registry so a bad value is turned down.
+fn apply(
+    world: &mut World,
+    registry: &Registry,
+    target: &Target,
+    text: &str,
+) -> Result<(), Box<dyn Error>> {
+    // the other fields as they are, each written by ron on its own
+    let replace = |fields: Option<Fields>, field: &str| -> Result<String, Box<dyn Error>> {
+        let mut fields = fields.ok_or("it is gone or not a struct anymore")?;
+        let value = fields
+            .iter_mut()
+            .find(|(name, _)| name == field)
+            .ok_or_else(|| format!("no field named {}", field))?;
+        value.1 = text.to_string();
+        Ok(join(&fields))
+    };
+    match target {
+        Target::Component(name, field) => {
+            let entity = world
+                .fetch::<Inspector>()
+                .entity
+                .ok_or("no entity selected")?;
+            let component = registry.component(name).ok_or("unknown component")?;
+            let ron = match field {
+                Some(field) => replace(component.save_fields(world, entity)?, field)?,
+                None => text.to_string(),
+            };
+            component
+                .load(world, entity, &ron)
+                .map_err(|err| format!("{}: {}", name, err))?;
+        }
+        Target::Resource(name, field) => {
+            let resource = registry.resource(name).ok_or("unknown resource")?;
+            let ron = match field {
+                Some(field) => replace(resource.save_fields(world)?, field)?,
+                None => text.to_string(),
+            };
+            resource
+                .load(world, &ron)
+                .map_err(|err| format!("{}: {}", name, err))?;
+        }
+    }
+    Ok(())
+}
+
+fn join(fields: &[(String, String)]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| format!("{}:{}", name, value))
+        .collect();
+    format!("({})", fields.join(","))
+}
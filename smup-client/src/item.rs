@@ -0,0 +1,306 @@
+extern crate specs;
+
+use crate::audio::{Sfx, SfxEvent, SfxEvents};
+use crate::graze::Player;
+use crate::render::{Color, Pivot, Size, Sprite};
+use crate::score::{ScoreEvent, ScoreEvents, ScoreKind};
+use crate::sim::Rng;
+use crate::types::{GameState, PlayField, Position, Velocity};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use specs::{Component, VecStorage};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ItemKind {
+    Power,
+    Point,
+    BombPiece,
+    LifePiece,
+    /// Left behind by bullets cancelled with a bomb.
+    Cancel,
+}
+
+#[derive(Clone, Component, Debug, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Item {
+    pub kind: ItemKind,
+    /// Attracted items ignore gravity and fly straight to the closest player.
+    pub attracted: bool,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ItemDrop {
+    pub kind: ItemKind,
+    pub count: u32,
+}
+
+/// Items spawned where the entity dies.
+#[derive(Clone, Component, Debug, Default, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Drops {
+    pub items: Vec<ItemDrop>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ItemDef {
+    /// Score for points and cancels, power units for power and pieces for
+    /// bomb and life pieces.
+    pub value: u32,
+    pub size: f32,
+    pub color: [f32; 4],
+    /// Upwards speed given to the item when it drops.
+    pub pop_speed: f32,
+    pub gravity: f32,
+    pub max_fall_speed: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ItemConfig {
+    /// Players above this line collect every item on screen at full value.
+    pub collection_line: f32,
+    pub magnet_radius: f32,
+    pub collect_radius: f32,
+    pub attract_speed: f32,
+    /// Fraction of a point item value left when collected at the bottom of
+    /// the play field, the value grows linearly up to the collection line.
+    pub min_point_value: f32,
+    pub max_power: u32,
+    pub pieces_per_bomb: u32,
+    pub pieces_per_life: u32,
+    pub items: HashMap<ItemKind, ItemDef>,
+}
+
+impl Default for ItemConfig {
+    fn default() -> ItemConfig {
+        ItemConfig {
+            collection_line: 120.,
+            magnet_radius: 48.,
+            collect_radius: 12.,
+            attract_speed: 400.,
+            min_point_value: 0.1,
+            max_power: 400,
+            pieces_per_bomb: 5,
+            pieces_per_life: 5,
+            items: HashMap::new(),
+        }
+    }
+}
+
+impl ItemConfig {
+    /// What a point item is worth when collected at height `y`.
+    pub fn point_value(&self, base: u32, y: f32, field: &PlayField) -> u64 {
+        let span = (field.h - self.collection_line).max(1.);
+        let depth = ((y - self.collection_line) / span).clamp(0., 1.);
+        let ratio = 1. - depth * (1. - self.min_point_value);
+        (base as f32 * ratio) as u64
+    }
+}
+
+pub fn spawn_item(
+    entities: &Entities,
+    lazy: &LazyUpdate,
+    cfg: &ItemConfig,
+    kind: ItemKind,
+    at: (f32, f32),
+    attracted: bool,
+) {
+    let def = match cfg.items.get(&kind) {
+        Some(def) => def,
+        None => return,
+    };
+    let [r, g, b, a] = def.color;
+    let item = entities.create();
+    lazy.insert(item, Item { kind, attracted });
+    lazy.insert(item, Position { x: at.0, y: at.1 });
+    lazy.insert(
+        item,
+        Velocity {
+            x: 0.,
+            y: -def.pop_speed,
+        },
+    );
+    lazy.insert(
+        item,
+        Sprite {
+            color: Color { r, g, b, a },
+            size: Size {
+                w: def.size,
+                h: def.size,
+            },
+            pivot: Pivot {
+                x: def.size / 2.,
+                y: def.size / 2.,
+            },
+            region: None,
+        },
+    );
+}
+
+/// Spawns the drops of an entity, spread horizontally around `at`.
+pub fn spawn_drops(
+    entities: &Entities,
+    lazy: &LazyUpdate,
+    cfg: &ItemConfig,
+    drops: &Drops,
+    at: &Position,
+    rng: &mut Rng,
+) {
+    let total: u32 = drops.items.iter().map(|d| d.count).sum();
+    let mut i = 0;
+    for drop in drops.items.iter() {
+        for _ in 0..drop.count {
+            let offset = (i as f32 - (total as f32 - 1.) / 2.) * 8. + rng.range(-4., 4.);
+            let height = rng.range(-8., 8.);
+            spawn_item(
+                entities,
+                lazy,
+                cfg,
+                drop.kind,
+                (at.x + offset, at.y + height),
+                false,
+            );
+            i += 1;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn collect(
+    kind: ItemKind,
+    def: &ItemDef,
+    pos: &Position,
+    player: &mut Player,
+    gs: &mut GameState,
+    score: &mut ScoreEvents,
+    cfg: &ItemConfig,
+    field: &PlayField,
+    full_value: bool,
+) {
+    match kind {
+        ItemKind::Power => player.power = (player.power + def.value).min(cfg.max_power),
+        ItemKind::Point => {
+            let value = if full_value {
+                def.value as u64
+            } else {
+                cfg.point_value(def.value, pos.y, field)
+            };
+            score
+                .events
+                .push(ScoreEvent::with_points(ScoreKind::PointItem, value))
+        }
+        ItemKind::Cancel => score
+            .events
+            .push(ScoreEvent::with_points(ScoreKind::Cancel, def.value as u64)),
+        ItemKind::BombPiece => {
+            let per_bomb = cfg.pieces_per_bomb.max(1);
+            gs.bomb_pieces += def.value;
+            gs.bombs += gs.bomb_pieces / per_bomb;
+            gs.bomb_pieces %= per_bomb;
+        }
+        ItemKind::LifePiece => {
+            let per_life = cfg.pieces_per_life.max(1);
+            gs.life_pieces += def.value;
+            gs.lives += gs.life_pieces / per_life;
+            gs.life_pieces %= per_life;
+        }
+    }
+}
+
+/// Moves items, attracts them to players and applies their effect once
+/// collected.
+pub struct ItemSystem {}
+impl<'a> System<'a> for ItemSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, ItemConfig>,
+        Read<'a, PlayField>,
+        Write<'a, GameState>,
+        Write<'a, ScoreEvents>,
+        WriteStorage<'a, Player>,
+        WriteStorage<'a, Item>,
+        WriteStorage<'a, Position>,
+        WriteStorage<'a, Velocity>,
+        Write<'a, SfxEvents>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            cfg,
+            field,
+            mut gs,
+            mut score,
+            mut players,
+            mut items,
+            mut positions,
+            mut velocities,
+            mut sfx,
+        ): Self::SystemData,
+    ) {
+        let targets: Vec<(Entity, f32, f32)> = (&entities, &players, &positions)
+            .join()
+            .map(|(e, _, pos)| (e, pos.x, pos.y))
+            .collect();
+        let collect_all = targets.iter().any(|(_, _, y)| *y <= cfg.collection_line);
+        let dt = gs.delta as f32;
+
+        for (entity, item, pos, vel) in
+            (&entities, &mut items, &mut positions, &mut velocities).join()
+        {
+            let def = match cfg.items.get(&item.kind) {
+                Some(def) => def,
+                None => continue,
+            };
+            let closest = targets
+                .iter()
+                .map(|(e, x, y)| (*e, x - pos.x, y - pos.y))
+                .min_by(|a, b| {
+                    let (da, db) = (a.1 * a.1 + a.2 * a.2, b.1 * b.1 + b.2 * b.2);
+                    da.partial_cmp(&db).unwrap_or(std::cmp::Ordering::Equal)
+                });
+            let attraction = closest.and_then(|(player, dx, dy)| {
+                let dist = (dx * dx + dy * dy).sqrt();
+                if item.attracted || collect_all || dist <= cfg.magnet_radius {
+                    Some((player, dx, dy, dist))
+                } else {
+                    None
+                }
+            });
+            item.attracted = attraction.is_some();
+
+            if let Some((player, dx, dy, dist)) = attraction {
+                if dist <= cfg.collect_radius {
+                    if let Some(player) = players.get_mut(player) {
+                        sfx.single_write(SfxEvent::at(Sfx::Pickup, pos));
+                        collect(
+                            item.kind,
+                            def,
+                            pos,
+                            player,
+                            &mut gs,
+                            &mut score,
+                            &cfg,
+                            &field,
+                            collect_all,
+                        );
+                    }
+                    let _ = entities.delete(entity);
+                    continue;
+                }
+                let step = (cfg.attract_speed * dt).min(dist);
+                pos.x += dx / dist * step;
+                pos.y += dy / dist * step;
+                vel.x = 0.;
+                vel.y = 0.;
+            } else {
+                vel.x = 0.;
+                vel.y = (vel.y + def.gravity * dt).min(def.max_fall_speed);
+                if pos.y > field.h + def.size {
+                    let _ = entities.delete(entity);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,35 @@
+//! The game itself, shared by the windowed client and the server.
+
+extern crate specs;
+
+pub mod assets;
+pub mod audio;
+pub mod bomb;
+pub mod boss;
+pub mod bullet;
+pub mod enemy;
+pub mod game;
+pub mod game_loop;
+pub mod gamepad;
+pub mod graze;
+pub mod headless;
+pub mod inspector;
+pub mod item;
+pub mod menu;
+pub mod net;
+pub mod online;
+pub mod phy;
+pub mod prefab;
+pub mod registry;
+pub mod reload;
+pub mod render;
+pub mod replay;
+pub mod scene;
+pub mod score;
+pub mod server;
+pub mod settings;
+pub mod shot;
+pub mod sim;
+pub mod snapshot;
+pub mod stage;
+pub mod types;
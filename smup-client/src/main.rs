@@ -3,183 +3,1239 @@ extern crate piston;
 extern crate piston_window;
 extern crate specs;
 
-mod types;
-mod phy;
-mod render;
-
-use types::{GameState, Position};
-
-use phy::PhysicsSystem;
 use piston_window::*;
-use render::Sprite;
+use smup_client::assets::{Asset, Assets, FontData, Handle, ImageData, LoadingSystem, SoundData};
+use smup_client::audio::{self, Audio, AudioConfig, AudioSystem, SfxTable};
+use smup_client::bomb::BombConfig;
+use smup_client::boss::{Boss, BossDef};
+use smup_client::enemy::Enemy;
+use smup_client::game;
+use smup_client::game_loop::{Frontend, GameLoop, Phase};
+use smup_client::gamepad::{default_backend, GamepadBackend, GamepadSystem, Gamepads};
+use smup_client::graze::{GrazeConfig, Player};
+use smup_client::headless::{
+    play_netplay, play_online, play_replay, verify_determinism, Headless, InputScript,
+};
+use smup_client::inspector::{self, Inspector, View};
+use smup_client::item::ItemConfig;
+use smup_client::menu::{Menu, MenuSystem};
+use smup_client::net::{NetConfig, NetSession, Shim};
+use smup_client::online::OnlineClient;
+use smup_client::phy::{PhysicsDebug, PhysicsDebugSystem, Sensor};
+use smup_client::prefab::Prefabs;
+use smup_client::reload::{PollingReload, ReloadErrors};
+use smup_client::render::{DebugOverlay, Interpolation, InterpolationSystem, Sprite, SpriteAtlas};
+use smup_client::replay::{
+    Playback, Recording, Replay, ReplayControl, ReplayControlSystem, ReplaySelection,
+};
+use smup_client::scene::{PlayFlowSystem, Scene, SceneControl, SceneStack, Transition};
+use smup_client::score::{HiScore, HiScores, Score, ScoreConfig};
+use smup_client::settings::Settings;
+use smup_client::sim::{self, Rng};
+use smup_client::snapshot::{QuickSaveSystem, SaveStateControl, Snapshot, StateRequest};
+use smup_client::stage::Stage;
+use smup_client::types::{GameState, InputFrame, InputState, PlayField, Position, Velocity};
 use specs::prelude::*;
-use specs::Component;
-use std::sync::{Arc, Mutex};
-
-#[derive(Default, Component, Debug)]
-#[storage(NullStorage)]
-struct MouseTracker {}
+use specs::SystemData;
+use std::cell::RefCell;
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// The render event being drawn.
 #[derive(Default)]
 struct WindowEvent {
     event: Option<Event>,
 }
 
-#[derive(Component, Debug)]
-#[storage(VecStorage)]
-struct Velocity {
-    x: f32,
-    y: f32,
+/// The window, shared by the frontend and the render system.
+type Win = Rc<RefCell<PistonWindow>>;
+
+struct RenderSystem {
+    win: Win,
+    glyphs: Rc<RefCell<Glyphs>>,
+    /// The texture made of the atlas image, until the image changes.
+    texture: Option<(Arc<ImageData>, G2dTexture)>,
 }
 
-struct MouseTrackSystem {}
-impl<'a> System<'a> for MouseTrackSystem {
-    type SystemData = (
-        WriteStorage<'a, Position>,
-        ReadStorage<'a, MouseTracker>,
-        Read<'a, GameState>,
-    );
-    fn run(&mut self, (mut pos_store, _track, gs): Self::SystemData) {
-        for pos in (&mut pos_store).join() {
-            (*pos).x = gs.mouse_position.x;
-            (*pos).y = gs.mouse_position.y;
+/// Takes the window events into the input state, an update or a render
+/// ending the phase.
+struct WindowFrontend {
+    win: Win,
+    /// Update events seen, the game steps on one in `ups / sim::UPS`.
+    updates: u64,
+}
+
+impl WindowFrontend {
+    fn new(win: &Win) -> WindowFrontend {
+        WindowFrontend {
+            win: win.clone(),
+            updates: 0,
         }
     }
 }
 
-struct RenderSystem {
-    win: Arc<Mutex<PistonWindow>>,
+/// How much the play field is scaled and where it starts, fitting it in
+/// the middle of the window.
+fn view(window: Size, field: &PlayField) -> (f64, [f64; 2]) {
+    let (w, h) = (field.w as f64, field.h as f64);
+    let scale = (window.width / w).min(window.height / h);
+    let x = (window.width - w * scale) / 2.;
+    let y = (window.height - h * scale) / 2.;
+    (scale, [x, y])
 }
 
-struct InputSystem {
-    win: Arc<Mutex<PistonWindow>>,
+fn handle_mouse_cursor(position: [f64; 2], view: (f64, [f64; 2]), gs: &mut GameState) {
+    let (scale, [x, y]) = view;
+    gs.mouse_position = Position {
+        x: ((position[0] - x) / scale) as f32,
+        y: ((position[1] - y) / scale) as f32,
+    }
 }
 
-fn handle_mouse_cursor(position: [f64; 2], gs: &mut GameState) {
-    gs.mouse_position = Position {
-        x: position[0] as f32,
-        y: position[1] as f32,
+/// Keys go to the inspector while it is shown, and clicks pick entities.
+fn handle_button(args: ButtonArgs, world: &World) {
+    let keys = &world.fetch::<Settings>().keys;
+    let mut input = world.write_resource::<InputState>();
+    let mut inspector = world.write_resource::<Inspector>();
+    let pressed = args.state == ButtonState::Press;
+    match args.button {
+        Button::Keyboard(key) if pressed && keys.debug.contains(&key) => {
+            let mut overlay = world.write_resource::<DebugOverlay>();
+            overlay.shown = !overlay.shown;
+        }
+        Button::Keyboard(key) if pressed && keys.inspect.contains(&key) => {
+            inspector.shown = !inspector.shown;
+            // the game lets go of the keys held
+            *input = InputState::default();
+        }
+        Button::Keyboard(key) if pressed && inspector.shown => inspector.key(key),
+        Button::Keyboard(_) if inspector.shown => {}
+        Button::Keyboard(key) => keys.press(key, pressed, &mut input),
+        Button::Mouse(MouseButton::Left) if pressed && inspector.shown => {
+            inspector.click(world.fetch::<GameState>().mouse_position.clone())
+        }
+        _ => {}
     }
 }
 
-impl<'a> System<'a> for InputSystem {
-    type SystemData = (Write<'a, GameState>, Write<'a, WindowEvent>);
-    fn run(&mut self, (mut gs, mut we): Self::SystemData) {
-        let mut win = self.win.lock().unwrap();
-        match win.next() {
-            Some(event) => {
-                match &event {
-                    Event::Loop(Loop::Update(args)) => gs.delta = args.dt,
-                    Event::Input(_input, _opts) => match event.mouse_cursor_args() {
-                        Some(cursor) => handle_mouse_cursor(cursor, &mut gs),
-                        None => (),
-                    },
-                    _discard => {}
-                }
-                we.event = Some(event)
+impl Frontend for WindowFrontend {
+    fn next_phase(&mut self, world: &mut World) -> Option<Phase> {
+        let mut win = self.win.borrow_mut();
+        let view = view(win.size(), &world.fetch::<PlayField>());
+        for event in win.by_ref() {
+            if let Some(cursor) = event.mouse_cursor_args() {
+                handle_mouse_cursor(cursor, view, &mut world.write_resource::<GameState>())
+            }
+            if let Some(button) = event.button_args() {
+                handle_button(button, world)
+            }
+            if let Some(text) = event.text_args() {
+                world.write_resource::<Inspector>().text(&text)
+            }
+            if event.update_args().is_some() {
+                // always a whole step, whatever the event loop measured
+                self.updates += 1;
+                let every = (world.fetch::<Settings>().video.ups / sim::UPS).max(1);
+                let step = if self.updates % every == 0 {
+                    sim::STEP
+                } else {
+                    0.
+                };
+                return Some(Phase::Update(step));
+            }
+            if let Some(args) = event.render_args() {
+                // the updates since the last step and the time since the
+                // last update, as a share of a step
+                let ups = world.fetch::<Settings>().video.ups;
+                let every = (ups / sim::UPS).max(1);
+                let since = (self.updates % every) as f64 / ups as f64 + args.ext_dt;
+                world.write_resource::<Interpolation>().alpha = (since / sim::STEP) as f32;
+                world.write_resource::<WindowEvent>().event = Some(event);
+                return Some(Phase::Render);
             }
-            None => gs.exit = true,
         }
+        None
     }
 }
 
 impl<'a> System<'a> for RenderSystem {
     type SystemData = (
+        Entities<'a>,
+        Read<'a, Interpolation>,
         ReadStorage<'a, Position>,
         ReadStorage<'a, Sprite>,
+        ReadStorage<'a, Player>,
+        ReadStorage<'a, Boss>,
+        ReadStorage<'a, Enemy>,
+        Read<'a, InputState>,
+        Read<'a, GrazeConfig>,
+        Read<'a, BombConfig>,
+        Read<'a, PlayField>,
+        Read<'a, SceneControl>,
+        Read<'a, Menu>,
+        Read<'a, ReplayControl>,
         Read<'a, WindowEvent>,
+        Read<'a, ReloadErrors>,
+        DebugData<'a>,
+        Read<'a, Inspector>,
+        Read<'a, SpriteAtlas>,
+        Read<'a, Settings>,
     );
 
-    fn run(&mut self, (positions, sprites, we): Self::SystemData) {
+    fn run(
+        &mut self,
+        (
+            entities,
+            interpolation,
+            positions,
+            sprites,
+            players,
+            bosses,
+            enemies,
+            input,
+            graze,
+            bomb,
+            field,
+            scene,
+            menu,
+            replay,
+            we,
+            errors,
+            debug,
+            inspector,
+            atlas,
+            settings,
+        ): Self::SystemData,
+    ) {
         match &we.event {
             None => {}
             Some(event) => {
-                let mut win = self.win.lock().unwrap();
+                let mut win = self.win.borrow_mut();
+                let mut glyphs = self.glyphs.borrow_mut();
+                let (scale, [x, y]) = view(win.size(), &field);
+                let current = match (&self.texture, &atlas.image) {
+                    (Some((made_of, _)), Some(image)) => Arc::ptr_eq(made_of, image),
+                    (None, None) => true,
+                    _ => false,
+                };
+                if !current {
+                    let mut context = win.create_texture_context();
+                    self.texture = atlas.image.as_ref().and_then(|image| {
+                        Texture::from_image(&mut context, &image.image, &TextureSettings::new())
+                            .map(|texture| (image.clone(), texture))
+                            .ok()
+                    });
+                }
+                let texture = self.texture.as_ref().map(|(_, texture)| texture);
 
-                win.draw_2d(event, |context, graphics, _device| {
-                    clear([1.; 4], graphics);
-                    for (pos, sprite) in (&positions, &sprites).join() {
+                win.draw_2d(event, |context, graphics, device| {
+                    clear([0., 0., 0., 1.], graphics);
+                    let context = context.trans(x, y).scale(scale, scale);
+                    let screen = [0., 0., field.w as f64, field.h as f64];
+                    rectangle([1.; 4], screen, context.transform, graphics);
+                    if !scene.current.shows_world() {
+                        draw_menu(&menu, &field, &mut glyphs, context, graphics);
+                        draw_errors(&errors.lines, &field, &mut glyphs, context, graphics);
+                        draw_inspector(&inspector, &field, &mut glyphs, context, graphics);
+                        rectangle(
+                            [0., 0., 0., scene.fade],
+                            screen,
+                            context.transform,
+                            graphics,
+                        );
+                        glyphs.factory.encoder.flush(device);
+                        return;
+                    }
+                    for (entity, pos, sprite) in (&entities, &positions, &sprites).join() {
+                        let (x, y) = interpolation.position(entity, pos);
                         let (w, h) = (sprite.size.w, sprite.size.h);
+                        let rect = [
+                            (x - sprite.pivot.x) as f64,
+                            (y - sprite.pivot.y) as f64,
+                            w as f64,
+                            h as f64,
+                        ];
+                        let region = sprite.region.as_ref().and_then(|name| atlas.region(name));
+                        match (region, texture) {
+                            (Some(region), Some(texture)) => {
+                                Image::new_color(sprite.color.to_array())
+                                    .rect(rect)
+                                    .src_rect([
+                                        region.x as f64,
+                                        region.y as f64,
+                                        region.w as f64,
+                                        region.h as f64,
+                                    ])
+                                    .draw(texture, &context.draw_state, context.transform, graphics)
+                            }
+                            _ => rectangle(
+                                sprite.color.to_array(),
+                                rect,
+                                context.transform,
+                                graphics,
+                            ),
+                        }
+                    }
+                    if input.focus && settings.show_hitbox {
+                        let r = graze.hit_radius as f64;
+                        for (entity, pos, _) in (&entities, &positions, &players).join() {
+                            let (x, y) = interpolation.position(entity, pos);
+                            ellipse(
+                                [1., 0., 0., 1.],
+                                [x as f64 - r, y as f64 - r, r * 2., r * 2.],
+                                context.transform,
+                                graphics,
+                            );
+                        }
+                    }
+                    for (boss, enemy) in (&bosses, &enemies).join() {
+                        let phase = boss.current();
+                        let width = field.w as f64 - 20.;
+                        let health = (enemy.health / phase.health).max(0.) as f64;
+                        let time = (boss.timer / phase.time_limit).max(0.);
+                        rectangle(
+                            [0.8, 0., 0., 1.],
+                            [10., 8., width * health, 6.],
+                            context.transform,
+                            graphics,
+                        );
+                        rectangle(
+                            [0.3, 0.3, 0.3, 1.],
+                            [10., 16., width * time, 3.],
+                            context.transform,
+                            graphics,
+                        );
+                        for i in 0..boss.phases_left() {
+                            rectangle(
+                                [0.8, 0., 0., 1.],
+                                [10. + i as f64 * 8., 22., 6., 6.],
+                                context.transform,
+                                graphics,
+                            );
+                        }
+                    }
+                    let flash = players
+                        .join()
+                        .map(|p| p.bombing / bomb.duration)
+                        .fold(0., f64::max);
+                    if flash > 0. {
                         rectangle(
-                            sprite.color.to_array(),
-                            [
-                                (pos.x - sprite.pivot.x) as f64,
-                                (pos.y - sprite.pivot.y) as f64,
-                                w as f64,
-                                h as f64,
-                            ],
+                            [1., 1., 1., (flash * 0.6) as f32],
+                            screen,
                             context.transform,
                             graphics,
                         );
                     }
+                    debug.draw(&mut glyphs, context, graphics);
+                    if scene.current == Scene::Replay {
+                        let clock = |tick: u32| {
+                            let secs = tick as u64 / sim::UPS;
+                            format!("{:02}:{:02}", secs / 60, secs % 60)
+                        };
+                        let status = format!(
+                            "Replay x{}  {} / {}{}",
+                            replay.speed,
+                            clock(replay.tick),
+                            clock(replay.ticks),
+                            if replay.paused { "  paused" } else { "" }
+                        );
+                        let _ = text::Text::new_color([0., 0., 0., 1.], 14).draw(
+                            &status,
+                            &mut *glyphs,
+                            &context.draw_state,
+                            context.transform.trans(10., field.h as f64 - 10.),
+                            graphics,
+                        );
+                    }
+                    if !menu.items.is_empty() || !menu.lines.is_empty() {
+                        rectangle([1., 1., 1., 0.7], screen, context.transform, graphics);
+                        draw_menu(&menu, &field, &mut glyphs, context, graphics);
+                    }
+                    draw_errors(&errors.lines, &field, &mut glyphs, context, graphics);
+                    draw_inspector(&inspector, &field, &mut glyphs, context, graphics);
+                    rectangle(
+                        [0., 0., 0., scene.fade],
+                        screen,
+                        context.transform,
+                        graphics,
+                    );
+                    glyphs.factory.encoder.flush(device);
                 });
             }
         }
     }
 }
 
-fn create_world() -> World {
-    let mut world = World::new();
-    world.register::<Position>();
-    world.register::<Velocity>();
-    world.register::<MouseTracker>();
-    world.register::<Sprite>();
-    world
+/// What the debug overlay draws.
+#[derive(SystemData)]
+struct DebugData<'a> {
+    overlay: Read<'a, DebugOverlay>,
+    physics: Read<'a, PhysicsDebug>,
+    entities: Entities<'a>,
+    interpolation: Read<'a, Interpolation>,
+    positions: ReadStorage<'a, Position>,
+    velocities: ReadStorage<'a, Velocity>,
+    sprites: ReadStorage<'a, Sprite>,
 }
 
-fn main() {
-    use render::{Color, Pivot, Size};
-    let mut world = create_world();
-    world
-        .create_entity()
-        .with(Position { x: 0.0, y: 0.0 })
-        .with(MouseTracker {})
-        .with(Sprite {
-            color: Color {
-                r: 1.,
-                g: 1.,
-                ..Default::default()
-            },
-            size: Size { w: 50., h: 50. },
-            pivot: Pivot { x: 25., y: 25. },
+impl<'a> DebugData<'a> {
+    /// Seconds of motion the velocity lines stand for.
+    const VELOCITY_TIME: f64 = 0.25;
+
+    /// Colliders and their bounding boxes, contacts and their normals,
+    /// velocities, sprite pivots and entity ids.
+    fn draw(&self, glyphs: &mut Glyphs, context: Context, g: &mut G2d) {
+        if !self.overlay.shown {
+            return;
+        }
+        let transform = context.transform;
+        for collider in self.physics.colliders.iter() {
+            let color = match collider.sensor.kind {
+                Sensor::Hitbox => [1., 0., 0., 1.],
+                Sensor::Grazebox => [0.9, 0.6, 0., 1.],
+                Sensor::Bullet => [0.8, 0., 0.8, 1.],
+                Sensor::Shot => [0., 0.3, 1., 1.],
+                Sensor::Enemy => [0., 0.6, 0., 1.],
+            };
+            let ((x, y), r) = (collider.center, collider.radius);
+            Ellipse::new_border(color, 0.5).draw(
+                [
+                    (x - r) as f64,
+                    (y - r) as f64,
+                    (r * 2.) as f64,
+                    (r * 2.) as f64,
+                ],
+                &context.draw_state,
+                transform,
+                g,
+            );
+            let ((x0, y0), (x1, y1)) = collider.aabb;
+            Rectangle::new_border([0.5, 0.5, 0.5, 0.6], 0.25).draw(
+                [x0 as f64, y0 as f64, (x1 - x0) as f64, (y1 - y0) as f64],
+                &context.draw_state,
+                transform,
+                g,
+            );
+        }
+        for contact in self.physics.contacts.iter() {
+            let ((x1, y1), (x2, y2)) = contact.points;
+            for (x, y) in [(x1, y1), (x2, y2)] {
+                rectangle(
+                    [1., 0., 0., 1.],
+                    [x as f64 - 1., y as f64 - 1., 2., 2.],
+                    transform,
+                    g,
+                );
+            }
+            let (nx, ny) = contact.normal;
+            line_from_to(
+                [1., 0., 0., 1.],
+                0.3,
+                [x1 as f64, y1 as f64],
+                [(x1 + nx * 8.) as f64, (y1 + ny * 8.) as f64],
+                transform,
+                g,
+            );
+        }
+        for (entity, pos) in (&self.entities, &self.positions).join() {
+            let (x, y) = self.interpolation.position(entity, pos);
+            let (x, y) = (x as f64, y as f64);
+            if let Some(velocity) = self.velocities.get(entity) {
+                line_from_to(
+                    [0., 0.5, 1., 1.],
+                    0.3,
+                    [x, y],
+                    [
+                        x + velocity.x as f64 * Self::VELOCITY_TIME,
+                        y + velocity.y as f64 * Self::VELOCITY_TIME,
+                    ],
+                    transform,
+                    g,
+                );
+            }
+            if self.sprites.contains(entity) {
+                let cross = [0., 0., 0., 1.];
+                line_from_to(cross, 0.25, [x - 2., y], [x + 2., y], transform, g);
+                line_from_to(cross, 0.25, [x, y - 2.], [x, y + 2.], transform, g);
+            }
+            let _ = text::Text::new_color([0., 0., 0., 1.], 8).draw(
+                &entity.id().to_string(),
+                glyphs,
+                &context.draw_state,
+                transform.trans(x + 3., y - 3.),
+                g,
+            );
+        }
+    }
+}
+
+/// Shows the rows of the inspector down the right of the screen, and marks
+/// the selected entity.
+fn draw_inspector(
+    inspector: &Inspector,
+    field: &PlayField,
+    glyphs: &mut Glyphs,
+    context: Context,
+    g: &mut G2d,
+) {
+    if !inspector.shown {
+        return;
+    }
+    if let (View::Entities, Some(at)) = (inspector.view, &inspector.at) {
+        Ellipse::new_border([1., 0.8, 0., 1.], 1.).draw(
+            [at.x as f64 - 12., at.y as f64 - 12., 24., 24.],
+            &context.draw_state,
+            context.transform,
+            g,
+        );
+    }
+    const WIDTH: f64 = 300.;
+    const LINE: f64 = 13.;
+    let x = field.w as f64 - WIDTH;
+    rectangle(
+        [0., 0., 0., 0.8],
+        [x, 0., WIDTH, field.h as f64],
+        context.transform,
+        g,
+    );
+    let mut line = |text: &str, color: [f32; 4], column: f64, y: f64| {
+        let _ = text::Text::new_color(color, 10).draw(
+            text,
+            glyphs,
+            &context.draw_state,
+            context.transform.trans(x + 6. + column, y),
+            g,
+        );
+    };
+    let grey = [0.6, 0.6, 0.6, 1.];
+    line(
+        "Tab: view  Left/Right: next  Up/Down: row  Enter: edit",
+        grey,
+        0.,
+        14.,
+    );
+    let visible = ((field.h as f64 - 60.) / LINE).max(1.) as usize;
+    let first = (inspector.row + 1).saturating_sub(visible);
+    let clip = |text: &str, chars: usize| match text.char_indices().nth(chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    };
+    for (i, row) in inspector.rows.iter().enumerate().skip(first).take(visible) {
+        let y = 34. + (i - first) as f64 * LINE;
+        let selected = i == inspector.row;
+        let color = match (selected, row.editable()) {
+            (true, _) => [1., 0.8, 0., 1.],
+            (false, true) => [1., 1., 1., 1.],
+            (false, false) => grey,
+        };
+        line(&clip(&row.label, 20), color, 0., y);
+        let value = match (&inspector.editing, selected) {
+            // the end of the text, where it is typed
+            (Some(text), true) => {
+                let shown: String = text.chars().rev().take(28).collect();
+                format!("{}_", shown.chars().rev().collect::<String>())
+            }
+            _ => clip(&row.value, 28),
+        };
+        line(&value, color, 120., y);
+    }
+    if let Some(message) = &inspector.message {
+        line(
+            &clip(message, 48),
+            [1., 0.3, 0.3, 1.],
+            0.,
+            field.h as f64 - 10.,
+        );
+    }
+}
+
+/// Lists the assets that failed to load across the top of the screen.
+fn draw_errors(
+    lines: &[String],
+    field: &PlayField,
+    glyphs: &mut Glyphs,
+    context: Context,
+    g: &mut G2d,
+) {
+    if lines.is_empty() {
+        return;
+    }
+    let height = 8. + 16. * lines.len() as f64;
+    rectangle(
+        [0.3, 0., 0., 0.85],
+        [0., 0., field.w as f64, height],
+        context.transform,
+        g,
+    );
+    for (i, line) in lines.iter().enumerate() {
+        let _ = text::Text::new_color([1., 1., 1., 1.], 12).draw(
+            line,
+            glyphs,
+            &context.draw_state,
+            context.transform.trans(6., 18. + 16. * i as f64),
+            g,
+        );
+    }
+}
+
+fn draw_menu(menu: &Menu, field: &PlayField, glyphs: &mut Glyphs, context: Context, g: &mut G2d) {
+    let x = field.w as f64 / 2. - 120.;
+    let mut y = field.h as f64 / 3.;
+    let mut line = |text: &str, size: u32, color: [f32; 4], y: f64| {
+        let _ = text::Text::new_color(color, size).draw(
+            text,
+            glyphs,
+            &context.draw_state,
+            context.transform.trans(x, y),
+            g,
+        );
+    };
+    line(&menu.title, 32, [0., 0., 0., 1.], y);
+    y += 40.;
+    for text in menu.lines.iter() {
+        line(text, 16, [0.2, 0.2, 0.2, 1.], y);
+        y += 24.;
+    }
+    y += 16.;
+    for (i, item) in menu.items.iter().enumerate() {
+        let (label, color) = if i == menu.selected {
+            (format!("> {}", item.label), [0.8, 0., 0., 1.])
+        } else {
+            (format!("  {}", item.label), [0., 0., 0., 1.])
+        };
+        line(&label, 20, color, y);
+        y += 28.;
+    }
+}
+
+/// Controllers, shared by the dispatchers of every scene.
+type Pads = Rc<RefCell<Box<dyn GamepadBackend>>>;
+/// Sound output, shared the same way.
+type Sound = Rc<RefCell<Audio>>;
+
+fn menu_dispatcher(pads: &Pads, sound: &Sound) -> Dispatcher<'static, 'static> {
+    DispatcherBuilder::new()
+        .with_thread_local(GamepadSystem {
+            backend: pads.clone(),
+        })
+        .with_thread_local(MenuSystem::default())
+        .with_thread_local(AudioSystem {
+            audio: sound.clone(),
         })
-        .build();
+        .build()
+}
 
-    world.insert(GameState {
-        delta: 0.,
-        ..Default::default()
-    });
+fn play_dispatcher(pads: &Pads, sound: &Sound) -> Dispatcher<'static, 'static> {
+    DispatcherBuilder::new()
+        .with_thread_local(GamepadSystem {
+            backend: pads.clone(),
+        })
+        .with_thread_local(QuickSaveSystem::default())
+        .with_thread_local(AudioSystem {
+            audio: sound.clone(),
+        })
+        .build()
+}
+
+fn loading_dispatcher(pads: &Pads, sound: &Sound) -> Dispatcher<'static, 'static> {
+    DispatcherBuilder::new()
+        .with_thread_local(GamepadSystem {
+            backend: pads.clone(),
+        })
+        .with_thread_local(LoadingSystem {})
+        .with_thread_local(AudioSystem {
+            audio: sound.clone(),
+        })
+        .build()
+}
+
+/// Draws the frame, on render phases.
+fn render_dispatcher(win: &Win, glyphs: &Rc<RefCell<Glyphs>>) -> Dispatcher<'static, 'static> {
+    DispatcherBuilder::new()
+        .with_thread_local(InterpolationSystem {})
+        .with_thread_local(PhysicsDebugSystem {})
+        .with_thread_local(RenderSystem {
+            win: win.clone(),
+            glyphs: glyphs.clone(),
+            texture: None,
+        })
+        .build()
+}
+
+/// Runs on the update ticks of the playing scene.
+fn play_tick_dispatcher() -> Dispatcher<'static, 'static> {
+    game::gameplay(DispatcherBuilder::new())
+        .with(PlayFlowSystem::default(), "play_flow", &["score"])
+        .build()
+}
+
+/// The playback itself is driven by `Playback`, outside of the dispatcher.
+fn replay_dispatcher(pads: &Pads, sound: &Sound) -> Dispatcher<'static, 'static> {
+    DispatcherBuilder::new()
+        .with_thread_local(GamepadSystem {
+            backend: pads.clone(),
+        })
+        .with_thread_local(ReplayControlSystem::default())
+        .with_thread_local(AudioSystem {
+            audio: sound.clone(),
+        })
+        .build()
+}
+
+/// The netplay session runs the ticks itself, outside of the dispatcher.
+fn netplay_dispatcher(pads: &Pads, sound: &Sound) -> Dispatcher<'static, 'static> {
+    DispatcherBuilder::new()
+        .with_thread_local(GamepadSystem {
+            backend: pads.clone(),
+        })
+        .with_thread_local(PlayFlowSystem::default())
+        .with_thread_local(AudioSystem {
+            audio: sound.clone(),
+        })
+        .build()
+}
+
+/// Shows what the server sends, nothing is simulated here.
+fn online_dispatcher(pads: &Pads, sound: &Sound) -> Dispatcher<'static, 'static> {
+    DispatcherBuilder::new()
+        .with_thread_local(GamepadSystem {
+            backend: pads.clone(),
+        })
+        .with_thread_local(AudioSystem {
+            audio: sound.clone(),
+        })
+        .build()
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut assets = Assets::background();
+    let guardian_def = assets.load::<BossDef>("assets/bosses/guardian.ron");
+    let mut guardian = required(guardian_def.clone());
+    if args.iter().any(|arg| arg == "--headless") {
+        run_headless(&args, &guardian);
+        return;
+    }
+
+    let mut world = game::create_world();
+    if game::insert_resources(&mut world, &mut assets).is_err() {
+        process::exit(1);
+    }
     world.insert(WindowEvent::default());
+    world.insert(DebugOverlay {
+        shown: args.iter().any(|arg| arg == "--debug"),
+    });
+    world.insert(Inspector::default());
+    // the game plays silent without these, the loader tells why
+    let audio_cfg = assets.load::<AudioConfig>("assets/audio.ron").wait();
+    let audio_cfg = audio_cfg.map(|cfg| (*cfg).clone()).unwrap_or_default();
+    let sfx = assets.load::<SfxTable>("assets/sfx.ron").wait();
+    let sfx = sfx.map(|sfx| (*sfx).clone()).unwrap_or_default();
+    let sounds = sfx.sounds.values().map(|sound| &sound.file);
+    for path in audio_cfg.music.values().chain(sounds) {
+        assets.load::<SoundData>(path);
+    }
+    world.insert(audio_cfg);
+    world.insert(sfx);
+    // sprites are drawn as rectangles without it
+    world.insert(SpriteAtlas::load(&mut assets, "assets/sprites.ron"));
+    let mut reload = args.iter().any(|arg| arg == "--hot-reload").then(|| {
+        let mut reload = PollingReload::default();
+        reload.resource::<ItemConfig>(&mut assets, "assets/items.ron");
+        reload.resource::<ScoreConfig>(&mut assets, "assets/score.ron");
+        reload.resource::<Prefabs>(&mut assets, "assets/prefabs.ron");
+        reload.resource::<AudioConfig>(&mut assets, "assets/audio.ron");
+        reload.resource::<SfxTable>(&mut assets, "assets/sfx.ron");
+        reload.boss(guardian_def.clone());
+        reload.atlas(&mut assets, "assets/sprites.ron");
+        reload
+    });
+    let mut settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("could not load the settings: {}", err);
+        Settings::default()
+    });
+    if let Err(err) = settings.apply_args(&args) {
+        eprintln!("{}", err);
+        process::exit(2);
+    }
+    world.write_resource::<GameState>().difficulty = settings.difficulty;
 
-    let mut window: PistonWindow = WindowSettings::new("Hello Piston!", [640, 480])
-        .exit_on_esc(true)
+    let mut session = net_config_or_exit(&args).map(|config| {
+        println!("waiting for {}", config.peer);
+        NetSession::connect(&mut world, config, &guardian, clock_seed()).unwrap_or_else(|err| {
+            eprintln!("could not start the netplay session: {}", err);
+            process::exit(1)
+        })
+    });
+    let mut online = flag(&args, "--connect", "an address like 127.0.0.1:7100").map(|server| {
+        let local = flag(&args, "--local", "an address like 0.0.0.0:7101")
+            .unwrap_or_else(|| "0.0.0.0:0".parse().unwrap());
+        println!("joining {}", server);
+        OnlineClient::connect(local, server).unwrap_or_else(|err| {
+            eprintln!("could not join {}: {}", server, err);
+            process::exit(1)
+        })
+    });
+
+    let video = &settings.video;
+    let mut window: PistonWindow = WindowSettings::new("smup", [video.width, video.height])
+        .fullscreen(video.fullscreen)
+        .vsync(video.vsync)
+        .exit_on_esc(false)
         .build()
-        .unwrap();
-    window.set_event_settings(EventSettings{
-        ups: 30,
-        lazy: true,
+        .unwrap_or_else(|err| {
+            eprintln!("could not open the window: {}", err);
+            process::exit(1)
+        });
+    window.set_event_settings(EventSettings {
+        ups: video.ups,
+        max_fps: video.max_fps,
         ..Default::default()
     });
-    let window = window;
-
-    let mut physics = PhysicsSystem::new();
-    physics.add_box(phy::Vec2::new(10., 10.));
-
-    let win = Arc::new(Mutex::new(window));
-    let mut dispatcher = DispatcherBuilder::new()
-        .with(MouseTrackSystem {}, "mouse_tracker", &[])
-        //.with(physics, "physics", &[])
-        .with_thread_local(InputSystem { win: win.clone() })
-        .with_thread_local(RenderSystem { win: win.clone() })
-        .build();
-
-    loop {
-        dispatcher.dispatch(&mut world);
-        dispatcher.setup(&mut world);
-        let gs = world.fetch::<GameState>();
-        if gs.exit {
-            println!("exit");
-            break;
+    world.insert(settings);
+    let font = required(assets.load::<FontData>("assets/fonts/DejaVuSans.ttf"));
+    // the glyphs keep the font until the game ends
+    let font: &'static [u8] = Box::leak(font.bytes.clone().into_boxed_slice());
+    let texture_context = window.create_texture_context();
+    let glyphs =
+        Glyphs::from_bytes(font, texture_context, TextureSettings::new()).unwrap_or_else(|_| {
+            eprintln!("assets/fonts/DejaVuSans.ttf: not a font glyphs can be made of");
+            process::exit(1)
+        });
+    world.insert(assets);
+
+    let win: Win = Rc::new(RefCell::new(window));
+    let glyphs = Rc::new(RefCell::new(glyphs));
+    let first = if session.is_some() {
+        Scene::Netplay
+    } else if online.is_some() {
+        Scene::Online
+    } else {
+        Scene::Loading
+    };
+    let pads: Pads = Rc::new(RefCell::new(default_backend()));
+    let sound: Sound = Rc::new(RefCell::new(Audio::new(audio::default_backend())));
+    let mut scenes = SceneStack::new(first, 0.5);
+    let mut game_loop = GameLoop::new(
+        &mut world,
+        WindowFrontend::new(&win),
+        render_dispatcher(&win, &glyphs),
+    );
+    scenes.add(
+        &mut world,
+        Scene::Loading,
+        loading_dispatcher(&pads, &sound),
+    );
+    for &scene in [
+        Scene::Title,
+        Scene::Options,
+        Scene::StageSelect,
+        Scene::Paused,
+        Scene::GameOver,
+        Scene::Credits,
+        Scene::Replays,
+    ]
+    .iter()
+    {
+        scenes.add(&mut world, scene, menu_dispatcher(&pads, &sound));
+    }
+    scenes.add(&mut world, Scene::Playing, play_dispatcher(&pads, &sound));
+    scenes.add_tick(&mut world, Scene::Playing, play_tick_dispatcher());
+    scenes.add(&mut world, Scene::Replay, replay_dispatcher(&pads, &sound));
+    scenes.add(
+        &mut world,
+        Scene::Netplay,
+        netplay_dispatcher(&pads, &sound),
+    );
+    scenes.add(&mut world, Scene::Online, online_dispatcher(&pads, &sound));
+
+    let mut shown = None;
+    let mut in_run = false;
+    let mut playback: Option<Playback> = None;
+    game_loop.run(&mut world, |world| {
+        let current = match scenes.current() {
+            Some(current) => current,
+            None => return false,
+        };
+        if shown != Some(current) {
+            shown = Some(current);
+            let menu = Menu::for_scene(
+                current,
+                &world.fetch::<GameState>(),
+                &world.fetch::<Score>(),
+                &world.fetch::<Settings>(),
+            );
+            world.insert(menu);
+        }
+        if let Some(entered) = scenes.run(world) {
+            if let Some(playback) = playback.take() {
+                playback.close(world);
+            }
+            if entered != Scene::Paused {
+                if let Some(mut session) = session.take() {
+                    session.linger(world);
+                    save_hiscore(world);
+                }
+                if let Some(client) = online.take() {
+                    client.leave(world);
+                }
+            }
+            match entered {
+                Scene::Playing => {
+                    let players = world.fetch::<Gamepads>().players();
+                    game::start_run(world, &guardian, clock_seed(), players);
+                    in_run = true;
+                }
+                Scene::Paused => {}
+                Scene::Replay => playback = start_playback(world, &guardian),
+                _ if in_run => {
+                    end_run(world);
+                    in_run = false;
+                }
+                _ => {}
+            }
+            // a scene entered again starts from a fresh menu
+            shown = None;
+        }
+        let request = world.write_resource::<SaveStateControl>().pending.take();
+        if let Some(request) = request {
+            quick_state(world, request);
+        }
+        if scenes.current() == Some(Scene::Replay) {
+            if let Some(playback) = playback.as_mut() {
+                playback.update(world);
+            }
+        }
+        if let Some(net) = session.as_mut() {
+            // the peer keeps hearing from us while paused
+            let local = if scenes.current() == Some(Scene::Netplay)
+                && world.fetch::<GameState>().delta > 0.
+            {
+                let input = world.fetch::<InputState>();
+                let gs = world.fetch::<GameState>();
+                Some(InputFrame::capture(&input, &gs.mouse_position))
+            } else {
+                None
+            };
+            if let Err(err) = net.update(world, local) {
+                eprintln!("{}", err);
+                session = None;
+                world
+                    .write_resource::<SceneControl>()
+                    .go(Transition::Reset(Scene::Title));
+            }
+        }
+        if let Some(client) = online.as_mut() {
+            let (local, frame) = {
+                let gs = world.fetch::<GameState>();
+                let local = if scenes.current() == Some(Scene::Online) && gs.delta > 0. {
+                    let input = world.fetch::<InputState>();
+                    Some(InputFrame::capture(&input, &gs.mouse_position))
+                } else {
+                    None
+                };
+                // every update event, not only those the game steps on,
+                // moves the shown state along
+                let ups = world.fetch::<Settings>().video.ups.max(1);
+                (local, 1. / ups as f64)
+            };
+            let result = client.update(world, local, frame);
+            if let Err(err) = result {
+                eprintln!("{}", err);
+                world
+                    .write_resource::<SceneControl>()
+                    .go(Transition::Reset(Scene::Title));
+            } else if client.is_over() && scenes.current() == Some(Scene::Online) {
+                world
+                    .write_resource::<SceneControl>()
+                    .fade_to(Transition::Replace(Scene::GameOver));
+            }
+        }
+        if let Some(reload) = reload.as_mut() {
+            reload.poll(world);
+            // runs started from now on use the new boss
+            if let Some(def) = guardian_def.get() {
+                guardian = def;
+            }
+        }
+        inspector::update(world);
+        true
+    });
+    println!("exit");
+    if in_run {
+        end_run(&world);
+    }
+    if session.is_some() {
+        save_hiscore(&world);
+    }
+}
+
+fn start_playback(world: &mut World, boss: &BossDef) -> Option<Playback> {
+    let path = world.write_resource::<ReplaySelection>().path.take()?;
+    match Replay::load(&path) {
+        Ok(replay) => {
+            if replay.version != env!("CARGO_PKG_VERSION") {
+                eprintln!(
+                    "{} was recorded by version {}, it may not play back the same",
+                    path.display(),
+                    replay.version
+                );
+            }
+            Some(Playback::new(world, replay, boss))
+        }
+        Err(err) => {
+            eprintln!("could not load {}: {}", path.display(), err);
+            world
+                .write_resource::<SceneControl>()
+                .go(Transition::Reset(Scene::Title));
+            None
+        }
+    }
+}
+
+/// Keeps what is worth keeping from the run that just ended.
+fn end_run(world: &World) {
+    save_hiscore(world);
+    if world.read_storage::<Player>().join().count() > 1 {
+        // replays only hold the input of the first player
+        println!("co-op runs are not recorded");
+        return;
+    }
+    let replay = world.fetch::<Recording>().finish(
+        world.fetch::<Rng>().seed,
+        world.fetch::<Stage>().number,
+        world.fetch::<GameState>().difficulty,
+    );
+    match replay.save() {
+        Ok(path) => println!("Replay saved to {}", path.display()),
+        Err(err) => eprintln!("could not save the replay: {}", err),
+    }
+}
+
+/// Saves the run being played to the quick save state, or goes back to it.
+fn quick_state(world: &mut World, request: StateRequest) {
+    match request {
+        StateRequest::Save => match Snapshot::take(world).quick_save() {
+            Ok(path) => println!("State saved to {}", path.display()),
+            Err(err) => eprintln!("could not save the state: {}", err),
+        },
+        StateRequest::Load => match Snapshot::quick_load() {
+            Ok(snapshot) => {
+                // the keys held right now win over the ones saved
+                let input = (*world.fetch::<InputState>()).clone();
+                let mouse = world.fetch::<GameState>().mouse_position.clone();
+                snapshot.restore(world);
+                world.insert(input);
+                world.write_resource::<GameState>().mouse_position = mouse;
+            }
+            Err(err) => eprintln!("could not load the state: {}", err),
+        },
+    }
+}
+
+/// The asset of `handle`, quitting when it could not be loaded. The loader
+/// already said why.
+fn required<T: Asset>(handle: Handle<T>) -> Arc<T> {
+    handle.wait().unwrap_or_else(|_| process::exit(1))
+}
+
+fn clock_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// `--headless [--ticks N] [--script PATH] [--seed N] [--verify]
+/// [--snapshot-at N] [--record PATH] [--replay PATH] [--save-state PATH]
+/// [--load-state PATH]` plays the first stage without a window, feeding the
+/// scripted input, and prints how the run went. With `--verify` the run is
+/// played twice and the world checksums compared after every tick, the
+/// second run going through a snapshot at `--snapshot-at`. `--record` saves
+/// the run as a replay, `--replay` plays one back instead of the script.
+/// `--save-state` saves where the run ended, `--load-state` carries on from
+/// a saved state. With the netplay flags of `net_config` the run is played
+/// against a peer instead, the script giving the input of this machine.
+/// `--connect ADDR [--local ADDR]` plays on a `smup-server` with the
+/// script, as a bot for load tests.
+fn run_headless(args: &[String], boss: &BossDef) {
+    let value = |flag: &str| flag_value(args, flag);
+    let ticks = flag(args, "--ticks", "a number of ticks").unwrap_or(sim::UPS * 60 * 5);
+    let seed = flag(args, "--seed", "a number").unwrap_or(0);
+    let script = match value("--script") {
+        Some(path) => InputScript::load(path).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            process::exit(1)
+        }),
+        None => InputScript::default(),
+    };
+    if let Some(server) = flag(args, "--connect", "an address like 127.0.0.1:7100") {
+        let local = flag(args, "--local", "an address like 0.0.0.0:7101")
+            .unwrap_or_else(|| "0.0.0.0:0".parse().unwrap());
+        let client = play_online(local, server, &script, ticks as u32).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+        if let Some(state) = client.latest() {
+            println!(
+                "tick {} score {} lives {} over {}",
+                state.tick, state.score, state.lives, state.over
+            );
+        }
+        let stats = &client.stats;
+        println!(
+            "states {} full {} unusable {} bytes {}",
+            stats.states, stats.full_states, stats.unusable, stats.bytes
+        );
+        return;
+    }
+    if let Some(config) = net_config_or_exit(args) {
+        let (outcome, session) = play_netplay(boss, config, &script, seed, ticks as u32)
+            .unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            });
+        println!("{}", outcome);
+        println!(
+            "rollbacks {} resimulated {} ticks",
+            session.rollbacks, session.resimulated
+        );
+        if session.desync.is_some() {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.iter().any(|arg| arg == "--verify") {
+        let snapshot_at = flag(args, "--snapshot-at", "a tick number");
+        match verify_determinism(boss, &script, seed, ticks, snapshot_at) {
+            Ok(ticks) => println!("deterministic over {} ticks", ticks),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(path) = value("--replay") {
+        let replay = Replay::load(Path::new(path)).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            process::exit(1)
+        });
+        // the loader already said what game data is missing
+        let outcome = play_replay(boss, replay).unwrap_or_else(|_| process::exit(1));
+        println!("{}", outcome);
+        return;
+    }
+    let mut headless = match value("--load-state") {
+        Some(path) => {
+            let snapshot = Snapshot::load(Path::new(path)).unwrap_or_else(|err| {
+                eprintln!("{}: {}", path, err);
+                process::exit(1)
+            });
+            Headless::from_snapshot(script, snapshot)
+        }
+        None => Headless::new(boss, script, seed),
+    }
+    .unwrap_or_else(|_| process::exit(1));
+    println!("{}", headless.run(ticks));
+    if let Some(path) = value("--record") {
+        if let Err(err) = headless.replay().write(Path::new(path)) {
+            eprintln!("could not write {}: {}", path, err);
+        }
+    }
+    if let Some(path) = value("--save-state") {
+        if let Err(err) = Snapshot::take(&headless.world).write(Path::new(path)) {
+            eprintln!("could not write {}: {}", path, err);
+        }
+    }
+}
+
+/// `--netplay --local ADDR --peer ADDR [--slot 0|1] [--delay N]
+/// [--latency MS] [--jitter MS] [--loss SHARE]` plays two player co-op
+/// against the peer. `--latency`, `--jitter` and `--loss` hold back and
+/// drop outgoing packets, to try bad connections out on localhost.
+fn net_config(args: &[String]) -> Result<Option<NetConfig>, String> {
+    if !args.iter().any(|arg| arg == "--netplay") {
+        return Ok(None);
+    }
+    let address = |flag: &str| {
+        parse_flag(args, flag, "an address like 127.0.0.1:7000")?
+            .ok_or_else(|| format!("--netplay needs {}", flag))
+    };
+    let number = |flag: &str| parse_flag::<u64>(args, flag, "a number");
+    Ok(Some(NetConfig {
+        local: address("--local")?,
+        peer: address("--peer")?,
+        slot: number("--slot")?.unwrap_or(0).min(1) as usize,
+        delay: number("--delay")?.unwrap_or(2) as u32,
+        shim: Shim {
+            latency: number("--latency")?.unwrap_or(0),
+            jitter: number("--jitter")?.unwrap_or(0),
+            loss: parse_flag(args, "--loss", "a share like 0.1")?.unwrap_or(0.),
+        },
+    }))
+}
+
+/// The netplay flags, stopping the game with what is wrong with them.
+fn net_config_or_exit(args: &[String]) -> Option<NetConfig> {
+    net_config(args).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(2)
+    })
+}
+
+/// What follows `flag` on the command line.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+}
+
+/// Value of `flag` on the command line, or what it should have been when
+/// it does not parse.
+fn parse_flag<T: FromStr>(
+    args: &[String],
+    flag: &str,
+    expected: &str,
+) -> Result<Option<T>, String> {
+    match flag_value(args, flag) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("{} takes {}, not {:?}", flag, expected, value)),
+        None => Ok(None),
+    }
+}
+
+/// Value of `flag` on the command line. A value that does not parse stops
+/// the game with what it should have been.
+fn flag<T: FromStr>(args: &[String], flag: &str, expected: &str) -> Option<T> {
+    parse_flag(args, flag, expected).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(2)
+    })
+}
+
+fn save_hiscore(world: &World) {
+    let score = world.fetch::<Score>();
+    let stage = world.fetch::<Stage>();
+    let cfg = world.fetch::<ScoreConfig>();
+    let mut table = match HiScores::load() {
+        Ok(table) => table,
+        Err(err) => {
+            eprintln!("could not load hi-scores: {}", err);
+            return;
+        }
+    };
+    let entry = HiScore::new(&world.fetch::<Settings>().name, score.total, stage.number);
+    if let Some(rank) = table.insert(entry, cfg.hiscore_entries) {
+        println!("New hi-score #{}: {}", rank + 1, score.total);
+        if let Err(err) = table.save() {
+            eprintln!("could not save hi-scores: {}", err);
         }
     }
 }
@@ -0,0 +1,300 @@
+extern crate specs;
+
+use crate::audio::Channel;
+use crate::replay::{Replay, ReplaySelection};
+use crate::scene::{Scene, SceneControl, Transition};
+use crate::score::{Score, StageBonus};
+use crate::settings::Settings;
+use crate::stage::Stage;
+use crate::types::{Difficulty, GameState, InputState};
+use specs::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MenuAction {
+    Go(Transition),
+    FadeTo(Transition),
+    /// Starts a run on the given stage.
+    Start(u32),
+    Difficulty,
+    ShowHitbox,
+    /// Plays the replay at this index of `Replay::list`.
+    Watch(usize),
+    /// Turns a volume up on confirm or right, down on left.
+    Volume(Channel),
+}
+
+#[derive(Clone, Debug)]
+pub struct MenuItem {
+    pub label: String,
+    pub action: MenuAction,
+}
+
+impl MenuItem {
+    fn new(label: &str, action: MenuAction) -> MenuItem {
+        MenuItem {
+            label: label.to_string(),
+            action,
+        }
+    }
+}
+
+/// The menu of the current scene.
+#[derive(Clone, Debug, Default)]
+pub struct Menu {
+    pub title: String,
+    /// Text shown between the title and the items.
+    pub lines: Vec<String>,
+    pub items: Vec<MenuItem>,
+    pub selected: usize,
+    /// What cancel does, if anything.
+    pub back: Option<MenuAction>,
+    /// Set until the menu has seen one frame of input, so a key still held
+    /// from the previous scene does not trigger it.
+    fresh: bool,
+}
+
+impl Menu {
+    fn new(title: &str) -> Menu {
+        Menu {
+            title: title.to_string(),
+            fresh: true,
+            ..Default::default()
+        }
+    }
+
+    fn item(mut self, label: &str, action: MenuAction) -> Menu {
+        self.items.push(MenuItem::new(label, action));
+        self
+    }
+
+    fn line(mut self, text: String) -> Menu {
+        self.lines.push(text);
+        self
+    }
+
+    fn back(mut self, action: MenuAction) -> Menu {
+        self.back = Some(action);
+        self
+    }
+
+    /// Shown over the play field while the stage bonus is counted.
+    pub fn stage_clear(bonus: &StageBonus) -> Menu {
+        let mut menu = Menu::new("Stage Clear");
+        for (name, points) in bonus.lines.iter() {
+            menu = menu.line(format!("{:<8}{:>12}", name, points));
+        }
+        menu = menu.line(format!("{:<8}{:>12}", "Total", bonus.total()));
+        for capture in bonus.captures.iter() {
+            menu = menu.line(format!("Captured {}", capture));
+        }
+        menu
+    }
+
+    pub fn for_scene(scene: Scene, gs: &GameState, score: &Score, settings: &Settings) -> Menu {
+        use MenuAction::*;
+        let volume = |name: &str, channel: Channel| {
+            let percent = (settings.volumes.get(channel) * 100.).round();
+            format!("{} volume: {}%", name, percent)
+        };
+        let to_title = FadeTo(Transition::Reset(Scene::Title));
+        match scene {
+            Scene::Loading => Menu::new("Loading"),
+            Scene::Title => Menu::new("SMUP")
+                .item("Start", Go(Transition::Push(Scene::StageSelect)))
+                .item("Options", Go(Transition::Push(Scene::Options)))
+                .item("Replays", Go(Transition::Push(Scene::Replays)))
+                .item("Credits", Go(Transition::Push(Scene::Credits)))
+                .item("Quit", Go(Transition::Quit)),
+            Scene::Options => Menu::new("Options")
+                .item(&format!("Difficulty: {:?}", gs.difficulty), Difficulty)
+                .item(
+                    &format!(
+                        "Show hitbox: {}",
+                        if settings.show_hitbox { "on" } else { "off" }
+                    ),
+                    ShowHitbox,
+                )
+                .item(&volume("Master", Channel::Master), Volume(Channel::Master))
+                .item(&volume("Music", Channel::Music), Volume(Channel::Music))
+                .item(&volume("Sound", Channel::Sfx), Volume(Channel::Sfx))
+                .item("Back", Go(Transition::Pop))
+                .back(Go(Transition::Pop)),
+            Scene::StageSelect => Menu::new("Select stage")
+                .item("Stage 1", Start(1))
+                .item("Back", Go(Transition::Pop))
+                .back(Go(Transition::Pop)),
+            Scene::Playing => Menu::default(),
+            Scene::Paused => Menu::new("Paused")
+                .item("Resume", Go(Transition::Pop))
+                .item("Retire", to_title)
+                .back(Go(Transition::Pop)),
+            Scene::GameOver => Menu::new("Game Over")
+                .line(format!("Score {}", score.total))
+                .item("Back to title", to_title)
+                .back(to_title),
+            Scene::Credits => Menu::new("Credits")
+                .line("smup-rust".to_string())
+                .line("Built with piston, specs and nphysics".to_string())
+                .line("DejaVu fonts by the DejaVu project".to_string())
+                .item("Back to title", to_title)
+                .back(to_title),
+            Scene::Replays => {
+                let mut menu = Menu::new("Replays");
+                let replays = Replay::list();
+                if replays.is_empty() {
+                    menu = menu.line("No replays yet".to_string());
+                }
+                for (i, path) in replays.iter().enumerate().take(MAX_REPLAYS) {
+                    let name = path.file_stem().map(|name| name.to_string_lossy());
+                    menu = menu.item(&name.unwrap_or_default(), Watch(i));
+                }
+                menu.item("Back", Go(Transition::Pop))
+                    .back(Go(Transition::Pop))
+            }
+            Scene::Replay => Menu::default(),
+            Scene::Netplay => Menu::default(),
+            Scene::Online => Menu::default(),
+        }
+    }
+}
+
+/// Replays listed in the browser, the most recent ones.
+const MAX_REPLAYS: usize = 8;
+
+fn next_difficulty(difficulty: Difficulty) -> Difficulty {
+    match difficulty {
+        Difficulty::Easy => Difficulty::Normal,
+        Difficulty::Normal => Difficulty::Hard,
+        Difficulty::Hard => Difficulty::Lunatic,
+        Difficulty::Lunatic => Difficulty::Easy,
+    }
+}
+
+/// How much one press changes a volume.
+const VOLUME_STEP: f32 = 0.1;
+
+/// Turns `channel` up by `steps`, wrapping from full to silent when turned
+/// up past full.
+fn change_volume(settings: &mut Settings, channel: Channel, steps: f32) {
+    let volume = settings.volumes.get(channel);
+    let changed = if steps > 0. && volume >= 1. {
+        0.
+    } else {
+        // rounded so the steps do not drift
+        ((volume + steps * VOLUME_STEP) / VOLUME_STEP).round() * VOLUME_STEP
+    };
+    if let Err(err) = settings.change(|settings| settings.volumes.set(channel, changed)) {
+        eprintln!("could not save the settings: {}", err);
+    }
+}
+
+/// Moves the menu cursor and runs the selected action.
+#[derive(Default)]
+pub struct MenuSystem {
+    last: InputState,
+}
+
+impl<'a> System<'a> for MenuSystem {
+    type SystemData = (
+        Write<'a, Menu>,
+        Write<'a, SceneControl>,
+        Write<'a, GameState>,
+        Write<'a, Stage>,
+        Write<'a, ReplaySelection>,
+        Write<'a, Settings>,
+        Read<'a, Score>,
+        Read<'a, InputState>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            mut menu,
+            mut ctl,
+            mut gs,
+            mut stage,
+            mut selection,
+            mut settings,
+            score,
+            input,
+        ): Self::SystemData,
+    ) {
+        let last = std::mem::replace(&mut self.last, input.clone());
+        if menu.fresh {
+            menu.fresh = false;
+            return;
+        }
+        if menu.items.is_empty() || ctl.is_changing() {
+            return;
+        }
+
+        let count = menu.items.len();
+        if input.down && !last.down {
+            menu.selected = (menu.selected + 1) % count;
+        }
+        if input.up && !last.up {
+            menu.selected = (menu.selected + count - 1) % count;
+        }
+        let selected = menu.items[menu.selected].action;
+        let volume = match selected {
+            MenuAction::Volume(channel) => Some(channel),
+            _ => None,
+        };
+        let action = if input.confirm && !last.confirm {
+            Some(selected)
+        } else if input.cancel && !last.cancel {
+            menu.back
+        } else if input.right && !last.right && volume.is_some() {
+            Some(selected)
+        } else {
+            None
+        };
+        let turned_down = match volume {
+            Some(channel) if input.left && !last.left => {
+                change_volume(&mut settings, channel, -1.);
+                true
+            }
+            _ => false,
+        };
+
+        match action {
+            Some(MenuAction::Go(transition)) => ctl.go(transition),
+            Some(MenuAction::FadeTo(transition)) => ctl.fade_to(transition),
+            Some(MenuAction::Start(number)) => {
+                stage.number = number;
+                ctl.fade_to(Transition::Reset(Scene::Playing));
+            }
+            Some(MenuAction::Difficulty) => {
+                let difficulty = next_difficulty(gs.difficulty);
+                gs.difficulty = difficulty;
+                if let Err(err) = settings.change(|settings| settings.difficulty = difficulty) {
+                    eprintln!("could not save the settings: {}", err);
+                }
+            }
+            Some(MenuAction::ShowHitbox) => {
+                let shown = !settings.show_hitbox;
+                if let Err(err) = settings.change(|settings| settings.show_hitbox = shown) {
+                    eprintln!("could not save the settings: {}", err);
+                }
+            }
+            Some(MenuAction::Watch(i)) => {
+                selection.path = Replay::list().get(i).cloned();
+                ctl.fade_to(Transition::Reset(Scene::Replay));
+            }
+            Some(MenuAction::Volume(channel)) => change_volume(&mut settings, channel, 1.),
+            None => {}
+        }
+        let changed = matches!(
+            action,
+            Some(MenuAction::Difficulty)
+                | Some(MenuAction::ShowHitbox)
+                | Some(MenuAction::Volume(_))
+        );
+        if changed || turned_down {
+            let selected = menu.selected;
+            *menu = Menu::for_scene(ctl.current, &gs, &score, &settings);
+            menu.selected = selected;
+            menu.fresh = false;
+        }
+    }
+}
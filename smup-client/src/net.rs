@@ -0,0 +1,606 @@
+extern crate specs;
+
+use crate::audio::SfxEvents;
+use crate::boss::BossDef;
+use crate::game;
+use crate::headless::Outcome;
+use crate::sim::{self, Checksum, Rng, STEP};
+use crate::snapshot::Snapshot;
+use crate::stage::Stage;
+use crate::types::{GameState, InputFrame, InputState, PlayerInputs};
+use specs::prelude::*;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8; 4] = b"SMNP";
+const HELLO: u8 = 0;
+const INPUT: u8 = 1;
+
+/// Furthest the simulation may run ahead of the input heard from the peer.
+pub const MAX_PREDICTION: u32 = 8;
+/// Input frames sent in one packet at most.
+const MAX_FRAMES: u32 = 64;
+/// Ticks of checksums kept to compare with the ones of the peer.
+const CHECKSUM_HISTORY: u32 = 120;
+/// Time without hearing from the peer before giving up on it.
+const TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const HELLO_PERIOD: Duration = Duration::from_millis(100);
+
+/// Made up network conditions, to try the netcode out on one machine.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Shim {
+    /// Milliseconds every packet is held back.
+    pub latency: u64,
+    /// Up to this many milliseconds more, so packets can overtake each other.
+    pub jitter: u64,
+    /// Share of the packets dropped, from 0 to 1.
+    pub loss: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct NetConfig {
+    pub local: SocketAddr,
+    pub peer: SocketAddr,
+    /// Player slot of this machine. Slot 0 hosts and picks the seed.
+    pub slot: usize,
+    /// Ticks between reading the local input and playing it. Hides that
+    /// much latency without rolling back.
+    pub delay: u32,
+    pub shim: Shim,
+}
+
+/// UDP socket talking to the peer, through the shim.
+struct Link {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    shim: Shim,
+    rng: Rng,
+    outgoing: Vec<(Instant, Vec<u8>)>,
+}
+
+impl Link {
+    fn open(config: &NetConfig) -> Result<Link, Box<dyn Error>> {
+        let socket = UdpSocket::bind(config.local)?;
+        socket.set_nonblocking(true)?;
+        Ok(Link {
+            socket,
+            peer: config.peer,
+            shim: config.shim,
+            rng: Rng::new(config.local.port() as u64),
+            outgoing: vec![],
+        })
+    }
+
+    fn send(&mut self, packet: Vec<u8>) {
+        if self.rng.next_f32() < self.shim.loss {
+            return;
+        }
+        let jitter = self.rng.next_u64() % (self.shim.jitter + 1);
+        let due = Instant::now() + Duration::from_millis(self.shim.latency + jitter);
+        self.outgoing.push((due, packet));
+        self.flush();
+    }
+
+    /// Sends the packets the shim held back long enough.
+    fn flush(&mut self) {
+        let now = Instant::now();
+        let (socket, peer) = (&self.socket, self.peer);
+        self.outgoing.retain(|(due, packet)| {
+            if *due > now {
+                return true;
+            }
+            // failed sends are nothing more than lost packets
+            let _ = socket.send_to(packet, peer);
+            false
+        });
+    }
+
+    fn receive(&mut self) -> Vec<Packet> {
+        let mut buf = [0; 2048];
+        let mut packets = vec![];
+        while let Ok((len, from)) = self.socket.recv_from(&mut buf) {
+            if from != self.peer {
+                continue;
+            }
+            if let Ok(packet) = Packet::decode(&buf[..len]) {
+                packets.push(packet);
+            }
+        }
+        packets
+    }
+}
+
+/// What peers tell each other. Every packet carries the seed picked by the
+/// host, so the guest can start from whichever packet arrives first.
+#[derive(Debug)]
+enum Message {
+    Hello,
+    Input {
+        /// Ticks of input received from the other side so far.
+        ack: u32,
+        /// Tick of the first frame.
+        from: u32,
+        frames: Vec<InputFrame>,
+        /// Checksum of the last tick played with confirmed input.
+        checksum: Option<(u32, u64)>,
+    },
+}
+
+#[derive(Debug)]
+struct Packet {
+    seed: u64,
+    message: Message,
+}
+
+impl Packet {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64);
+        out.extend_from_slice(MAGIC);
+        match &self.message {
+            Message::Hello => {
+                out.push(HELLO);
+                out.extend_from_slice(&self.seed.to_le_bytes());
+            }
+            Message::Input {
+                ack,
+                from,
+                frames,
+                checksum,
+            } => {
+                out.push(INPUT);
+                out.extend_from_slice(&self.seed.to_le_bytes());
+                out.extend_from_slice(&ack.to_le_bytes());
+                out.extend_from_slice(&from.to_le_bytes());
+                out.push(frames.len() as u8);
+                for frame in frames.iter() {
+                    write_frame(&mut out, frame);
+                }
+                let (tick, value) = checksum.unwrap_or((u32::MAX, 0));
+                out.extend_from_slice(&tick.to_le_bytes());
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Packet, Box<dyn Error>> {
+        let mut r = Reader { bytes };
+        if r.take(4)? != MAGIC {
+            return Err("not a netplay packet".into());
+        }
+        let kind = r.u8()?;
+        let seed = r.u64()?;
+        let message = match kind {
+            HELLO => Message::Hello,
+            INPUT => {
+                let ack = r.u32()?;
+                let from = r.u32()?;
+                let count = r.u8()?;
+                let mut frames = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    frames.push(r.frame()?);
+                }
+                let tick = r.u32()?;
+                let value = r.u64()?;
+                Message::Input {
+                    ack,
+                    from,
+                    frames,
+                    checksum: if tick == u32::MAX {
+                        None
+                    } else {
+                        Some((tick, value))
+                    },
+                }
+            }
+            other => return Err(format!("unknown packet kind {}", other).into()),
+        };
+        Ok(Packet { seed, message })
+    }
+}
+
+pub(crate) fn write_frame(out: &mut Vec<u8>, frame: &InputFrame) {
+    out.push(frame.buttons);
+    out.extend_from_slice(&frame.x.to_le_bytes());
+    out.extend_from_slice(&frame.y.to_le_bytes());
+}
+
+/// Reads the little endian values packets are made of.
+pub(crate) struct Reader<'a> {
+    pub bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if self.bytes.len() < n {
+            return Err("truncated packet".into());
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, Box<dyn Error>> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn frame(&mut self) -> Result<InputFrame, Box<dyn Error>> {
+        Ok(InputFrame {
+            buttons: self.u8()?,
+            x: self.f32()?,
+            y: self.f32()?,
+        })
+    }
+}
+
+/// Two player game over UDP with rollback. Remote input that has not
+/// arrived yet is guessed to be the last one heard, and when the real one
+/// turns out different the world goes back to a snapshot taken before the
+/// wrong guess and the ticks since are played again.
+pub struct NetSession {
+    config: NetConfig,
+    link: Link,
+    seed: u64,
+    dispatcher: Dispatcher<'static, 'static>,
+    /// Input of this machine by tick, `delay` ticks ahead of the world.
+    local: Vec<InputFrame>,
+    /// Input of the peer by tick, as far as it arrived without gaps.
+    remote: Vec<InputFrame>,
+    /// Remote input guessed for the ticks played ahead of `remote`.
+    guesses: BTreeMap<u32, InputFrame>,
+    /// World before every tick played on a guess.
+    snapshots: BTreeMap<u32, Snapshot>,
+    /// Ticks of local input the peer has.
+    peer_ack: u32,
+    /// World checksum after every tick played.
+    checksums: BTreeMap<u32, u64>,
+    /// Last checksum reported by the peer, kept until ours is confirmed.
+    peer_checksum: Option<(u32, u64)>,
+    /// First tick where the peers ended up in different worlds.
+    pub desync: Option<u32>,
+    /// How the run ended and at which tick, as far as this machine knows.
+    over: Option<(u32, Outcome)>,
+    /// Last tick to play, for runs of a set length.
+    pub stop_at: u32,
+    last_heard: Instant,
+    pub rollbacks: u32,
+    pub resimulated: u32,
+}
+
+impl NetSession {
+    /// Waits for the peer and starts a two player run with it.
+    pub fn connect(
+        world: &mut World,
+        config: NetConfig,
+        boss: &BossDef,
+        seed: u64,
+    ) -> Result<NetSession, Box<dyn Error>> {
+        let mut link = Link::open(&config)?;
+        let started = Instant::now();
+        let mut hello = started - HELLO_PERIOD;
+        let seed = loop {
+            if started.elapsed() > CONNECT_TIMEOUT {
+                return Err(format!("no answer from {}", config.peer).into());
+            }
+            if hello.elapsed() >= HELLO_PERIOD {
+                link.send(
+                    Packet {
+                        seed,
+                        message: Message::Hello,
+                    }
+                    .encode(),
+                );
+                hello = Instant::now();
+            }
+            link.flush();
+            // the host starts as soon as the guest answers, the guest once
+            // it knows the seed of the host
+            if let Some(packet) = link.receive().into_iter().next() {
+                break if config.slot == 0 { seed } else { packet.seed };
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+
+        let mut dispatcher = game::simulation(DispatcherBuilder::new()).build();
+        dispatcher.setup(world);
+        game::start_run(world, boss, seed, 2);
+        let delay = config.delay;
+        Ok(NetSession {
+            config,
+            link,
+            seed,
+            dispatcher,
+            local: vec![InputFrame::default(); delay as usize],
+            remote: vec![],
+            guesses: BTreeMap::new(),
+            snapshots: BTreeMap::new(),
+            peer_ack: 0,
+            checksums: BTreeMap::new(),
+            peer_checksum: None,
+            desync: None,
+            over: None,
+            stop_at: u32::MAX,
+            last_heard: Instant::now(),
+            rollbacks: 0,
+            resimulated: 0,
+        })
+    }
+
+    /// Tick the next local input will be played at.
+    pub fn input_tick(&self) -> u32 {
+        self.local.len() as u32
+    }
+
+    /// Handles what the peer sent, plays the next tick with `local` as the
+    /// input of this machine when the peer is not too far behind, and sends
+    /// the input the peer is missing. Without `local` the world stays where
+    /// it is but the peer keeps hearing from this machine.
+    pub fn update(
+        &mut self,
+        world: &mut World,
+        local: Option<InputFrame>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.receive(world);
+        if let Some(frame) = local {
+            if self.can_advance(world) {
+                self.local.push(frame);
+                self.simulate(world);
+            }
+        }
+        self.send(world);
+        if self.last_heard.elapsed() > TIMEOUT {
+            return Err(format!("lost the connection to {}", self.config.peer).into());
+        }
+        Ok(())
+    }
+
+    /// How the run ended, once both machines agree on the input up to
+    /// there.
+    pub fn finished(&self) -> Option<&Outcome> {
+        match &self.over {
+            Some((tick, outcome)) if *tick <= self.remote.len() as u32 => Some(outcome),
+            _ => None,
+        }
+    }
+
+    /// Keeps answering the peer after the run is over, until it has all
+    /// the input it needs or stops answering.
+    pub fn linger(&mut self, world: &mut World) {
+        let started = Instant::now();
+        while self.peer_ack < self.local.len() as u32 && started.elapsed() < TIMEOUT {
+            self.receive(world);
+            self.send(world);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn can_advance(&self, world: &World) -> bool {
+        let tick = current_tick(world);
+        tick < self.stop_at && tick < self.remote.len() as u32 + MAX_PREDICTION
+    }
+
+    fn receive(&mut self, world: &mut World) {
+        for packet in self.link.receive() {
+            self.last_heard = Instant::now();
+            if let Message::Input {
+                ack,
+                from,
+                frames,
+                checksum,
+            } = packet.message
+            {
+                self.peer_ack = self.peer_ack.max(ack);
+                let known = self.remote.len() as u32;
+                if from <= known {
+                    let skip = (known - from) as usize;
+                    self.remote.extend(frames.into_iter().skip(skip));
+                }
+                if checksum.is_some() {
+                    self.peer_checksum = checksum;
+                }
+            }
+        }
+
+        let confirmed = self.remote.len() as u32;
+        let wrong = self
+            .guesses
+            .range(..confirmed)
+            .find(|(tick, guess)| self.remote[**tick as usize] != **guess)
+            .map(|(tick, _)| *tick);
+        match wrong {
+            Some(tick) => self.rollback(world, tick),
+            None => {
+                // the guesses were right, nothing to go back to before them
+                self.guesses = self.guesses.split_off(&confirmed);
+                self.snapshots = self.snapshots.split_off(&confirmed);
+            }
+        }
+        self.compare_checksums(world);
+    }
+
+    /// Plays the tick the world is at with the input known or guessed for
+    /// it.
+    fn simulate(&mut self, world: &mut World) {
+        let tick = current_tick(world);
+        let remote = match self.remote.get(tick as usize) {
+            Some(frame) => *frame,
+            None => {
+                self.snapshots.insert(tick, Snapshot::take(world));
+                let guess = self.remote.last().cloned().unwrap_or_default();
+                self.guesses.insert(tick, guess);
+                guess
+            }
+        };
+        {
+            let mut inputs = world.write_resource::<PlayerInputs>();
+            inputs.set(self.config.slot, self.local[tick as usize]);
+            inputs.set(1 - self.config.slot, remote);
+            let mut gs = world.write_resource::<GameState>();
+            gs.paused = false;
+            gs.advance(STEP);
+        }
+        self.dispatcher.dispatch(world);
+        sim::maintain(world);
+
+        self.checksums.insert(tick, world.fetch::<Checksum>().value);
+        if self.over.is_none() && (is_over(world) || tick + 1 >= self.stop_at) {
+            self.over = Some((tick + 1, Outcome::of(world)));
+        }
+    }
+
+    /// Goes back to before `tick` and plays the ticks since again.
+    fn rollback(&mut self, world: &mut World, tick: u32) {
+        let now = current_tick(world);
+        let snapshot = self
+            .snapshots
+            .remove(&tick)
+            .expect("every tick played on a guess has a snapshot");
+        // the keys held right now are not part of the rollback
+        let input = (*world.fetch::<InputState>()).clone();
+        let mouse = world.fetch::<GameState>().mouse_position.clone();
+        snapshot.restore(world);
+        world.insert(input);
+        world.write_resource::<GameState>().mouse_position = mouse;
+
+        self.guesses.clear();
+        self.snapshots.clear();
+        if matches!(self.over, Some((over, _)) if over > tick) {
+            self.over = None;
+        }
+        // the sounds of these ticks were heard when they were first played
+        let heard = world.remove::<SfxEvents>();
+        world.insert(SfxEvents::new());
+        while current_tick(world) < now {
+            self.simulate(world);
+        }
+        if let Some(heard) = heard {
+            world.insert(heard);
+        }
+        self.rollbacks += 1;
+        self.resimulated += now - tick;
+    }
+
+    /// Checks the last checksum of the peer against ours for the same
+    /// tick, once that tick was played with confirmed input on both sides.
+    fn compare_checksums(&mut self, world: &World) {
+        let settled = self.settled(world);
+        if let Some((tick, theirs)) = self.peer_checksum {
+            if tick < settled {
+                if let Some(&ours) = self.checksums.get(&tick) {
+                    if ours != theirs && self.desync.is_none() {
+                        eprintln!(
+                            "desync at tick {}: {:016x} here, {:016x} on the peer",
+                            tick, ours, theirs
+                        );
+                        self.desync = Some(tick);
+                    }
+                }
+                self.peer_checksum = None;
+            }
+        }
+        let oldest = settled.saturating_sub(CHECKSUM_HISTORY);
+        self.checksums = self.checksums.split_off(&oldest);
+    }
+
+    /// Ticks played with confirmed input only.
+    fn settled(&self, world: &World) -> u32 {
+        current_tick(world).min(self.remote.len() as u32)
+    }
+
+    fn send(&mut self, world: &World) {
+        let end = (self.local.len() as u32).min(self.peer_ack + MAX_FRAMES);
+        let frames = self.local[self.peer_ack as usize..end as usize].to_vec();
+        let checksum = self
+            .settled(world)
+            .checked_sub(1)
+            .and_then(|tick| self.checksums.get(&tick).map(|value| (tick, *value)));
+        let packet = Packet {
+            seed: self.seed,
+            message: Message::Input {
+                ack: self.remote.len() as u32,
+                from: self.peer_ack,
+                frames,
+                checksum,
+            },
+        };
+        self.link.send(packet.encode());
+        self.link.flush();
+    }
+}
+
+fn current_tick(world: &World) -> u32 {
+    world.fetch::<GameState>().tick
+}
+
+fn is_over(world: &World) -> bool {
+    world.fetch::<GameState>().lives == 0 || world.fetch::<Stage>().cleared
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::{play_netplay, InputScript};
+
+    fn free_port() -> SocketAddr {
+        UdpSocket::bind("127.0.0.1:0")
+            .and_then(|socket| socket.local_addr())
+            .unwrap()
+    }
+
+    #[test]
+    fn peers_agree_over_a_bad_network() {
+        let boss = BossDef::load("assets/bosses/guardian.ron").unwrap();
+        let script = InputScript::load("assets/scripts/attack.ron").unwrap();
+        let (a, b) = (free_port(), free_port());
+        let shim = Shim {
+            latency: 40,
+            jitter: 30,
+            loss: 0.1,
+        };
+        let peers: Vec<_> = [(a, b, 0), (b, a, 1)]
+            .iter()
+            .map(|&(local, peer, slot)| {
+                let (boss, script) = (boss.clone(), script.clone());
+                let config = NetConfig {
+                    local,
+                    peer,
+                    slot,
+                    delay: 1,
+                    shim,
+                };
+                thread::spawn(move || {
+                    let (outcome, session) = play_netplay(&boss, config, &script, 7, 150).unwrap();
+                    (outcome, session.desync, session.rollbacks)
+                })
+            })
+            .collect();
+        let results: Vec<(Outcome, Option<u32>, u32)> =
+            peers.into_iter().map(|peer| peer.join().unwrap()).collect();
+
+        for (_, desync, _) in results.iter() {
+            assert_eq!(*desync, None);
+        }
+        // every rollback restores a snapshot and rebuilds the physics worlds
+        assert!(results.iter().any(|(_, _, rollbacks)| *rollbacks > 0));
+        let (first, second) = (&results[0].0, &results[1].0);
+        assert_eq!(first.to_string(), second.to_string());
+        assert_eq!(first.ticks, 150);
+    }
+}
@@ -0,0 +1,253 @@
+extern crate specs;
+
+use crate::render::Sprite;
+use crate::score::Score;
+use crate::server::{StateDelta, ToClient, ToServer, WorldState, HISTORY, TIMEOUT};
+use crate::sim::UPS;
+use crate::types::{GameState, InputFrame, Position};
+use specs::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Ticks the client shows behind the latest state, so there is usually a
+/// newer one to move towards.
+const INTERPOLATION_DELAY: f64 = 3.;
+/// Ticks the shown time may drift from where it should be before it jumps
+/// there instead of catching up.
+const MAX_DRIFT: f64 = 10.;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const JOIN_PERIOD: Duration = Duration::from_millis(200);
+/// Largest datagram UDP can carry.
+const MAX_DATAGRAM: usize = 65536;
+
+#[derive(Clone, Debug, Default)]
+pub struct ClientStats {
+    pub states: u64,
+    pub bytes: u64,
+    /// States that came whole instead of as changes.
+    pub full_states: u64,
+    /// States encoded against one no longer kept.
+    pub unusable: u64,
+}
+
+/// Plays on a `Server`: sends the local input and shows the states the
+/// server sends back, drawn a little in the past and interpolated between
+/// the two around the shown time.
+pub struct OnlineClient {
+    socket: UdpSocket,
+    server: SocketAddr,
+    /// Player slot, spectators have none.
+    pub slot: Option<usize>,
+    pub seed: u64,
+    states: BTreeMap<u32, WorldState>,
+    seq: u32,
+    /// Tick shown, between two received states.
+    shown_tick: f64,
+    /// Local entities standing for the ones of the server.
+    shown: HashMap<u64, Entity>,
+    last_heard: Instant,
+    pub stats: ClientStats,
+    /// Where datagrams are received into.
+    buf: Vec<u8>,
+}
+
+impl OnlineClient {
+    pub fn connect(local: SocketAddr, server: SocketAddr) -> Result<OnlineClient, Box<dyn Error>> {
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        let started = Instant::now();
+        let mut buf = [0; 2048];
+        loop {
+            if started.elapsed() > CONNECT_TIMEOUT {
+                return Err(format!("no answer from {}", server).into());
+            }
+            // a send failing is a lost join, tried again below
+            let _ = socket.send_to(&ToServer::Join.encode(), server);
+            let asked = Instant::now();
+            while asked.elapsed() < JOIN_PERIOD {
+                if let Ok((len, from)) = socket.recv_from(&mut buf) {
+                    if from != server {
+                        continue;
+                    }
+                    if let Ok(ToClient::Welcome { slot, seed }) = ToClient::decode(&buf[..len]) {
+                        return Ok(OnlineClient {
+                            socket,
+                            server,
+                            slot,
+                            seed,
+                            states: BTreeMap::new(),
+                            seq: 0,
+                            shown_tick: 0.,
+                            shown: HashMap::new(),
+                            last_heard: Instant::now(),
+                            stats: ClientStats::default(),
+                            buf: vec![0; MAX_DATAGRAM],
+                        });
+                    }
+                }
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+    }
+
+    /// Latest state received.
+    pub fn latest(&self) -> Option<&WorldState> {
+        self.states.values().next_back()
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.latest().map(|state| state.over).unwrap_or(false)
+    }
+
+    /// Takes in the states that arrived, sends `local` when there is input
+    /// for a new tick and moves the shown entities `real_delta` seconds
+    /// ahead.
+    pub fn update(
+        &mut self,
+        world: &mut World,
+        local: Option<InputFrame>,
+        real_delta: f64,
+    ) -> Result<(), Box<dyn Error>> {
+        self.receive();
+        if let Some(frame) = local {
+            self.seq += 1;
+            let packet = ToServer::Input {
+                seq: self.seq,
+                ack: self.states.keys().next_back().cloned(),
+                frame,
+            };
+            // lost input is replaced by the next one
+            let _ = self.socket.send_to(&packet.encode(), self.server);
+        }
+        self.show(world, real_delta);
+        if self.last_heard.elapsed() > TIMEOUT {
+            return Err(format!("lost the connection to {}", self.server).into());
+        }
+        Ok(())
+    }
+
+    /// Tells the server this client is gone and takes its entities out of
+    /// the world.
+    pub fn leave(self, world: &mut World) {
+        let _ = self.socket.send_to(&ToServer::Leave.encode(), self.server);
+        let shown: Vec<Entity> = self.shown.values().cloned().collect();
+        let _ = world.delete_entities(&shown);
+        world.maintain();
+    }
+
+    fn receive(&mut self) {
+        while let Ok((len, from)) = self.socket.recv_from(&mut self.buf) {
+            if from != self.server {
+                continue;
+            }
+            self.last_heard = Instant::now();
+            if let Ok(ToClient::State(delta)) = ToClient::decode(&self.buf[..len]) {
+                self.stats.states += 1;
+                self.stats.bytes += len as u64;
+                self.take(delta);
+            }
+        }
+    }
+
+    fn take(&mut self, delta: StateDelta) {
+        if self.states.contains_key(&delta.tick) {
+            return;
+        }
+        let base = match delta.base {
+            None => {
+                self.stats.full_states += 1;
+                None
+            }
+            Some(tick) => match self.states.get(&tick) {
+                Some(base) => Some(base),
+                None => {
+                    self.stats.unusable += 1;
+                    return;
+                }
+            },
+        };
+        if let Ok(state) = delta.apply(base) {
+            self.states.insert(state.tick, state);
+            while self.states.len() > HISTORY {
+                let oldest = *self.states.keys().next().expect("more states than kept");
+                self.states.remove(&oldest);
+            }
+        }
+    }
+
+    /// Moves the shown tick along and puts the entities where they are at
+    /// that time.
+    fn show(&mut self, world: &mut World, real_delta: f64) {
+        let latest = match self.states.keys().next_back() {
+            Some(tick) => *tick as f64,
+            None => return,
+        };
+        let target = latest - INTERPOLATION_DELAY;
+        self.shown_tick += real_delta * UPS as f64;
+        if (self.shown_tick - target).abs() > MAX_DRIFT {
+            self.shown_tick = target;
+        } else {
+            // catch up slowly rather than jump
+            self.shown_tick += (target - self.shown_tick) * 0.1;
+        }
+
+        let tick = self.shown_tick.floor().max(0.) as u32;
+        let from = match self.states.range(..=tick).next_back() {
+            Some((_, state)) => state,
+            None => self.states.values().next().expect("at least one state"),
+        };
+        let to = self.states.range(tick + 1..).next().map(|(_, state)| state);
+        let alpha = match to {
+            Some(to) => {
+                let span = (to.tick - from.tick) as f64;
+                ((self.shown_tick - from.tick as f64) / span).clamp(0., 1.) as f32
+            }
+            None => 0.,
+        };
+
+        {
+            let mut gs = world.write_resource::<GameState>();
+            gs.lives = from.lives;
+            world.write_resource::<Score>().total = from.score;
+        }
+        let gone: Vec<u64> = self
+            .shown
+            .keys()
+            .filter(|key| !from.entities.contains_key(key))
+            .cloned()
+            .collect();
+        for key in gone {
+            if let Some(entity) = self.shown.remove(&key) {
+                let _ = world.delete_entity(entity);
+            }
+        }
+        for (key, state) in from.entities.iter() {
+            let (mut x, mut y) = (state.x, state.y);
+            if let Some(next) = to.and_then(|to| to.entities.get(key)) {
+                x += (next.x - x) * alpha;
+                y += (next.y - y) * alpha;
+            }
+            let position = Position { x, y };
+            match self.shown.get(key) {
+                Some(&entity) => {
+                    let mut positions = world.write_storage::<Position>();
+                    let _ = positions.insert(entity, position);
+                    let mut sprites = world.write_storage::<Sprite>();
+                    let _ = sprites.insert(entity, state.look.sprite());
+                }
+                None => {
+                    let entity = world
+                        .create_entity()
+                        .with(position)
+                        .with(state.look.sprite())
+                        .build();
+                    self.shown.insert(*key, entity);
+                }
+            }
+        }
+        world.maintain();
+    }
+}
@@ -1,22 +1,183 @@
 extern crate specs;
 
-use crate::types::{GameState};
+use crate::render::DebugOverlay;
+use crate::types::{GameState, Position};
 
 pub use nalgebra::Vector2;
 
-use nphysics2d::math::Velocity;
+use nalgebra::Isometry2;
+use ncollide2d::pipeline::object::CollisionGroups;
+use ncollide2d::query::{self, Proximity};
+use ncollide2d::shape::{Ball, ShapeHandle};
+use nphysics2d::force_generator::DefaultForceGeneratorSet;
+use nphysics2d::joint::DefaultJointConstraintSet;
 use nphysics2d::object::{
     BodyPartHandle, BodyStatus, ColliderDesc, DefaultBodyHandle, DefaultBodySet,
-    DefaultColliderSet, RigidBodyDesc
+    DefaultColliderHandle, DefaultColliderSet, RigidBodyDesc,
 };
-use nphysics2d::joint::{DefaultJointConstraintSet};
 use nphysics2d::world::{DefaultGeometricalWorld, DefaultMechanicalWorld};
-use nphysics2d::force_generator::{DefaultForceGeneratorSet};
-use specs::{System, Read};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use specs::{Component, VecStorage};
+use std::collections::BTreeMap;
 
 pub type Vec2 = Vector2<f32>;
 pub type Handle = DefaultBodyHandle;
 
+/// What a sensor collider stands for, used to tell apart the colliders
+/// involved in a proximity event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Sensor {
+    /// The tiny area that kills the player when touched by a bullet.
+    Hitbox,
+    /// The larger area around the player where bullets count as grazed.
+    Grazebox,
+    /// An enemy bullet.
+    Bullet,
+    /// A shot fired by the player.
+    Shot,
+    /// The body of an enemy, hurt by shots.
+    Enemy,
+}
+
+const PLAYER_GROUP: usize = 0;
+const BULLET_GROUP: usize = 1;
+const SHOT_GROUP: usize = 2;
+const ENEMY_GROUP: usize = 3;
+
+impl Sensor {
+    fn groups(self) -> CollisionGroups {
+        match self {
+            Sensor::Hitbox | Sensor::Grazebox => CollisionGroups::new()
+                .with_membership(&[PLAYER_GROUP])
+                .with_whitelist(&[BULLET_GROUP]),
+            Sensor::Bullet => CollisionGroups::new()
+                .with_membership(&[BULLET_GROUP])
+                .with_whitelist(&[PLAYER_GROUP]),
+            Sensor::Shot => CollisionGroups::new()
+                .with_membership(&[SHOT_GROUP])
+                .with_whitelist(&[ENEMY_GROUP]),
+            Sensor::Enemy => CollisionGroups::new()
+                .with_membership(&[ENEMY_GROUP])
+                .with_whitelist(&[SHOT_GROUP]),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct SensorDesc {
+    pub kind: Sensor,
+    pub radius: f32,
+}
+
+/// Circular sensors attached to an entity, all centered on its `Position`.
+#[derive(Clone, Component, Debug, Default, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Colliders {
+    pub sensors: Vec<SensorDesc>,
+}
+
+impl Colliders {
+    pub fn new(kind: Sensor, radius: f32) -> Colliders {
+        Colliders {
+            sensors: vec![SensorDesc { kind, radius }],
+        }
+    }
+
+    pub fn with(mut self, kind: Sensor, radius: f32) -> Colliders {
+        self.sensors.push(SensorDesc { kind, radius });
+        self
+    }
+}
+
+/// One side of a proximity event.
+#[derive(Clone, Copy, Debug)]
+pub struct SensorRef {
+    pub entity: Entity,
+    pub kind: Sensor,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SensorEvent {
+    pub a: SensorRef,
+    pub b: SensorRef,
+    /// `true` when the sensors started overlapping, `false` when they
+    /// stopped.
+    pub started: bool,
+}
+
+impl SensorEvent {
+    /// Returns the entities owning the `first` and `second` sensors, in that
+    /// order, if the event happened between those two kinds.
+    pub fn between(&self, first: Sensor, second: Sensor) -> Option<(Entity, Entity)> {
+        if self.a.kind == first && self.b.kind == second {
+            Some((self.a.entity, self.b.entity))
+        } else if self.b.kind == first && self.a.kind == second {
+            Some((self.b.entity, self.a.entity))
+        } else {
+            None
+        }
+    }
+}
+
+/// Proximity events produced by the last physics step.
+#[derive(Debug, Default)]
+pub struct SensorEvents {
+    pub events: Vec<SensorEvent>,
+}
+
+/// A physics body as of the last step.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BodyState {
+    pub entity: u32,
+    pub at: (f32, f32),
+    pub colliders: Colliders,
+}
+
+/// The bodies of the physics worlds, published after every step so they
+/// can be saved with the rest of the world. Setting `restored` has the
+/// worlds rebuilt from them on the next step.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PhysicsBodies {
+    pub bodies: Vec<BodyState>,
+    #[serde(skip)]
+    pub restored: bool,
+}
+
+/// A collider where the physics world has it.
+#[derive(Clone, Copy, Debug)]
+pub struct ColliderShape {
+    pub sensor: SensorRef,
+    pub center: (f32, f32),
+    pub radius: f32,
+    /// Corners of the bounding box, smallest first.
+    pub aabb: ((f32, f32), (f32, f32)),
+}
+
+/// Where two overlapping colliders touch.
+#[derive(Clone, Copy, Debug)]
+pub struct Contact {
+    /// The deepest points of each collider inside the other.
+    pub points: ((f32, f32), (f32, f32)),
+    /// From the first collider to the second.
+    pub normal: (f32, f32),
+    pub depth: f32,
+}
+
+/// The colliders and contacts of the physics bodies, for the debug overlay.
+/// Only kept up to date by `PhysicsDebugSystem` while the overlay is shown.
+#[derive(Debug, Default)]
+pub struct PhysicsDebug {
+    pub colliders: Vec<ColliderShape>,
+    pub contacts: Vec<Contact>,
+}
+
+struct Tracked {
+    body: Handle,
+    colliders: Vec<DefaultColliderHandle>,
+    sensors: Colliders,
+}
+
 pub struct PhysicsSystem {
     gworld: DefaultGeometricalWorld<f32>,
     mworld: DefaultMechanicalWorld<f32>,
@@ -24,56 +185,339 @@ pub struct PhysicsSystem {
     colliders: DefaultColliderSet<f32>,
     constraints: DefaultJointConstraintSet<f32>,
     forces: DefaultForceGeneratorSet<f32>,
+    tracked: BTreeMap<Entity, Tracked>,
+    /// Run the bodies belong to, the worlds start over with every run.
+    run: u32,
 }
 
 impl<'a> System<'a> for PhysicsSystem {
-    type SystemData = Read<'a, GameState>;
-    fn run(&mut self, gs: Self::SystemData) {
-        self.step_for(gs.delta);
-        println!("Last iteration: {}", self.mworld.counters.step_time)
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, GameState>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Colliders>,
+        Write<'a, SensorEvents>,
+        Write<'a, PhysicsBodies>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, gs, positions, colliders, mut events, mut bodies): Self::SystemData,
+    ) {
+        events.events.clear();
+        if bodies.restored {
+            self.restore(&entities, &bodies);
+            self.run = gs.run;
+            bodies.restored = false;
+        } else if self.run != gs.run {
+            *self = PhysicsSystem::new();
+            self.run = gs.run;
+        }
+        self.sync_bodies(&entities, &positions, &colliders);
+        if gs.delta > 0. {
+            self.step_for(gs.delta);
+            self.collect_events(&mut events);
+            self.publish(&mut bodies);
+        }
     }
 }
 
-impl PhysicsSystem {
+impl Default for PhysicsSystem {
+    fn default() -> PhysicsSystem {
+        PhysicsSystem::new()
+    }
+}
 
+impl PhysicsSystem {
     pub fn new() -> PhysicsSystem {
         let mut sys = PhysicsSystem {
             gworld: DefaultGeometricalWorld::new(),
-            mworld: DefaultMechanicalWorld::new(Vec2::new(0., 9.8)),
+            mworld: DefaultMechanicalWorld::new(Vec2::new(0., 0.)),
             bodies: DefaultBodySet::new(),
             colliders: DefaultColliderSet::new(),
             constraints: DefaultJointConstraintSet::new(),
             forces: DefaultForceGeneratorSet::new(),
+            tracked: BTreeMap::new(),
+            run: 0,
         };
         sys.mworld.counters.enable();
         sys.gworld.maintain(&mut sys.bodies, &mut sys.colliders);
-        sys.mworld.maintain(&mut sys.gworld, &mut sys.bodies, &mut sys.colliders, &mut sys.constraints);
+        sys.mworld.maintain(
+            &mut sys.gworld,
+            &mut sys.bodies,
+            &mut sys.colliders,
+            &mut sys.constraints,
+        );
         sys
     }
 
-    fn step_for(&mut self, _update_dt: f64) {
+    fn step_for(&mut self, update_dt: f64) {
+        self.mworld.set_timestep(update_dt as f32);
         self.mworld.step(
             &mut self.gworld,
             &mut self.bodies,
             &mut self.colliders,
             &mut self.constraints,
-            &mut self.forces);
+            &mut self.forces,
+        );
+    }
+
+    /// Creates bodies for new entities, moves the existing ones to their
+    /// current `Position` and drops the ones whose entity is gone.
+    fn sync_bodies(
+        &mut self,
+        entities: &Entities,
+        positions: &ReadStorage<Position>,
+        colliders: &ReadStorage<Colliders>,
+    ) {
+        let stale: Vec<Entity> = self
+            .tracked
+            .keys()
+            .filter(|e| !entities.is_alive(**e) || !colliders.contains(**e))
+            .cloned()
+            .collect();
+        for entity in stale {
+            self.remove(entity);
+        }
+
+        for (entity, pos, col) in (entities, positions, colliders).join() {
+            let at = Vec2::new(pos.x, pos.y);
+            match self.tracked.get(&entity) {
+                Some(tracked) => {
+                    if let Some(body) = self.bodies.rigid_body_mut(tracked.body) {
+                        body.set_position(Isometry2::new(at, 0.));
+                    }
+                }
+                None => self.add(entity, at, col),
+            }
+        }
+    }
+
+    fn publish(&self, out: &mut PhysicsBodies) {
+        out.bodies.clear();
+        for (entity, tracked) in self.tracked.iter() {
+            if let Some(body) = self.bodies.rigid_body(tracked.body) {
+                let at = body.position().translation.vector;
+                out.bodies.push(BodyState {
+                    entity: entity.id(),
+                    at: (at.x, at.y),
+                    colliders: tracked.sensors.clone(),
+                });
+            }
+        }
+    }
+
+    /// Starts the worlds over with the saved bodies. Only collision
+    /// detection is run on them, to find out which sensors already overlap
+    /// without moving anything; its events were already reported before the
+    /// bodies were saved.
+    fn restore(&mut self, entities: &Entities, saved: &PhysicsBodies) {
+        *self = PhysicsSystem::new();
+        for body in saved.bodies.iter() {
+            let at = Vec2::new(body.at.0, body.at.1);
+            self.add(entities.entity(body.entity), at, &body.colliders);
+        }
+        self.gworld.maintain(&mut self.bodies, &mut self.colliders);
+        self.gworld
+            .sync_colliders(&self.bodies, &mut self.colliders);
+        self.gworld.perform_broad_phase(&self.colliders);
+        self.gworld.perform_narrow_phase(&self.colliders);
+        self.gworld.clear_events();
     }
 
-    pub fn add_box(&mut self, pos: Vec2) -> Handle {
-        use ncollide2d::shape::{Cuboid, ShapeHandle};
-        let shape = ShapeHandle::new(Cuboid::new(Vector2::new(25., 25.)));
+    fn add(&mut self, entity: Entity, at: Vec2, col: &Colliders) {
+        // Pairs of kinematic bodies are never tested against each other, so
+        // bodies are dynamic ones without gravity that get teleported to
+        // their entity position every step.
         let body = RigidBodyDesc::new()
-            .translation(pos)
-            .velocity(Velocity::linear(1., 0.))
-            .status(BodyStatus::Kinematic)
+            .translation(at)
+            .status(BodyStatus::Dynamic)
+            .gravity_enabled(false)
+            .sleep_threshold(None)
+            .mass(1.)
             .build();
-        let handle = self.bodies.insert(body);
-        self.colliders.insert(
-            ColliderDesc::new(shape)
-                .density(1.)
-                .build(BodyPartHandle(handle, 0)),
+        let body = self.bodies.insert(body);
+        let colliders = col
+            .sensors
+            .iter()
+            .map(|desc| {
+                let shape = ShapeHandle::new(Ball::new(desc.radius));
+                self.colliders.insert(
+                    ColliderDesc::new(shape)
+                        .sensor(true)
+                        .collision_groups(desc.kind.groups())
+                        .user_data(SensorRef {
+                            entity,
+                            kind: desc.kind,
+                        })
+                        .build(BodyPartHandle(body, 0)),
+                )
+            })
+            .collect();
+        self.tracked.insert(
+            entity,
+            Tracked {
+                body,
+                colliders,
+                sensors: col.clone(),
+            },
         );
-        handle
+    }
+
+    fn remove(&mut self, entity: Entity) {
+        if let Some(tracked) = self.tracked.remove(&entity) {
+            for collider in tracked.colliders {
+                self.colliders.remove(collider);
+            }
+            self.bodies.remove(tracked.body);
+        }
+    }
+
+    fn sensor_ref(&self, handle: DefaultColliderHandle) -> Option<SensorRef> {
+        self.colliders
+            .get(handle)
+            .and_then(|c| c.user_data())
+            .and_then(|data| data.downcast_ref::<SensorRef>())
+            .cloned()
+    }
+
+    fn collect_events(&self, events: &mut SensorEvents) {
+        for ev in self.gworld.proximity_events().iter() {
+            let started = match (ev.prev_status, ev.new_status) {
+                (Proximity::Intersecting, Proximity::Intersecting) => continue,
+                (_, Proximity::Intersecting) => true,
+                (Proximity::Intersecting, _) => false,
+                _ => continue,
+            };
+            if let (Some(a), Some(b)) =
+                (self.sensor_ref(ev.collider1), self.sensor_ref(ev.collider2))
+            {
+                events.events.push(SensorEvent { a, b, started });
+            }
+        }
+        // keep the order stable between runs for deterministic replays
+        events
+            .events
+            .sort_by_key(|ev| (ev.a.entity, ev.b.entity, ev.started));
+    }
+}
+
+/// Fills `PhysicsDebug` from the bodies published by the last physics
+/// step. Runs with the drawing, so the simulation never depends on the
+/// overlay.
+pub struct PhysicsDebugSystem {}
+impl<'a> System<'a> for PhysicsDebugSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, PhysicsBodies>,
+        Read<'a, DebugOverlay>,
+        Write<'a, PhysicsDebug>,
+    );
+
+    fn run(&mut self, (entities, bodies, overlay, mut debug): Self::SystemData) {
+        if !overlay.shown {
+            return;
+        }
+        let mut colliders = Vec::new();
+        for body in bodies.bodies.iter() {
+            let (x, y) = body.at;
+            let entity = entities.entity(body.entity);
+            for desc in body.colliders.sensors.iter() {
+                colliders.push(ColliderShape {
+                    sensor: SensorRef {
+                        entity,
+                        kind: desc.kind,
+                    },
+                    center: (x, y),
+                    radius: desc.radius,
+                    aabb: (
+                        (x - desc.radius, y - desc.radius),
+                        (x + desc.radius, y + desc.radius),
+                    ),
+                });
+            }
+        }
+        // sweep along x: only colliders whose boxes overlap get a contact
+        colliders.sort_by(|a, b| a.aabb.0 .0.total_cmp(&b.aabb.0 .0));
+        debug.contacts.clear();
+        for (i, c1) in colliders.iter().enumerate() {
+            let ((_, top), (right, bottom)) = c1.aabb;
+            let overlapping = colliders[i + 1..]
+                .iter()
+                .take_while(|c2| c2.aabb.0 .0 <= right)
+                .filter(|c2| c2.aabb.0 .1 <= bottom && top <= c2.aabb.1 .1);
+            for c2 in overlapping {
+                if c1.sensor.entity == c2.sensor.entity
+                    || !c1
+                        .sensor
+                        .kind
+                        .groups()
+                        .can_interact_with_groups(&c2.sensor.kind.groups())
+                {
+                    continue;
+                }
+                let contact = query::contact(
+                    &Isometry2::translation(c1.center.0, c1.center.1),
+                    &Ball::new(c1.radius),
+                    &Isometry2::translation(c2.center.0, c2.center.1),
+                    &Ball::new(c2.radius),
+                    0.,
+                );
+                if let Some(contact) = contact {
+                    debug.contacts.push(Contact {
+                        points: (
+                            (contact.world1.x, contact.world1.y),
+                            (contact.world2.x, contact.world2.y),
+                        ),
+                        normal: (contact.normal.x, contact.normal.y),
+                        depth: contact.depth,
+                    });
+                }
+            }
+        }
+        debug.colliders = colliders;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_contacts_are_between_overlapping_colliders_that_interact() {
+        let mut world = World::new();
+        let bodies = [
+            (
+                (0., 0.),
+                Colliders::new(Sensor::Hitbox, 2.).with(Sensor::Grazebox, 20.),
+            ),
+            ((5., 0.), Colliders::new(Sensor::Bullet, 4.)),
+            ((100., 0.), Colliders::new(Sensor::Bullet, 4.)),
+            ((5., 30.), Colliders::new(Sensor::Bullet, 4.)),
+            ((1., 0.), Colliders::new(Sensor::Shot, 3.)),
+        ];
+        let bodies = bodies
+            .iter()
+            .map(|(at, colliders)| BodyState {
+                entity: world.create_entity().build().id(),
+                at: *at,
+                colliders: colliders.clone(),
+            })
+            .collect();
+        world.insert(PhysicsBodies {
+            bodies,
+            restored: false,
+        });
+        world.insert(DebugOverlay { shown: true });
+        world.insert(PhysicsDebug::default());
+
+        PhysicsDebugSystem {}.run_now(&world);
+        let debug = world.fetch::<PhysicsDebug>();
+        assert_eq!(debug.colliders.len(), 6);
+        // the near bullet in both the hitbox and the grazebox
+        assert_eq!(debug.contacts.len(), 2);
+        for contact in debug.contacts.iter() {
+            assert!(contact.depth > 0.);
+        }
     }
 }
@@ -0,0 +1,328 @@
+extern crate specs;
+
+use crate::registry::with_components;
+use crate::types::Position;
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use specs::SystemData;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// Prefabs the game spawns by name, a file without them is rejected.
+pub const REQUIRED: [&str; 3] = ["player", "bullet", "boss"];
+
+macro_rules! prefab_components {
+    ($($field:ident: $component:ty,)*) => {
+        /// Components of a prefab, any of which can be left out. Prefab files
+        /// enable `implicit_some` so they are given without `Some(...)`.
+        #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+        #[serde(default)]
+        pub struct PrefabComponents {
+            $(pub $field: Option<$component>,)*
+        }
+
+        impl PrefabComponents {
+            /// Takes every component `other` has, keeping the others.
+            pub fn merge(&mut self, other: &PrefabComponents) {
+                $(if let Some(component) = &other.$field {
+                    self.$field = Some(component.clone());
+                })*
+            }
+
+            fn build<B: Builder>(self, builder: B) -> B {
+                $(let builder = match self.$field {
+                    Some(component) => builder.with(component),
+                    None => builder,
+                };)*
+                builder
+            }
+        }
+    };
+}
+
+with_components!(prefab_components);
+
+/// An entity spawned along with its prefab.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChildDef {
+    pub prefab: String,
+    /// Where the child goes, from the position of its parent.
+    #[serde(default)]
+    pub offset: (f32, f32),
+    /// Replace the components of the child prefab.
+    #[serde(default)]
+    pub components: PrefabComponents,
+}
+
+/// A named entity template.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Prefab {
+    /// Prefab whose components and children this one starts from.
+    #[serde(default)]
+    pub parent: Option<String>,
+    #[serde(default)]
+    pub components: PrefabComponents,
+    #[serde(default)]
+    pub children: Vec<ChildDef>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PrefabError {
+    Unknown(String),
+    /// The prefab is its own parent or child, through others or not.
+    Cycle(String),
+}
+
+impl fmt::Display for PrefabError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrefabError::Unknown(name) => write!(f, "no prefab named {}", name),
+            PrefabError::Cycle(name) => write!(f, "prefab {} contains itself", name),
+        }
+    }
+}
+
+impl Error for PrefabError {}
+
+/// Every prefab, by name.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Prefabs {
+    pub prefabs: HashMap<String, Prefab>,
+}
+
+impl Prefabs {
+    /// Reads prefabs from ron, checking that each of them can be spawned.
+    pub fn parse(text: &str) -> Result<Prefabs, Box<dyn Error>> {
+        let prefabs: Prefabs = ron::de::from_str(text)?;
+        for name in REQUIRED.iter() {
+            if !prefabs.prefabs.contains_key(*name) {
+                return Err(PrefabError::Unknown(name.to_string()).into());
+            }
+        }
+        let mut names: Vec<&String> = prefabs.prefabs.keys().collect();
+        names.sort();
+        for name in names {
+            prefabs.check(name, &mut Vec::new())?;
+        }
+        Ok(prefabs)
+    }
+
+    /// Makes sure `name` and its children resolve, `within` being the
+    /// prefabs it is a child of.
+    fn check<'a>(&'a self, name: &'a str, within: &mut Vec<&'a str>) -> Result<(), PrefabError> {
+        if within.contains(&name) {
+            return Err(PrefabError::Cycle(name.to_string()));
+        }
+        within.push(name);
+        for child in self.resolve(name)?.1 {
+            self.check(&child.prefab, within)?;
+        }
+        within.pop();
+        Ok(())
+    }
+
+    /// The components of `name`, with those of its parents underneath.
+    pub fn components(&self, name: &str) -> Result<PrefabComponents, PrefabError> {
+        Ok(self.resolve(name)?.0)
+    }
+
+    /// The components and children of `name`, with those of its parents
+    /// underneath.
+    fn resolve<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> Result<(PrefabComponents, Vec<&'a ChildDef>), PrefabError> {
+        let mut chain: Vec<(&str, &Prefab)> = Vec::new();
+        let mut next = Some(name);
+        while let Some(name) = next {
+            if chain.iter().any(|(seen, _)| *seen == name) {
+                return Err(PrefabError::Cycle(name.to_string()));
+            }
+            let prefab = self
+                .prefabs
+                .get(name)
+                .ok_or_else(|| PrefabError::Unknown(name.to_string()))?;
+            chain.push((name, prefab));
+            next = prefab.parent.as_deref();
+        }
+        let mut components = PrefabComponents::default();
+        let mut children = Vec::new();
+        for (_, prefab) in chain.iter().rev() {
+            components.merge(&prefab.components);
+            children.extend(prefab.children.iter());
+        }
+        Ok((components, children))
+    }
+
+    /// Spawns `name` and its children with the builders made by `create`,
+    /// the components in `overrides` replacing those of the prefab.
+    pub fn spawn<B, F>(
+        &self,
+        name: &str,
+        overrides: &PrefabComponents,
+        create: &mut F,
+    ) -> Result<Entity, PrefabError>
+    where
+        B: Builder,
+        F: FnMut() -> B,
+    {
+        let (mut components, children) = self.resolve(name)?;
+        components.merge(overrides);
+        let origin = components.position.clone();
+        let entity = components.build(create()).build();
+        for child in children {
+            let mut overrides = child.components.clone();
+            if let Some(origin) = &origin {
+                overrides.position = Some(Position {
+                    x: origin.x + child.offset.0,
+                    y: origin.y + child.offset.1,
+                });
+            }
+            self.spawn(&child.prefab, &overrides, create)?;
+        }
+        Ok(entity)
+    }
+}
+
+/// Spawns the prefab `name` right away.
+pub fn spawn_prefab(
+    world: &World,
+    name: &str,
+    overrides: &PrefabComponents,
+) -> Result<Entity, PrefabError> {
+    let prefabs = world.fetch::<Prefabs>();
+    prefabs.spawn(name, overrides, &mut || world.create_entity_unchecked())
+}
+
+/// What systems spawn prefabs with. The entities are made right away and
+/// get their components through `LazyUpdate` once the dispatch is over.
+#[derive(SystemData)]
+pub struct PrefabSpawner<'a> {
+    pub entities: Entities<'a>,
+    pub lazy: Read<'a, LazyUpdate>,
+    pub prefabs: Read<'a, Prefabs>,
+}
+
+impl<'a> PrefabSpawner<'a> {
+    pub fn spawn_prefab(
+        &self,
+        name: &str,
+        overrides: &PrefabComponents,
+    ) -> Result<Entity, PrefabError> {
+        let (entities, lazy) = (&self.entities, &self.lazy);
+        self.prefabs
+            .spawn(name, overrides, &mut || lazy.create_entity(entities))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game;
+    use crate::types::Velocity;
+
+    /// Prefab file with the required prefabs and those in `more`.
+    fn parse(more: &str) -> Result<Prefabs, Box<dyn Error>> {
+        Prefabs::parse(&format!(
+            "#![enable(implicit_some)]
+            (prefabs: {{
+                \"player\": (), \"bullet\": (), \"boss\": (),
+                {}
+            }})",
+            more
+        ))
+    }
+
+    fn error(more: &str) -> PrefabError {
+        let err = parse(more).unwrap_err();
+        err.downcast_ref::<PrefabError>()
+            .expect("a prefab error")
+            .clone()
+    }
+
+    #[test]
+    fn children_override_their_parents() {
+        let prefabs = parse(
+            "\"base\": (components: (velocity: (x: 1.0, y: 1.0), enemy: (health: 10.0))),
+            \"middle\": (parent: \"base\", components: (velocity: (x: 2.0, y: 2.0))),
+            \"top\": (parent: \"middle\", components: (enemy: (health: 30.0)))",
+        )
+        .unwrap();
+        let top = prefabs.components("top").unwrap();
+        assert_eq!(top.velocity.map(|v| (v.x, v.y)), Some((2., 2.)));
+        assert_eq!(top.enemy.map(|e| e.health), Some(30.));
+        let middle = prefabs.components("middle").unwrap();
+        assert_eq!(middle.enemy.map(|e| e.health), Some(10.));
+    }
+
+    #[test]
+    fn missing_prefabs_are_unknown() {
+        assert_eq!(
+            error("\"orphan\": (parent: \"nobody\")"),
+            PrefabError::Unknown("nobody".to_string())
+        );
+        assert_eq!(
+            error("\"spawner\": (children: [(prefab: \"nobody\")])"),
+            PrefabError::Unknown("nobody".to_string())
+        );
+        let err = Prefabs::parse("(prefabs: { \"bullet\": (), \"boss\": () })").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<PrefabError>(),
+            Some(&PrefabError::Unknown("player".to_string()))
+        );
+        let prefabs = parse("").unwrap();
+        assert_eq!(
+            prefabs.components("nobody").unwrap_err(),
+            PrefabError::Unknown("nobody".to_string())
+        );
+    }
+
+    #[test]
+    fn prefabs_cannot_contain_themselves() {
+        assert!(matches!(
+            error("\"a\": (parent: \"b\"), \"b\": (parent: \"a\")"),
+            PrefabError::Cycle(_)
+        ));
+        assert!(matches!(
+            error("\"a\": (children: [(prefab: \"b\")]), \"b\": (children: [(prefab: \"a\")])"),
+            PrefabError::Cycle(_)
+        ));
+        assert!(matches!(
+            error("\"a\": (parent: \"b\"), \"b\": (children: [(prefab: \"a\")])"),
+            PrefabError::Cycle(_)
+        ));
+    }
+
+    #[test]
+    fn children_spawn_at_their_offset() {
+        let prefabs = parse(
+            "\"wing\": (components: (velocity: (x: 0.0, y: 1.0))),
+            \"ship\": (children: [
+                (prefab: \"wing\", offset: (-10.0, 5.0)),
+                (prefab: \"wing\", offset: (10.0, 5.0), components: (velocity: (x: 0.0, y: 2.0))),
+            ])",
+        )
+        .unwrap();
+        let mut world = game::create_world();
+        world.insert(prefabs);
+        let at = PrefabComponents {
+            position: Some(Position { x: 100., y: 50. }),
+            ..Default::default()
+        };
+        let ship = spawn_prefab(&world, "ship", &at).unwrap();
+        world.maintain();
+
+        let entities = world.entities();
+        let positions = world.read_storage::<Position>();
+        let velocities = world.read_storage::<Velocity>();
+        let mut spawned: Vec<(f32, f32, Option<f32>)> = (&entities, &positions)
+            .join()
+            .filter(|(entity, _)| *entity != ship)
+            .map(|(entity, pos)| (pos.x, pos.y, velocities.get(entity).map(|v| v.y)))
+            .collect();
+        spawned.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(spawned, vec![(90., 55., Some(1.)), (110., 55., Some(2.))]);
+        assert_eq!(positions.get(ship).map(|p| (p.x, p.y)), Some((100., 50.)));
+    }
+}
@@ -0,0 +1,405 @@
+extern crate specs;
+
+use crate::bomb::BombConfig;
+use crate::graze::GrazeConfig;
+use crate::item::ItemConfig;
+use crate::score::{Score, ScoreConfig};
+use crate::shot::ShotConfig;
+use crate::sim::{Checksum, Rng};
+use crate::stage::Stage;
+use crate::types::{GameState, InputState, PlayField, PlayerInputs};
+use serde::de::DeserializeOwned;
+use serde::ser::{self, Impossible, SerializeStruct, Serializer};
+use serde::Serialize;
+use specs::prelude::*;
+use std::error::Error;
+use std::marker::PhantomData;
+
+/// Calls `$m` with every component of the game as `name: Type`. A component
+/// added here is registered with the world, saved in snapshots, usable in
+/// prefabs and known to the `Registry` by its name.
+macro_rules! with_components {
+    ($m:ident) => {
+        $m! {
+            position: crate::types::Position,
+            velocity: crate::types::Velocity,
+            mouse_tracker: crate::game::MouseTracker,
+            sprite: crate::render::Sprite,
+            player: crate::graze::Player,
+            colliders: crate::phy::Colliders,
+            bullet: crate::bullet::Bullet,
+            emitter: crate::bullet::Emitter,
+            enemy: crate::enemy::Enemy,
+            item: crate::item::Item,
+            drops: crate::item::Drops,
+            boss: crate::boss::Boss,
+            shot: crate::shot::Shot,
+        }
+    };
+}
+
+pub(crate) use with_components;
+
+/// The fields of a struct as ron, by name.
+pub type Fields = Vec<(String, String)>;
+
+/// A component type, known by name, read and written as ron.
+pub trait ComponentType: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn register(&self, world: &mut World);
+    fn has(&self, world: &World, entity: Entity) -> bool;
+    /// The component of `entity`, if it has one.
+    fn save(&self, world: &World, entity: Entity) -> Result<Option<String>, Box<dyn Error>>;
+    /// The fields of the component of `entity` as ron, by name, if it has
+    /// one and it is a struct.
+    fn save_fields(&self, world: &World, entity: Entity) -> Result<Option<Fields>, Box<dyn Error>>;
+    /// Gives `entity` the component in `ron`, replacing the one it had.
+    fn load(&self, world: &World, entity: Entity, ron: &str) -> Result<(), Box<dyn Error>>;
+    fn remove(&self, world: &World, entity: Entity);
+}
+
+struct Registered<T> {
+    name: &'static str,
+    component: PhantomData<T>,
+}
+
+impl<T> ComponentType for Registered<T>
+where
+    T: Component + Clone + Serialize + DeserializeOwned + Send + Sync,
+    T::Storage: Default,
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn register(&self, world: &mut World) {
+        world.register::<T>();
+    }
+
+    fn has(&self, world: &World, entity: Entity) -> bool {
+        world.read_storage::<T>().contains(entity)
+    }
+
+    fn save(&self, world: &World, entity: Entity) -> Result<Option<String>, Box<dyn Error>> {
+        match world.read_storage::<T>().get(entity) {
+            Some(component) => Ok(Some(ron::ser::to_string(component)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_fields(&self, world: &World, entity: Entity) -> Result<Option<Fields>, Box<dyn Error>> {
+        Ok(world.read_storage::<T>().get(entity).and_then(fields))
+    }
+
+    fn load(&self, world: &World, entity: Entity, ron: &str) -> Result<(), Box<dyn Error>> {
+        let component: T = ron::de::from_str(ron)?;
+        world.write_storage::<T>().insert(entity, component)?;
+        Ok(())
+    }
+
+    fn remove(&self, world: &World, entity: Entity) {
+        world.write_storage::<T>().remove(entity);
+    }
+}
+
+/// A resource type, known by name, read and written as ron.
+pub trait ResourceType: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// The resource, if the world has it.
+    fn save(&self, world: &World) -> Result<Option<String>, Box<dyn Error>>;
+    /// The fields of the resource as ron, by name, if the world has it and
+    /// it is a struct.
+    fn save_fields(&self, world: &World) -> Result<Option<Fields>, Box<dyn Error>>;
+    /// Replaces the resource with the one in `ron`.
+    fn load(&self, world: &mut World, ron: &str) -> Result<(), Box<dyn Error>>;
+}
+
+struct RegisteredResource<T> {
+    name: &'static str,
+    resource: PhantomData<T>,
+}
+
+impl<T> ResourceType for RegisteredResource<T>
+where
+    T: Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn save(&self, world: &World) -> Result<Option<String>, Box<dyn Error>> {
+        match world.try_fetch::<T>() {
+            Some(resource) => Ok(Some(ron::ser::to_string(&*resource)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_fields(&self, world: &World) -> Result<Option<Fields>, Box<dyn Error>> {
+        Ok(world
+            .try_fetch::<T>()
+            .and_then(|resource| fields(&*resource)))
+    }
+
+    fn load(&self, world: &mut World, ron: &str) -> Result<(), Box<dyn Error>> {
+        let resource: T = ron::de::from_str(ron)?;
+        world.insert(resource);
+        Ok(())
+    }
+}
+
+/// The fields of `value` as ron, by name, if it is a struct with some.
+/// Each field is written on its own, so no ron has to be taken apart.
+fn fields<T: Serialize>(value: &T) -> Option<Fields> {
+    value
+        .serialize(FieldSerializer)
+        .ok()
+        .filter(|fields| !fields.is_empty())
+}
+
+/// Serializes a struct into its fields as ron, turning anything else down.
+struct FieldSerializer;
+
+struct FieldList(Fields);
+
+macro_rules! not_a_struct {
+    ($($method:ident($($arg:ty),*) -> $ok:ty;)*) => {
+        $(fn $method(self, $(_: $arg),*) -> Result<$ok, ron::Error> {
+            Err(ser::Error::custom("not a struct"))
+        })*
+    };
+}
+
+impl Serializer for FieldSerializer {
+    type Ok = Fields;
+    type Error = ron::Error;
+    type SerializeSeq = Impossible<Self::Ok, ron::Error>;
+    type SerializeTuple = Impossible<Self::Ok, ron::Error>;
+    type SerializeTupleStruct = Impossible<Self::Ok, ron::Error>;
+    type SerializeTupleVariant = Impossible<Self::Ok, ron::Error>;
+    type SerializeMap = Impossible<Self::Ok, ron::Error>;
+    type SerializeStruct = FieldList;
+    type SerializeStructVariant = Impossible<Self::Ok, ron::Error>;
+
+    not_a_struct! {
+        serialize_bool(bool) -> Self::Ok;
+        serialize_i8(i8) -> Self::Ok;
+        serialize_i16(i16) -> Self::Ok;
+        serialize_i32(i32) -> Self::Ok;
+        serialize_i64(i64) -> Self::Ok;
+        serialize_u8(u8) -> Self::Ok;
+        serialize_u16(u16) -> Self::Ok;
+        serialize_u32(u32) -> Self::Ok;
+        serialize_u64(u64) -> Self::Ok;
+        serialize_f32(f32) -> Self::Ok;
+        serialize_f64(f64) -> Self::Ok;
+        serialize_char(char) -> Self::Ok;
+        serialize_str(&str) -> Self::Ok;
+        serialize_bytes(&[u8]) -> Self::Ok;
+        serialize_none() -> Self::Ok;
+        serialize_unit() -> Self::Ok;
+        serialize_unit_struct(&'static str) -> Self::Ok;
+        serialize_unit_variant(&'static str, u32, &'static str) -> Self::Ok;
+        serialize_seq(Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant;
+        serialize_map(Option<usize>) -> Self::SerializeMap;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant;
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, _: &T) -> Result<Self::Ok, ron::Error> {
+        Err(ser::Error::custom("not a struct"))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Self::Ok, ron::Error> {
+        Err(ser::Error::custom("not a struct"))
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Self::Ok, ron::Error> {
+        Err(ser::Error::custom("not a struct"))
+    }
+
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<FieldList, ron::Error> {
+        Ok(FieldList(Vec::with_capacity(len)))
+    }
+}
+
+impl SerializeStruct for FieldList {
+    type Ok = Fields;
+    type Error = ron::Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ron::Error> {
+        self.0.push((key.to_string(), ron::ser::to_string(&value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, ron::Error> {
+        Ok(self.0)
+    }
+}
+
+/// Every component and gameplay resource type, by name, for code that
+/// handles them without knowing their types: tools, saves and the like.
+pub struct Registry {
+    components: Vec<Box<dyn ComponentType>>,
+    resources: Vec<Box<dyn ResourceType>>,
+}
+
+macro_rules! registered_components {
+    ($($name:ident: $component:ty,)*) => {
+        vec![$(Box::new(Registered::<$component> {
+            name: stringify!($name),
+            component: PhantomData,
+        }) as Box<dyn ComponentType>,)*]
+    };
+}
+
+fn resource<T>(name: &'static str) -> Box<dyn ResourceType>
+where
+    T: Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    Box::new(RegisteredResource::<T> {
+        name,
+        resource: PhantomData,
+    })
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry {
+            components: with_components!(registered_components),
+            resources: vec![
+                resource::<GameState>("game_state"),
+                resource::<InputState>("input"),
+                resource::<PlayerInputs>("player_inputs"),
+                resource::<Score>("score"),
+                resource::<Stage>("stage"),
+                resource::<Rng>("rng"),
+                resource::<Checksum>("checksum"),
+                resource::<PlayField>("play_field"),
+                resource::<GrazeConfig>("graze_config"),
+                resource::<BombConfig>("bomb_config"),
+                resource::<ShotConfig>("shot_config"),
+                resource::<ItemConfig>("item_config"),
+                resource::<ScoreConfig>("score_config"),
+            ],
+        }
+    }
+}
+
+impl Registry {
+    pub fn components(&self) -> impl Iterator<Item = &dyn ComponentType> {
+        self.components.iter().map(|component| component.as_ref())
+    }
+
+    pub fn resources(&self) -> impl Iterator<Item = &dyn ResourceType> {
+        self.resources.iter().map(|resource| resource.as_ref())
+    }
+
+    pub fn component(&self, name: &str) -> Option<&dyn ComponentType> {
+        self.components().find(|component| component.name() == name)
+    }
+
+    pub fn resource(&self, name: &str) -> Option<&dyn ResourceType> {
+        self.resources().find(|resource| resource.name() == name)
+    }
+
+    /// Registers every component with `world`.
+    pub fn register(&self, world: &mut World) {
+        for component in self.components() {
+            component.register(world);
+        }
+    }
+
+    /// The components of `entity` by name.
+    pub fn save_entity(
+        &self,
+        world: &World,
+        entity: Entity,
+    ) -> Result<Vec<(&'static str, String)>, Box<dyn Error>> {
+        let mut saved = Vec::new();
+        for component in self.components() {
+            if let Some(ron) = component.save(world, entity)? {
+                saved.push((component.name(), ron));
+            }
+        }
+        Ok(saved)
+    }
+
+    /// Gives `entity` the components saved by `save_entity`.
+    pub fn load_entity(
+        &self,
+        world: &World,
+        entity: Entity,
+        saved: &[(String, String)],
+    ) -> Result<(), Box<dyn Error>> {
+        for (name, ron) in saved {
+            let component = self
+                .component(name)
+                .ok_or_else(|| format!("no component named {}", name))?;
+            component
+                .load(world, entity, ron)
+                .map_err(|err| format!("{}: {}", name, err))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phy::{Colliders, Sensor};
+    use serde::Deserialize;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Label {
+        text: String,
+        separator: char,
+    }
+
+    #[test]
+    fn fields_keep_commas_and_brackets() {
+        let label = Label {
+            text: "a, (b".to_string(),
+            separator: ',',
+        };
+        let fields = fields(&label).unwrap();
+        assert_eq!(
+            fields,
+            vec![
+                ("text".to_string(), "\"a, (b\"".to_string()),
+                ("separator".to_string(), "','".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn fields_keep_enums() {
+        let colliders = Colliders::new(Sensor::Hitbox, 2.).with(Sensor::Grazebox, 8.);
+        let fields = fields(&colliders).unwrap();
+        assert_eq!(fields[0].0, "sensors");
+        let back: Colliders = ron::de::from_str(&format!("(sensors:{})", fields[0].1)).unwrap();
+        assert_eq!(back.sensors[1].kind, Sensor::Grazebox);
+    }
+
+    #[test]
+    fn only_structs_have_fields() {
+        assert_eq!(fields(&3), None);
+        assert_eq!(fields(&vec![1, 2]), None);
+        assert_eq!(fields(&Sensor::Bullet), None);
+    }
+}
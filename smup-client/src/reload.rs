@@ -0,0 +1,269 @@
+extern crate specs;
+
+use crate::assets::{Asset, Assets, Atlas, Handle, ImageData};
+use crate::boss::{Boss, BossDef};
+use crate::bullet::Emitter;
+use crate::render::SpriteAtlas;
+use specs::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Time between two looks at the modification times of the files.
+const POLL_PERIOD: Duration = Duration::from_millis(500);
+
+/// Assets that could not be loaded, shown over the game until fixed.
+#[derive(Debug, Default)]
+pub struct ReloadErrors {
+    pub lines: Vec<String>,
+}
+
+/// Puts a reloaded asset where the game uses it.
+trait Watch {
+    fn paths(&self) -> Vec<&Path>;
+    fn apply(&mut self, world: &mut World);
+}
+
+/// A handle and the version of its asset last put to use.
+struct Watched<T> {
+    handle: Handle<T>,
+    version: u32,
+}
+
+impl<T: Asset> Watched<T> {
+    /// Watches `handle` from the version it is at, once loaded.
+    fn new(handle: Handle<T>) -> Watched<T> {
+        let _ = handle.wait();
+        let version = handle.version();
+        Watched { handle, version }
+    }
+
+    /// The asset, if it was loaded again since the last call.
+    fn reloaded(&mut self) -> Option<Arc<T>> {
+        let version = self.handle.version();
+        if version == self.version {
+            return None;
+        }
+        self.version = version;
+        let asset = self.handle.get()?;
+        println!("reloaded {}", self.handle.path().display());
+        Some(asset)
+    }
+}
+
+/// An asset used as a resource.
+struct ResourceWatch<T>(Watched<T>);
+
+impl<T: Asset + Clone> Watch for ResourceWatch<T> {
+    fn paths(&self) -> Vec<&Path> {
+        vec![self.0.handle.path()]
+    }
+
+    fn apply(&mut self, world: &mut World) {
+        if let Some(asset) = self.0.reloaded() {
+            world.insert((*asset).clone());
+        }
+    }
+}
+
+/// A boss definition, given to the bosses already in play.
+struct BossWatch(Watched<BossDef>);
+
+impl Watch for BossWatch {
+    fn paths(&self) -> Vec<&Path> {
+        vec![self.0.handle.path()]
+    }
+
+    fn apply(&mut self, world: &mut World) {
+        if let Some(def) = self.0.reloaded() {
+            update_bosses(world, &def);
+        }
+    }
+}
+
+/// The sprite atlas and the image it names, which may change with it.
+struct AtlasWatch {
+    atlas: Watched<Atlas>,
+    image: Option<Watched<ImageData>>,
+}
+
+impl Watch for AtlasWatch {
+    fn paths(&self) -> Vec<&Path> {
+        let image = self.image.iter().map(|image| image.handle.path());
+        std::iter::once(self.atlas.handle.path())
+            .chain(image)
+            .collect()
+    }
+
+    fn apply(&mut self, world: &mut World) {
+        if let Some(atlas) = self.atlas.reloaded() {
+            let handle = world
+                .write_resource::<Assets>()
+                .load::<ImageData>(&atlas.image);
+            // picked up below once loaded, even if it is the one in use
+            self.image = Some(Watched { handle, version: 0 });
+            world.write_resource::<SpriteAtlas>().atlas = Some(atlas);
+        }
+        if let Some(image) = self.image.as_mut().and_then(Watched::reloaded) {
+            world.write_resource::<SpriteAtlas>().image = Some(image);
+        }
+    }
+}
+
+/// Gives the bosses made from `def` its new phases, keeping the phase they
+/// are in and starting its patterns over.
+fn update_bosses(world: &mut World, def: &BossDef) {
+    let mut bosses = world.write_storage::<Boss>();
+    let mut emitters = world.write_storage::<Emitter>();
+    for (boss, emitter) in (&mut bosses, &mut emitters).join() {
+        if boss.name != def.name {
+            continue;
+        }
+        boss.phases = def.phases.clone();
+        boss.phase = boss.phase.min(boss.phases.len() - 1);
+        if boss.waypoint >= boss.current().path.len() {
+            boss.waypoint = 0;
+        }
+        emitter.patterns = boss.current().patterns.clone();
+    }
+}
+
+/// Development mode where changed asset files are loaded again and swapped
+/// into the running world. There is no file system watcher: every
+/// `POLL_PERIOD` the modification times of the watched files, and of those
+/// only, are compared with the ones they were loaded at. Files that no
+/// longer load keep their last good version and are listed in
+/// `ReloadErrors`.
+pub struct PollingReload {
+    watches: Vec<Box<dyn Watch>>,
+    polled: Instant,
+}
+
+impl Default for PollingReload {
+    fn default() -> PollingReload {
+        PollingReload {
+            watches: Vec::new(),
+            polled: Instant::now(),
+        }
+    }
+}
+
+impl PollingReload {
+    /// Replaces the `T` resource with the asset at `path` whenever it is
+    /// reloaded.
+    pub fn resource<T: Asset + Clone>(&mut self, assets: &mut Assets, path: impl AsRef<Path>) {
+        let handle = assets.load::<T>(path);
+        self.watches
+            .push(Box::new(ResourceWatch(Watched::new(handle))));
+    }
+
+    /// Updates the bosses in play whenever `handle` is reloaded.
+    pub fn boss(&mut self, handle: Handle<BossDef>) {
+        self.watches.push(Box::new(BossWatch(Watched::new(handle))));
+    }
+
+    /// Updates the `SpriteAtlas` whenever the atlas at `path` or its image
+    /// is reloaded.
+    pub fn atlas(&mut self, assets: &mut Assets, path: impl AsRef<Path>) {
+        let atlas = Watched::new(assets.load::<Atlas>(path));
+        let image = atlas
+            .handle
+            .get()
+            .map(|atlas| Watched::new(assets.load::<ImageData>(&atlas.image)));
+        self.watches.push(Box::new(AtlasWatch { atlas, image }));
+    }
+
+    /// Reloads the watched files changed since the last poll, when it is
+    /// time for one, and applies the reloads that finished.
+    pub fn poll(&mut self, world: &mut World) {
+        if self.polled.elapsed() >= POLL_PERIOD {
+            self.polled = Instant::now();
+            let paths: Vec<PathBuf> = self
+                .watches
+                .iter()
+                .flat_map(|watch| watch.paths())
+                .map(Path::to_path_buf)
+                .collect();
+            world.write_resource::<Assets>().reload_changed(&paths);
+        }
+        for watch in self.watches.iter_mut() {
+            watch.apply(world);
+        }
+        let mut lines: Vec<String> = world
+            .fetch::<Assets>()
+            .errors()
+            .iter()
+            .map(|err| err.to_string())
+            .collect();
+        lines.sort();
+        world.insert(ReloadErrors { lines });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::thread;
+
+    /// Polls until `done` or a few seconds went by.
+    fn poll_until(reload: &mut PollingReload, world: &mut World, done: impl Fn(&World) -> bool) {
+        for _ in 0..100 {
+            reload.poll(world);
+            if done(world) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("nothing was reloaded");
+    }
+
+    #[test]
+    fn atlas_follows_its_image() {
+        let dir = std::env::temp_dir().join(format!("smup-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (atlas, first, second) = (
+            dir.join("atlas.ron"),
+            dir.join("first.png"),
+            dir.join("second.png"),
+        );
+        fs::copy("assets/sprites.png", &first).unwrap();
+        fs::copy("assets/sprites.png", &second).unwrap();
+        let write_atlas = |image: &Path, region: &str| {
+            let ron = format!(
+                "(image: {:?}, regions: {{ {:?}: (x: 0, y: 0, w: 8, h: 8) }})",
+                image, region
+            );
+            fs::write(&atlas, ron).unwrap();
+        };
+        write_atlas(&first, "ship");
+
+        let mut world = World::new();
+        let mut assets = Assets::default();
+        world.insert(SpriteAtlas::load(&mut assets, &atlas));
+        let mut reload = PollingReload::default();
+        reload.atlas(&mut assets, &atlas);
+        world.insert(assets);
+        let shown = world.fetch::<SpriteAtlas>().image.clone().unwrap();
+        assert!(world.fetch::<SpriteAtlas>().region("ship").is_some());
+
+        // past the modification time the files were loaded at
+        thread::sleep(Duration::from_millis(1100));
+        write_atlas(&second, "wing");
+        poll_until(&mut reload, &mut world, |world| {
+            let sprites = world.fetch::<SpriteAtlas>();
+            let image = sprites.image.as_ref().unwrap();
+            sprites.region("wing").is_some() && !Arc::ptr_eq(image, &shown)
+        });
+        assert!(world.fetch::<SpriteAtlas>().region("ship").is_none());
+
+        let shown = world.fetch::<SpriteAtlas>().image.clone().unwrap();
+        thread::sleep(Duration::from_millis(1100));
+        fs::copy("assets/sprites.png", &second).unwrap();
+        poll_until(&mut reload, &mut world, |world| {
+            let image = world.fetch::<SpriteAtlas>().image.clone().unwrap();
+            !Arc::ptr_eq(&image, &shown)
+        });
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
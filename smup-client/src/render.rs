@@ -1,8 +1,15 @@
 extern crate specs;
 
+use crate::assets::{Assets, Atlas, ImageData, Region};
+use crate::types::{GameState, Position};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
 use specs::{Component, VecStorage};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Color {
     pub r: f32,
     pub g: f32,
@@ -27,13 +34,13 @@ impl Color {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Size {
     pub w: f32,
     pub h: f32,
 }
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Point {
     pub x: f32,
     pub y: f32,
@@ -41,10 +48,102 @@ pub struct Point {
 
 pub type Pivot = Point;
 
-#[derive(Component, Debug, Default)]
+#[derive(Clone, Component, Debug, Default, Serialize, Deserialize)]
 #[storage(VecStorage)]
 pub struct Sprite {
     pub color: Color,
     pub size: Size,
     pub pivot: Pivot,
+    /// Part of the `SpriteAtlas` drawn, tinted by `color`, instead of a
+    /// rectangle.
+    #[serde(default)]
+    pub region: Option<String>,
+}
+
+/// The atlas sprites with a `region` are drawn from, and its image. They
+/// are drawn as rectangles while either is missing.
+#[derive(Clone, Debug, Default)]
+pub struct SpriteAtlas {
+    pub atlas: Option<Arc<Atlas>>,
+    pub image: Option<Arc<ImageData>>,
+}
+
+impl SpriteAtlas {
+    /// Loads the atlas at `path` and the image it names, the loader
+    /// reporting what is missing.
+    pub fn load(assets: &mut Assets, path: impl AsRef<Path>) -> SpriteAtlas {
+        let atlas = assets.load::<Atlas>(path).wait().ok();
+        let image = atlas
+            .as_ref()
+            .and_then(|atlas| assets.load::<ImageData>(&atlas.image).wait().ok());
+        SpriteAtlas { atlas, image }
+    }
+
+    /// Where `name` is in the image.
+    pub fn region(&self, name: &str) -> Option<Region> {
+        self.atlas.as_ref()?.regions.get(name).copied()
+    }
+}
+
+/// Whether colliders, velocities and entity ids are drawn over the game.
+#[derive(Debug, Default)]
+pub struct DebugOverlay {
+    pub shown: bool,
+}
+
+/// Where entities were at the end of the tick before the last one, so
+/// frames drawn between two ticks can place them in between.
+#[derive(Debug, Default)]
+pub struct Interpolation {
+    run: u32,
+    tick: u32,
+    previous: HashMap<Entity, Position>,
+    current: HashMap<Entity, Position>,
+    /// The share of a step gone since the last tick, set by the frontend
+    /// from its update loop before each render.
+    pub alpha: f32,
+}
+
+impl Interpolation {
+    /// Where to draw `entity`, at `pos` after the last tick: `alpha` of
+    /// the way from where it was before.
+    pub fn position(&self, entity: Entity, pos: &Position) -> (f32, f32) {
+        let previous = match self.previous.get(&entity) {
+            Some(previous) => previous,
+            None => return (pos.x, pos.y),
+        };
+        let t = self.alpha.min(1.);
+        (
+            previous.x + (pos.x - previous.x) * t,
+            previous.y + (pos.y - previous.y) * t,
+        )
+    }
+}
+
+/// Keeps `Interpolation` up with the ticks. Entities are only moved in
+/// between ticks that follow each other, anything else is drawn where it
+/// is.
+pub struct InterpolationSystem {}
+impl<'a> System<'a> for InterpolationSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        Read<'a, GameState>,
+        Write<'a, Interpolation>,
+    );
+
+    fn run(&mut self, (entities, positions, gs, mut interpolation): Self::SystemData) {
+        if (gs.run, gs.tick) == (interpolation.run, interpolation.tick) {
+            return;
+        }
+        let next = gs.run == interpolation.run && gs.tick == interpolation.tick + 1;
+        let now = (&entities, &positions)
+            .join()
+            .map(|(entity, pos)| (entity, pos.clone()))
+            .collect();
+        let last = std::mem::replace(&mut interpolation.current, now);
+        interpolation.previous = if next { last } else { HashMap::new() };
+        interpolation.run = gs.run;
+        interpolation.tick = gs.tick;
+    }
 }
@@ -0,0 +1,443 @@
+extern crate specs;
+
+use crate::boss::BossDef;
+use crate::game;
+use crate::scene::{Scene, SceneControl, Transition};
+use crate::sim::{self, STEP};
+use crate::snapshot::Snapshot;
+use crate::stage::Stage;
+use crate::types::{Difficulty, GameState, InputFrame, InputState, PlayerInputs, Position};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"SMRP";
+const FORMAT: u8 = 1;
+/// Bytes of one input change: tick, buttons, x and y.
+const CHANGE_SIZE: usize = 13;
+
+/// A recorded run: what it started from and the input of every tick, kept
+/// as the ticks where the input changed.
+#[derive(Clone, Debug)]
+pub struct Replay {
+    /// Version of the game that recorded it.
+    pub version: String,
+    pub seed: u64,
+    pub stage: u32,
+    pub difficulty: Difficulty,
+    pub ticks: u32,
+    pub changes: Vec<(u32, InputFrame)>,
+}
+
+impl Replay {
+    /// Where replays are saved, inside the user data directory.
+    pub fn dir() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("smup").join("replays"))
+    }
+
+    /// Saved replays, newest first.
+    pub fn list() -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = Replay::dir()
+            .and_then(|dir| fs::read_dir(dir).ok())
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("rpy"))
+                    .collect()
+            })
+            .unwrap_or_default();
+        paths.sort();
+        paths.reverse();
+        paths
+    }
+
+    /// Saves the replay in the replay directory, named after the time.
+    pub fn save(&self) -> Result<PathBuf, Box<dyn Error>> {
+        let dir = Replay::dir().ok_or("no data directory for replays")?;
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.rpy", Local::now().format("%Y%m%d-%H%M%S")));
+        self.write(&path)?;
+        Ok(path)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, self.encode())?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Replay, Box<dyn Error>> {
+        Replay::decode(&fs::read(path)?)
+    }
+
+    /// Input of `tick`, the one of the last change at or before it.
+    pub fn input_at(&self, tick: u32) -> Option<InputFrame> {
+        let next = self.changes.partition_point(|(at, _)| *at <= tick);
+        next.checked_sub(1).map(|i| self.changes[i].1)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + self.changes.len() * CHANGE_SIZE);
+        out.extend_from_slice(MAGIC);
+        out.push(FORMAT);
+        out.push(self.version.len() as u8);
+        out.extend_from_slice(self.version.as_bytes());
+        out.extend_from_slice(&self.seed.to_le_bytes());
+        out.extend_from_slice(&self.stage.to_le_bytes());
+        out.push(self.difficulty as u8);
+        out.extend_from_slice(&self.ticks.to_le_bytes());
+        out.extend_from_slice(&(self.changes.len() as u32).to_le_bytes());
+        for (tick, frame) in self.changes.iter() {
+            out.extend_from_slice(&tick.to_le_bytes());
+            out.push(frame.buttons);
+            out.extend_from_slice(&frame.x.to_le_bytes());
+            out.extend_from_slice(&frame.y.to_le_bytes());
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Replay, Box<dyn Error>> {
+        let mut r = Reader { bytes };
+        if r.take(4)? != MAGIC {
+            return Err("not a replay file".into());
+        }
+        let format = r.u8()?;
+        if format != FORMAT {
+            return Err(format!("unsupported replay format {}", format).into());
+        }
+        let len = r.u8()? as usize;
+        let version = String::from_utf8(r.take(len)?.to_vec())?;
+        let seed = u64::from_le_bytes(r.take(8)?.try_into()?);
+        let stage = r.u32()?;
+        let difficulty = match r.u8()? {
+            0 => Difficulty::Easy,
+            1 => Difficulty::Normal,
+            2 => Difficulty::Hard,
+            3 => Difficulty::Lunatic,
+            other => return Err(format!("unknown difficulty {}", other).into()),
+        };
+        let ticks = r.u32()?;
+        let count = r.u32()? as usize;
+        if r.bytes.len() / CHANGE_SIZE < count {
+            return Err("truncated replay file".into());
+        }
+        let mut changes = Vec::with_capacity(count);
+        for _ in 0..count {
+            let tick = r.u32()?;
+            let buttons = r.u8()?;
+            let x = f32::from_le_bytes(r.take(4)?.try_into()?);
+            let y = f32::from_le_bytes(r.take(4)?.try_into()?);
+            changes.push((tick, InputFrame { buttons, x, y }));
+        }
+        Ok(Replay {
+            version,
+            seed,
+            stage,
+            difficulty,
+            ticks,
+            changes,
+        })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if self.bytes.len() < n {
+            return Err("truncated replay file".into());
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+}
+
+/// Input of the run being played, filled by `RecorderSystem`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Recording {
+    pub ticks: u32,
+    pub changes: Vec<(u32, InputFrame)>,
+}
+
+impl Recording {
+    pub fn finish(&self, seed: u64, stage: u32, difficulty: Difficulty) -> Replay {
+        Replay {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            seed,
+            stage,
+            difficulty,
+            ticks: self.ticks,
+            changes: self.changes.clone(),
+        }
+    }
+}
+
+/// Records the input every tick is played with.
+pub struct RecorderSystem {}
+impl<'a> System<'a> for RecorderSystem {
+    type SystemData = (
+        Write<'a, Recording>,
+        Read<'a, GameState>,
+        Read<'a, PlayerInputs>,
+    );
+
+    fn run(&mut self, (mut recording, gs, inputs): Self::SystemData) {
+        let frame = inputs.get(0);
+        if recording.changes.last().map(|(_, last)| *last) != Some(frame) {
+            recording.changes.push((gs.tick, frame));
+        }
+        recording.ticks = gs.tick + 1;
+    }
+}
+
+/// Replay picked in the replay browser.
+#[derive(Debug, Default)]
+pub struct ReplaySelection {
+    pub path: Option<PathBuf>,
+}
+
+/// Playback controls, set from the player input and read by `Playback`.
+#[derive(Debug)]
+pub struct ReplayControl {
+    pub paused: bool,
+    /// Ticks played per update.
+    pub speed: u32,
+    /// Ticks to jump by, backwards when negative.
+    pub seek: Option<i64>,
+    pub tick: u32,
+    pub ticks: u32,
+}
+
+impl Default for ReplayControl {
+    fn default() -> ReplayControl {
+        ReplayControl {
+            paused: false,
+            speed: 1,
+            seek: None,
+            tick: 0,
+            ticks: 0,
+        }
+    }
+}
+
+/// Confirm pauses, up and down change the speed, left and right jump ten
+/// seconds and cancel leaves.
+#[derive(Default)]
+pub struct ReplayControlSystem {
+    last: InputState,
+}
+
+impl ReplayControlSystem {
+    const MAX_SPEED: u32 = 8;
+    const SEEK_TICKS: i64 = 300;
+}
+
+impl<'a> System<'a> for ReplayControlSystem {
+    type SystemData = (
+        Write<'a, ReplayControl>,
+        Write<'a, SceneControl>,
+        Read<'a, InputState>,
+    );
+
+    fn run(&mut self, (mut ctl, mut scene, input): Self::SystemData) {
+        let last = std::mem::replace(&mut self.last, input.clone());
+        if scene.is_changing() {
+            return;
+        }
+        if input.confirm && !last.confirm {
+            ctl.paused = !ctl.paused;
+        }
+        if input.up && !last.up {
+            ctl.speed = (ctl.speed * 2).min(Self::MAX_SPEED);
+        }
+        if input.down && !last.down {
+            ctl.speed = (ctl.speed / 2).max(1);
+        }
+        if input.right && !last.right {
+            ctl.seek = Some(Self::SEEK_TICKS);
+        }
+        if input.left && !last.left {
+            ctl.seek = Some(-Self::SEEK_TICKS);
+        }
+        if input.cancel && !last.cancel {
+            scene.fade_to(Transition::Reset(Scene::Title));
+        }
+    }
+}
+
+/// Plays a replay back through the gameplay systems, feeding the recorded
+/// input in place of the player's.
+pub struct Playback {
+    pub replay: Replay,
+    boss: BossDef,
+    dispatcher: Dispatcher<'static, 'static>,
+    /// Difficulty picked in the options, put back when the playback ends.
+    difficulty: Difficulty,
+    /// Saved every `KEYFRAME_TICKS` ticks, so seeking back does not have to
+    /// play the run again from the start.
+    keyframes: Vec<Snapshot>,
+}
+
+const KEYFRAME_TICKS: u32 = 300;
+
+impl Playback {
+    pub fn new(world: &mut World, replay: Replay, boss: &BossDef) -> Playback {
+        let mut dispatcher = game::gameplay(DispatcherBuilder::new()).build();
+        dispatcher.setup(world);
+        let difficulty = world.fetch::<GameState>().difficulty;
+        world.insert(ReplayControl {
+            ticks: replay.ticks,
+            ..Default::default()
+        });
+        let mut playback = Playback {
+            replay,
+            boss: boss.clone(),
+            dispatcher,
+            difficulty,
+            keyframes: vec![],
+        };
+        playback.restart(world);
+        playback
+    }
+
+    fn restart(&mut self, world: &mut World) {
+        world.write_resource::<Stage>().number = self.replay.stage;
+        world.write_resource::<GameState>().difficulty = self.replay.difficulty;
+        game::start_run(world, &self.boss, self.replay.seed, 1);
+    }
+
+    /// Plays the next tick, returns false once the replay is over.
+    pub fn tick(&mut self, world: &mut World) -> bool {
+        let tick = current_tick(world);
+        if tick >= self.replay.ticks {
+            return false;
+        }
+        if tick == self.keyframes.len() as u32 * KEYFRAME_TICKS {
+            self.keyframes.push(Snapshot::take(world));
+        }
+        // the recorded input only lives for the tick, the live one is kept
+        // for the playback controls
+        let live = (*world.fetch::<InputState>()).clone();
+        let (x, y) = {
+            let mut gs = world.write_resource::<GameState>();
+            let mut input = world.write_resource::<InputState>();
+            let live_at = (gs.mouse_position.x, gs.mouse_position.y);
+            if let Some(frame) = self.replay.input_at(tick) {
+                frame.apply(&mut input, &mut gs.mouse_position);
+            }
+            gs.paused = false;
+            gs.advance(STEP);
+            live_at
+        };
+        self.dispatcher.dispatch(world);
+        sim::maintain(world);
+        *world.write_resource::<InputState>() = live;
+        world.write_resource::<GameState>().mouse_position = Position { x, y };
+        true
+    }
+
+    /// Moves to `tick`, going back to the last keyframe before it first when
+    /// going back.
+    pub fn seek(&mut self, world: &mut World, tick: u32) {
+        if tick < current_tick(world) {
+            match self.keyframes.get((tick / KEYFRAME_TICKS) as usize) {
+                Some(keyframe) => {
+                    // the playback controls keep the live input
+                    let live = (*world.fetch::<InputState>()).clone();
+                    keyframe.clone().restore(world);
+                    world.insert(live);
+                }
+                None => self.restart(world),
+            }
+        }
+        while current_tick(world) < tick && self.tick(world) {}
+    }
+
+    /// Applies the playback controls, to be called once per frame.
+    pub fn update(&mut self, world: &mut World) {
+        let (seek, paused, speed) = {
+            let mut ctl = world.write_resource::<ReplayControl>();
+            (ctl.seek.take(), ctl.paused, ctl.speed)
+        };
+        let tick = current_tick(world);
+        if let Some(offset) = seek {
+            self.seek(world, (tick as i64 + offset).max(0) as u32);
+        } else if !paused && world.fetch::<GameState>().real_delta > 0. {
+            for _ in 0..speed {
+                if !self.tick(world) {
+                    break;
+                }
+            }
+        }
+        world.write_resource::<ReplayControl>().tick = current_tick(world);
+    }
+
+    /// Puts back the settings the replay overrode.
+    pub fn close(self, world: &mut World) {
+        world.write_resource::<GameState>().difficulty = self.difficulty;
+    }
+}
+
+fn current_tick(world: &World) -> u32 {
+    world.fetch::<GameState>().tick
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay() -> Replay {
+        let frame = |buttons, x, y| InputFrame { buttons, x, y };
+        Replay {
+            version: "0.1.0".to_string(),
+            seed: 0x0123_4567_89ab_cdef,
+            stage: 2,
+            difficulty: Difficulty::Hard,
+            ticks: 600,
+            changes: vec![(0, frame(0, 320., 400.)), (12, frame(3, 100.5, -2.25))],
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let replay = replay();
+        let decoded = Replay::decode(&replay.encode()).unwrap();
+        assert_eq!(decoded.version, replay.version);
+        assert_eq!(decoded.seed, replay.seed);
+        assert_eq!(decoded.stage, replay.stage);
+        assert_eq!(decoded.difficulty, replay.difficulty);
+        assert_eq!(decoded.ticks, replay.ticks);
+        assert_eq!(decoded.changes, replay.changes);
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let bytes = replay().encode();
+        for len in 0..bytes.len() {
+            assert!(Replay::decode(&bytes[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn change_count_is_checked_before_allocating() {
+        let mut bytes = replay().encode();
+        let count = bytes.len() - 2 * CHANGE_SIZE - 4;
+        bytes[count..count + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = Replay::decode(&bytes).unwrap_err();
+        assert_eq!(err.to_string(), "truncated replay file");
+    }
+}
@@ -0,0 +1,260 @@
+extern crate specs;
+
+use crate::graze::Player;
+use crate::menu::Menu;
+use crate::score::Score;
+use crate::sim;
+use crate::stage::Stage;
+use crate::types::{GameState, InputState};
+use specs::prelude::*;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Scene {
+    /// Waits for the assets loading in the background.
+    Loading,
+    #[default]
+    Title,
+    Options,
+    StageSelect,
+    Playing,
+    Paused,
+    GameOver,
+    Credits,
+    /// Browser of the recorded replays.
+    Replays,
+    /// Playback of a replay.
+    Replay,
+    /// Two player run over the network.
+    Netplay,
+    /// Run played on a server.
+    Online,
+}
+
+impl Scene {
+    /// Whether the play field is drawn below the scene.
+    pub fn shows_world(self) -> bool {
+        matches!(
+            self,
+            Scene::Playing
+                | Scene::Paused
+                | Scene::GameOver
+                | Scene::Replay
+                | Scene::Netplay
+                | Scene::Online
+        )
+    }
+
+    /// Whether game time moves while the scene is on top.
+    pub fn runs_game_time(self) -> bool {
+        matches!(self, Scene::Playing | Scene::Netplay | Scene::Online)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transition {
+    Push(Scene),
+    Pop,
+    Replace(Scene),
+    /// Drops the whole stack and starts over from the given scene.
+    Reset(Scene),
+    Quit,
+}
+
+/// Lets systems ask for a scene change. The change is applied by
+/// `SceneStack` once the current frame is done.
+#[derive(Debug, Default)]
+pub struct SceneControl {
+    /// Scene on top of the stack.
+    pub current: Scene,
+    /// Opacity of the fade overlay, zero when no fade is running.
+    pub fade: f32,
+    pending: Option<Transition>,
+    fading_out: bool,
+}
+
+impl SceneControl {
+    /// Changes scene right away. Ignored while another change is pending.
+    pub fn go(&mut self, transition: Transition) {
+        if self.pending.is_none() {
+            self.pending = Some(transition);
+        }
+    }
+
+    /// Fades out, changes scene and fades back in.
+    pub fn fade_to(&mut self, transition: Transition) {
+        if self.pending.is_none() {
+            self.pending = Some(transition);
+            self.fading_out = true;
+        }
+    }
+
+    pub fn is_changing(&self) -> bool {
+        self.pending.is_some()
+    }
+}
+
+/// The scenes in play, each running its own dispatcher every frame and
+/// optionally another one on every update tick of game time. Only the scene
+/// on top of the stack is dispatched.
+pub struct SceneStack {
+    stack: Vec<Scene>,
+    dispatchers: HashMap<Scene, Dispatcher<'static, 'static>>,
+    ticks: HashMap<Scene, Dispatcher<'static, 'static>>,
+    /// Seconds for a fade to go fully dark and back.
+    fade_time: f32,
+}
+
+impl SceneStack {
+    pub fn new(first: Scene, fade_time: f32) -> SceneStack {
+        SceneStack {
+            stack: vec![first],
+            dispatchers: HashMap::new(),
+            ticks: HashMap::new(),
+            fade_time,
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        world: &mut World,
+        scene: Scene,
+        mut dispatcher: Dispatcher<'static, 'static>,
+    ) {
+        dispatcher.setup(world);
+        self.dispatchers.insert(scene, dispatcher);
+    }
+
+    /// Sets the dispatcher run on the update ticks of `scene`, before its
+    /// frame dispatcher.
+    pub fn add_tick(
+        &mut self,
+        world: &mut World,
+        scene: Scene,
+        mut dispatcher: Dispatcher<'static, 'static>,
+    ) {
+        dispatcher.setup(world);
+        self.ticks.insert(scene, dispatcher);
+    }
+
+    pub fn current(&self) -> Option<Scene> {
+        self.stack.last().cloned()
+    }
+
+    /// Runs one frame of the current scene and applies the scene change it
+    /// asked for, if any. Returns the scene that was entered by a push,
+    /// replace or reset so the caller can prepare it; scenes uncovered by a
+    /// pop carry on where they were.
+    pub fn run(&mut self, world: &mut World) -> Option<Scene> {
+        let current = self.current()?;
+        world.write_resource::<SceneControl>().current = current;
+        world.write_resource::<GameState>().paused = !current.runs_game_time();
+        if world.fetch::<GameState>().delta > 0. {
+            if let Some(dispatcher) = self.ticks.get_mut(&current) {
+                dispatcher.dispatch(world);
+                sim::maintain(world);
+            }
+        }
+        if let Some(dispatcher) = self.dispatchers.get_mut(&current) {
+            dispatcher.dispatch(world);
+        }
+        world.maintain();
+
+        let delta = world.fetch::<GameState>().real_delta as f32;
+        let step = if self.fade_time > 0. {
+            delta / (self.fade_time / 2.)
+        } else {
+            1.
+        };
+        let mut ctl = world.write_resource::<SceneControl>();
+        if ctl.fading_out {
+            ctl.fade = (ctl.fade + step).min(1.);
+            if ctl.fade < 1. {
+                return None;
+            }
+            ctl.fading_out = false;
+        } else {
+            ctl.fade = (ctl.fade - step).max(0.);
+        }
+        let transition = ctl.pending.take()?;
+        let entered = self.apply(transition);
+        if let Some(scene) = self.current() {
+            ctl.current = scene;
+        }
+        entered
+    }
+
+    fn apply(&mut self, transition: Transition) -> Option<Scene> {
+        match transition {
+            Transition::Push(scene) => self.stack.push(scene),
+            Transition::Pop => {
+                self.stack.pop();
+                return None;
+            }
+            Transition::Replace(scene) => {
+                self.stack.pop();
+                self.stack.push(scene);
+            }
+            Transition::Reset(scene) => {
+                self.stack.clear();
+                self.stack.push(scene);
+            }
+            Transition::Quit => {
+                self.stack.clear();
+                return None;
+            }
+        }
+        self.current()
+    }
+}
+
+/// Watches the run while playing: pauses, and ends the run when the last
+/// life is lost or the stage is cleared.
+#[derive(Default)]
+pub struct PlayFlowSystem {
+    paused: bool,
+    /// Seconds the stage has been cleared for.
+    cleared: f64,
+}
+
+impl PlayFlowSystem {
+    /// Seconds the stage bonus stays up before the credits roll.
+    const CLEAR_DELAY: f64 = 3.;
+}
+
+impl<'a> System<'a> for PlayFlowSystem {
+    type SystemData = (
+        Write<'a, SceneControl>,
+        Read<'a, InputState>,
+        Read<'a, GameState>,
+        Read<'a, Stage>,
+        Read<'a, Score>,
+        ReadStorage<'a, Player>,
+        Write<'a, Menu>,
+    );
+
+    fn run(&mut self, (mut ctl, input, gs, stage, score, players, mut menu): Self::SystemData) {
+        let pause = input.pause && !self.paused;
+        self.paused = input.pause;
+
+        let dying = players.join().any(|p| p.dying.is_some());
+        if gs.lives == 0 && !dying {
+            ctl.fade_to(Transition::Replace(Scene::GameOver));
+            return;
+        }
+        if let (true, Some(bonus)) = (stage.cleared, &score.stage_bonus) {
+            if menu.title.is_empty() {
+                *menu = Menu::stage_clear(bonus);
+            }
+            self.cleared += gs.real_delta;
+            if self.cleared >= Self::CLEAR_DELAY {
+                self.cleared = 0.;
+                ctl.fade_to(Transition::Replace(Scene::Credits));
+            }
+            return;
+        }
+        if pause {
+            ctl.go(Transition::Push(Scene::Paused));
+        }
+    }
+}
@@ -0,0 +1,371 @@
+extern crate specs;
+
+use crate::stage::Stage;
+use crate::types::GameState;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ScoreKind {
+    Graze,
+    EnemyKill,
+    Cancel,
+    PointItem,
+    /// A boss phase cleared without dying or bombing.
+    Capture,
+}
+
+/// Something worth points that happened during the last update.
+#[derive(Clone, Copy, Debug)]
+pub struct ScoreEvent {
+    pub kind: ScoreKind,
+    /// Points on top of the base value from the score table, like the value
+    /// of a point item.
+    pub points: u64,
+}
+
+impl ScoreEvent {
+    pub fn new(kind: ScoreKind) -> ScoreEvent {
+        ScoreEvent { kind, points: 0 }
+    }
+
+    pub fn with_points(kind: ScoreKind, points: u64) -> ScoreEvent {
+        ScoreEvent { kind, points }
+    }
+}
+
+/// Score events waiting to be applied by `ScoreSystem`.
+#[derive(Debug, Default)]
+pub struct ScoreEvents {
+    pub events: Vec<ScoreEvent>,
+    /// Boss phases captured during the last update.
+    pub captures: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ScoreRule {
+    pub base: u64,
+    /// Whether the event keeps the chain going.
+    pub chains: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StageBonusTable {
+    pub clear: u64,
+    pub per_life: u64,
+    pub per_bomb: u64,
+    pub per_graze: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScoreConfig {
+    /// Seconds a chain survives without a chaining event.
+    pub chain_window: f64,
+    /// Multiplier gained by every link of the chain.
+    pub chain_step: f32,
+    pub max_multiplier: f32,
+    pub events: HashMap<ScoreKind, ScoreRule>,
+    pub stage_bonus: StageBonusTable,
+    /// Entries kept in the hi-score table.
+    pub hiscore_entries: usize,
+}
+
+impl Default for ScoreConfig {
+    fn default() -> ScoreConfig {
+        ScoreConfig {
+            chain_window: 2.,
+            chain_step: 0.1,
+            max_multiplier: 4.,
+            events: HashMap::new(),
+            stage_bonus: StageBonusTable {
+                clear: 0,
+                per_life: 0,
+                per_bomb: 0,
+                per_graze: 0,
+            },
+            hiscore_entries: 10,
+        }
+    }
+}
+
+/// Line by line bonus awarded when a stage is cleared.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StageBonus {
+    pub lines: Vec<(String, u64)>,
+    /// Boss phases captured during the stage.
+    #[serde(default)]
+    pub captures: Vec<String>,
+}
+
+impl StageBonus {
+    pub fn total(&self) -> u64 {
+        self.lines.iter().map(|(_, points)| points).sum()
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Score {
+    pub total: u64,
+    pub chain: u32,
+    /// Seconds left before the chain breaks.
+    pub chain_timer: f64,
+    pub multiplier: f32,
+    /// Grazes during the current stage, used for the stage bonus.
+    pub stage_graze: u32,
+    #[serde(default)]
+    pub stage_captures: Vec<String>,
+    pub stage_bonus: Option<StageBonus>,
+}
+
+impl Score {
+    fn apply(&mut self, cfg: &ScoreConfig, ev: &ScoreEvent) {
+        let rule = cfg.events.get(&ev.kind).cloned().unwrap_or(ScoreRule {
+            base: 0,
+            chains: false,
+        });
+        if rule.chains {
+            self.chain += 1;
+            self.chain_timer = cfg.chain_window;
+        }
+        if ev.kind == ScoreKind::Graze {
+            self.stage_graze += 1;
+        }
+        self.multiplier = self.current_multiplier(cfg);
+        self.total += ((rule.base + ev.points) as f64 * self.multiplier as f64) as u64;
+    }
+
+    fn current_multiplier(&self, cfg: &ScoreConfig) -> f32 {
+        (1. + self.chain as f32 * cfg.chain_step).min(cfg.max_multiplier)
+    }
+
+    fn finish_stage(&mut self, cfg: &ScoreConfig, gs: &GameState) -> StageBonus {
+        let table = &cfg.stage_bonus;
+        let bonus = StageBonus {
+            lines: vec![
+                ("Clear".to_string(), table.clear),
+                ("Lives".to_string(), table.per_life * gs.lives as u64),
+                ("Bombs".to_string(), table.per_bomb * gs.bombs as u64),
+                (
+                    "Graze".to_string(),
+                    table.per_graze * self.stage_graze as u64,
+                ),
+            ],
+            captures: std::mem::take(&mut self.stage_captures),
+        };
+        self.total += bonus.total();
+        self.stage_graze = 0;
+        bonus
+    }
+}
+
+/// Applies score events and keeps the chain timer running.
+pub struct ScoreSystem {}
+impl<'a> System<'a> for ScoreSystem {
+    type SystemData = (
+        Write<'a, Score>,
+        Write<'a, ScoreEvents>,
+        Read<'a, ScoreConfig>,
+        Read<'a, GameState>,
+        Read<'a, Stage>,
+    );
+
+    fn run(&mut self, (mut score, mut events, cfg, gs, stage): Self::SystemData) {
+        if score.chain > 0 {
+            score.chain_timer -= gs.delta;
+            if score.chain_timer <= 0. {
+                score.chain = 0;
+                score.chain_timer = 0.;
+            }
+        }
+        for ev in events.events.drain(..) {
+            score.apply(&cfg, &ev);
+        }
+        score.stage_captures.append(&mut events.captures);
+        score.multiplier = score.current_multiplier(&cfg);
+
+        if stage.cleared && score.stage_bonus.is_none() {
+            score.stage_bonus = Some(score.finish_stage(&cfg, &gs));
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HiScore {
+    pub name: String,
+    pub score: u64,
+    pub stage: u32,
+    pub date: String,
+}
+
+impl HiScore {
+    pub fn new(name: &str, score: u64, stage: u32) -> HiScore {
+        HiScore {
+            name: name.to_string(),
+            score,
+            stage,
+            date: Local::now().format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+
+/// Best runs on this machine, highest score first.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HiScores {
+    pub entries: Vec<HiScore>,
+}
+
+impl HiScores {
+    /// Where the table is stored, inside the user data directory.
+    pub fn path() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("smup").join("hiscores.ron"))
+    }
+
+    /// Loads the table, starting a new one if there is none yet.
+    pub fn load() -> Result<HiScores, Box<dyn Error>> {
+        match HiScores::path() {
+            Some(path) if path.exists() => Ok(ron::de::from_str(&fs::read_to_string(path)?)?),
+            _ => Ok(HiScores::default()),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = HiScores::path().ok_or("no data directory for hi-scores")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Adds `entry` if it makes the top `max` and returns its rank.
+    pub fn insert(&mut self, entry: HiScore, max: usize) -> Option<usize> {
+        let rank = self
+            .entries
+            .iter()
+            .position(|e| e.score < entry.score)
+            .unwrap_or(self.entries.len());
+        if rank >= max {
+            return None;
+        }
+        self.entries.insert(rank, entry);
+        self.entries.truncate(max);
+        Some(rank)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ScoreConfig {
+        let mut cfg = ScoreConfig::default();
+        cfg.events.insert(
+            ScoreKind::EnemyKill,
+            ScoreRule {
+                base: 100,
+                chains: true,
+            },
+        );
+        cfg.events.insert(
+            ScoreKind::PointItem,
+            ScoreRule {
+                base: 10,
+                chains: false,
+            },
+        );
+        cfg
+    }
+
+    fn scores(values: &[u64]) -> HiScores {
+        HiScores {
+            entries: values.iter().map(|&v| HiScore::new("A", v, 1)).collect(),
+        }
+    }
+
+    fn values(table: &HiScores) -> Vec<u64> {
+        table.entries.iter().map(|e| e.score).collect()
+    }
+
+    #[test]
+    fn insert_keeps_highest_first() {
+        let mut table = scores(&[500, 300, 100]);
+        assert_eq!(table.insert(HiScore::new("B", 400, 1), 5), Some(1));
+        assert_eq!(table.insert(HiScore::new("C", 50, 1), 5), Some(4));
+        assert_eq!(values(&table), vec![500, 400, 300, 100, 50]);
+    }
+
+    #[test]
+    fn insert_ties_rank_below_older_entries() {
+        let mut table = scores(&[500, 300]);
+        assert_eq!(table.insert(HiScore::new("B", 300, 1), 5), Some(2));
+        assert_eq!(table.entries[1].name, "A");
+    }
+
+    #[test]
+    fn insert_truncates_to_max() {
+        let mut table = scores(&[500, 300, 100]);
+        assert_eq!(table.insert(HiScore::new("B", 400, 1), 3), Some(1));
+        assert_eq!(values(&table), vec![500, 400, 300]);
+        assert_eq!(table.insert(HiScore::new("C", 200, 1), 3), None);
+        assert_eq!(values(&table), vec![500, 400, 300]);
+    }
+
+    #[test]
+    fn chain_raises_multiplier_up_to_max() {
+        let mut cfg = config();
+        cfg.max_multiplier = 1.25;
+        let mut score = Score::default();
+        let kill = ScoreEvent::new(ScoreKind::EnemyKill);
+        score.apply(&cfg, &kill);
+        assert_eq!(score.chain, 1);
+        assert!((score.multiplier - 1.1).abs() < 1e-6);
+        assert_eq!(score.total, 110);
+        score.apply(&cfg, &kill);
+        assert!((score.multiplier - 1.2).abs() < 1e-6);
+        assert_eq!(score.total, 110 + 120);
+        score.apply(&cfg, &kill);
+        assert_eq!(score.chain, 3);
+        assert!((score.multiplier - 1.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn non_chaining_events_use_the_current_multiplier() {
+        let cfg = config();
+        let mut score = Score::default();
+        score.apply(&cfg, &ScoreEvent::new(ScoreKind::EnemyKill));
+        score.apply(&cfg, &ScoreEvent::with_points(ScoreKind::PointItem, 40));
+        assert_eq!(score.chain, 1);
+        assert_eq!(score.total, 110 + 55);
+    }
+
+    #[test]
+    fn chain_breaks_when_the_window_runs_out() {
+        let mut world = World::new();
+        world.insert(config());
+        world.insert(Stage::default());
+        world.insert(GameState {
+            delta: 1.5,
+            ..GameState::default()
+        });
+        world.insert(ScoreEvents {
+            events: vec![ScoreEvent::new(ScoreKind::EnemyKill)],
+            ..ScoreEvents::default()
+        });
+        world.insert(Score::default());
+        let mut system = ScoreSystem {};
+        system.run_now(&world);
+        assert_eq!(world.fetch::<Score>().chain, 1);
+        system.run_now(&world);
+        assert_eq!(world.fetch::<Score>().chain, 1);
+        system.run_now(&world);
+        let score = world.fetch::<Score>();
+        assert_eq!(score.chain, 0);
+        assert!((score.multiplier - 1.).abs() < 1e-6);
+    }
+}
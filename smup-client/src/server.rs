@@ -0,0 +1,788 @@
+extern crate specs;
+
+use crate::assets::Assets;
+use crate::boss::BossDef;
+use crate::game;
+use crate::headless::Outcome;
+use crate::net::{write_frame, Reader};
+use crate::render::Sprite;
+use crate::score::Score;
+use crate::sim::{self, STEP};
+use crate::stage::Stage;
+use crate::types::{GameState, InputFrame, PlayerInputs, Position};
+use specs::prelude::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8; 4] = b"SMSV";
+const JOIN: u8 = 0;
+const WELCOME: u8 = 1;
+const INPUT: u8 = 2;
+const STATE: u8 = 3;
+const LEAVE: u8 = 4;
+const NONE: u32 = u32::MAX;
+const NO_SLOT: u8 = u8::MAX;
+const HAS_POSITION: u8 = 1;
+const HAS_SPRITE: u8 = 1 << 1;
+
+/// Ticks of states kept by both sides to encode changes against.
+pub const HISTORY: usize = 64;
+/// Time without hearing from the other side before giving up on it.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest UDP datagram. States that do not fit are not sent, the next
+/// ones are encoded against an older state.
+const MAX_DATAGRAM: usize = 65507;
+
+/// How an entity looks, as clients draw it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Look {
+    pub color: [u8; 4],
+    pub w: f32,
+    pub h: f32,
+    pub pivot_x: f32,
+    pub pivot_y: f32,
+}
+
+impl Look {
+    pub fn of(sprite: &Sprite) -> Look {
+        let channel = |c: f32| (c.clamp(0., 1.) * 255.).round() as u8;
+        Look {
+            color: [
+                channel(sprite.color.r),
+                channel(sprite.color.g),
+                channel(sprite.color.b),
+                channel(sprite.color.a),
+            ],
+            w: sprite.size.w,
+            h: sprite.size.h,
+            pivot_x: sprite.pivot.x,
+            pivot_y: sprite.pivot.y,
+        }
+    }
+
+    pub fn sprite(&self) -> Sprite {
+        let mut sprite = Sprite::default();
+        let [r, g, b, a] = self.color;
+        sprite.color.r = r as f32 / 255.;
+        sprite.color.g = g as f32 / 255.;
+        sprite.color.b = b as f32 / 255.;
+        sprite.color.a = a as f32 / 255.;
+        sprite.size.w = self.w;
+        sprite.size.h = self.h;
+        sprite.pivot.x = self.pivot_x;
+        sprite.pivot.y = self.pivot_y;
+        sprite
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EntityState {
+    pub x: f32,
+    pub y: f32,
+    pub look: Look,
+}
+
+/// What clients see of a run at the end of a tick.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WorldState {
+    pub tick: u32,
+    pub score: u64,
+    pub lives: u32,
+    pub over: bool,
+    /// Drawn entities by id and generation, so an id handed out again
+    /// counts as a new entity.
+    pub entities: BTreeMap<u64, EntityState>,
+}
+
+impl WorldState {
+    pub fn of(world: &World) -> WorldState {
+        let gs = world.fetch::<GameState>();
+        let entities = world.entities();
+        let positions = world.read_storage::<Position>();
+        let sprites = world.read_storage::<Sprite>();
+        WorldState {
+            tick: gs.tick,
+            score: world.fetch::<Score>().total,
+            lives: gs.lives,
+            over: gs.lives == 0 || world.fetch::<Stage>().cleared,
+            entities: (&entities, &positions, &sprites)
+                .join()
+                .map(|(entity, pos, sprite)| {
+                    let key = (u64::from(entity.gen().id() as u32) << 32) | u64::from(entity.id());
+                    let state = EntityState {
+                        x: pos.x,
+                        y: pos.y,
+                        look: Look::of(sprite),
+                    };
+                    (key, state)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Entity that appeared or changed: its new position and look, when they
+/// changed.
+pub type Change = (u64, Option<(f32, f32)>, Option<Look>);
+
+/// A state as the changes from an older one the client has, or whole when
+/// there is none.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateDelta {
+    pub tick: u32,
+    pub base: Option<u32>,
+    pub score: u64,
+    pub lives: u32,
+    pub over: bool,
+    pub removed: Vec<u64>,
+    pub changed: Vec<Change>,
+}
+
+impl StateDelta {
+    pub fn between(base: Option<&WorldState>, state: &WorldState) -> StateDelta {
+        let empty = BTreeMap::new();
+        let old = base.map(|base| &base.entities).unwrap_or(&empty);
+        let removed = old
+            .keys()
+            .filter(|key| !state.entities.contains_key(key))
+            .cloned()
+            .collect();
+        let changed = state
+            .entities
+            .iter()
+            .filter_map(|(key, now)| {
+                let before = old.get(key);
+                let position = match before {
+                    Some(before) if (before.x, before.y) == (now.x, now.y) => None,
+                    _ => Some((now.x, now.y)),
+                };
+                let look = match before {
+                    Some(before) if before.look == now.look => None,
+                    _ => Some(now.look),
+                };
+                if position.is_none() && look.is_none() {
+                    None
+                } else {
+                    Some((*key, position, look))
+                }
+            })
+            .collect();
+        StateDelta {
+            tick: state.tick,
+            base: base.map(|base| base.tick),
+            score: state.score,
+            lives: state.lives,
+            over: state.over,
+            removed,
+            changed,
+        }
+    }
+
+    /// Rebuilds the state from the one it was encoded against.
+    pub fn apply(self, base: Option<&WorldState>) -> Result<WorldState, Box<dyn Error>> {
+        let mut entities = base.map(|base| base.entities.clone()).unwrap_or_default();
+        for key in self.removed.iter() {
+            entities.remove(key);
+        }
+        for (key, position, look) in self.changed {
+            let state = match (entities.get(&key), position, look) {
+                (Some(old), position, look) => {
+                    let (x, y) = position.unwrap_or((old.x, old.y));
+                    EntityState {
+                        x,
+                        y,
+                        look: look.unwrap_or(old.look),
+                    }
+                }
+                (None, Some((x, y)), Some(look)) => EntityState { x, y, look },
+                (None, _, _) => return Err("change to an entity never seen".into()),
+            };
+            entities.insert(key, state);
+        }
+        Ok(WorldState {
+            tick: self.tick,
+            score: self.score,
+            lives: self.lives,
+            over: self.over,
+            entities,
+        })
+    }
+}
+
+/// Packets from clients.
+#[derive(Debug)]
+pub enum ToServer {
+    Join,
+    Input {
+        /// Counts up with every input sent, older ones arriving late are
+        /// dropped.
+        seq: u32,
+        /// Latest state received, to encode the next ones against.
+        ack: Option<u32>,
+        frame: InputFrame,
+    },
+    Leave,
+}
+
+/// Packets from the server.
+#[derive(Debug)]
+pub enum ToClient {
+    Welcome {
+        /// Player slot, spectators have none.
+        slot: Option<usize>,
+        seed: u64,
+    },
+    State(StateDelta),
+}
+
+fn header(kind: u8) -> Vec<u8> {
+    let mut out = Vec::with_capacity(64);
+    out.extend_from_slice(MAGIC);
+    out.push(kind);
+    out
+}
+
+fn open(bytes: &[u8]) -> Result<(u8, Reader<'_>), Box<dyn Error>> {
+    let mut r = Reader { bytes };
+    if r.take(4)? != MAGIC {
+        return Err("not a server packet".into());
+    }
+    Ok((r.u8()?, r))
+}
+
+impl ToServer {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            ToServer::Join => header(JOIN),
+            ToServer::Input { seq, ack, frame } => {
+                let mut out = header(INPUT);
+                out.extend_from_slice(&seq.to_le_bytes());
+                out.extend_from_slice(&ack.unwrap_or(NONE).to_le_bytes());
+                write_frame(&mut out, frame);
+                out
+            }
+            ToServer::Leave => header(LEAVE),
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<ToServer, Box<dyn Error>> {
+        let (kind, mut r) = open(bytes)?;
+        Ok(match kind {
+            JOIN => ToServer::Join,
+            INPUT => {
+                let seq = r.u32()?;
+                let ack = r.u32()?;
+                ToServer::Input {
+                    seq,
+                    ack: if ack == NONE { None } else { Some(ack) },
+                    frame: r.frame()?,
+                }
+            }
+            LEAVE => ToServer::Leave,
+            other => return Err(format!("unknown packet kind {}", other).into()),
+        })
+    }
+}
+
+impl ToClient {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            ToClient::Welcome { slot, seed } => {
+                let mut out = header(WELCOME);
+                out.push(slot.map(|slot| slot as u8).unwrap_or(NO_SLOT));
+                out.extend_from_slice(&seed.to_le_bytes());
+                out
+            }
+            ToClient::State(delta) => {
+                let mut out = header(STATE);
+                out.extend_from_slice(&delta.tick.to_le_bytes());
+                out.extend_from_slice(&delta.base.unwrap_or(NONE).to_le_bytes());
+                out.extend_from_slice(&delta.score.to_le_bytes());
+                out.extend_from_slice(&delta.lives.to_le_bytes());
+                out.push(delta.over as u8);
+                out.extend_from_slice(&(delta.removed.len() as u32).to_le_bytes());
+                for key in delta.removed.iter() {
+                    out.extend_from_slice(&key.to_le_bytes());
+                }
+                out.extend_from_slice(&(delta.changed.len() as u32).to_le_bytes());
+                for (key, position, look) in delta.changed.iter() {
+                    out.extend_from_slice(&key.to_le_bytes());
+                    let mut flags = 0;
+                    if position.is_some() {
+                        flags |= HAS_POSITION;
+                    }
+                    if look.is_some() {
+                        flags |= HAS_SPRITE;
+                    }
+                    out.push(flags);
+                    if let Some((x, y)) = position {
+                        out.extend_from_slice(&x.to_le_bytes());
+                        out.extend_from_slice(&y.to_le_bytes());
+                    }
+                    if let Some(look) = look {
+                        out.extend_from_slice(&look.color);
+                        for value in [look.w, look.h, look.pivot_x, look.pivot_y].iter() {
+                            out.extend_from_slice(&value.to_le_bytes());
+                        }
+                    }
+                }
+                out
+            }
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<ToClient, Box<dyn Error>> {
+        let (kind, mut r) = open(bytes)?;
+        Ok(match kind {
+            WELCOME => {
+                let slot = r.u8()?;
+                ToClient::Welcome {
+                    slot: if slot == NO_SLOT {
+                        None
+                    } else {
+                        Some(slot as usize)
+                    },
+                    seed: r.u64()?,
+                }
+            }
+            STATE => {
+                let tick = r.u32()?;
+                let base = r.u32()?;
+                let score = r.u64()?;
+                let lives = r.u32()?;
+                let over = r.u8()? != 0;
+                let mut removed = vec![];
+                for _ in 0..r.u32()? {
+                    removed.push(r.u64()?);
+                }
+                let mut changed = vec![];
+                for _ in 0..r.u32()? {
+                    let key = r.u64()?;
+                    let flags = r.u8()?;
+                    let position = if flags & HAS_POSITION != 0 {
+                        Some((r.f32()?, r.f32()?))
+                    } else {
+                        None
+                    };
+                    let look = if flags & HAS_SPRITE != 0 {
+                        let mut color = [0; 4];
+                        color.copy_from_slice(r.take(4)?);
+                        Some(Look {
+                            color,
+                            w: r.f32()?,
+                            h: r.f32()?,
+                            pivot_x: r.f32()?,
+                            pivot_y: r.f32()?,
+                        })
+                    } else {
+                        None
+                    };
+                    changed.push((key, position, look));
+                }
+                ToClient::State(StateDelta {
+                    tick,
+                    base: if base == NONE { None } else { Some(base) },
+                    score,
+                    lives,
+                    over,
+                    removed,
+                    changed,
+                })
+            }
+            other => return Err(format!("unknown packet kind {}", other).into()),
+        })
+    }
+}
+
+struct Client {
+    addr: SocketAddr,
+    slot: Option<usize>,
+    ack: Option<u32>,
+    seq: u32,
+    last_heard: Instant,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ServerStats {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    /// States sent whole, with nothing to encode them against.
+    pub full_states: u64,
+    /// States too big for a datagram.
+    pub dropped_states: u64,
+}
+
+/// Plays a run for the clients connected to it. Players send their input
+/// and get the state of the world back after every tick, encoded against
+/// the last state they received. Clients beyond the player slots watch.
+pub struct Server {
+    socket: UdpSocket,
+    world: World,
+    dispatcher: Dispatcher<'static, 'static>,
+    players: usize,
+    seed: u64,
+    clients: Vec<Client>,
+    history: VecDeque<WorldState>,
+    outcome: Option<Outcome>,
+    pub stats: ServerStats,
+}
+
+impl Server {
+    pub fn new(
+        bind: SocketAddr,
+        boss: &BossDef,
+        players: usize,
+        seed: u64,
+    ) -> Result<Server, Box<dyn Error>> {
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_nonblocking(true)?;
+        let mut world = game::create_world();
+        game::insert_resources(&mut world, &mut Assets::default())?;
+        let mut dispatcher = game::simulation(DispatcherBuilder::new()).build();
+        dispatcher.setup(&mut world);
+        game::start_run(&mut world, boss, seed, players);
+        Ok(Server {
+            socket,
+            world,
+            dispatcher,
+            players,
+            seed,
+            clients: vec![],
+            history: VecDeque::new(),
+            outcome: None,
+            stats: ServerStats::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Box<dyn Error>> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn tick(&self) -> u32 {
+        self.world.fetch::<GameState>().tick
+    }
+
+    /// How the run ended, once it is over.
+    pub fn outcome(&self) -> Option<&Outcome> {
+        self.outcome.as_ref()
+    }
+
+    /// Handles what the clients sent and forgets the ones gone quiet.
+    pub fn receive(&mut self) {
+        let mut buf = [0; 2048];
+        while let Ok((len, addr)) = self.socket.recv_from(&mut buf) {
+            if let Ok(packet) = ToServer::decode(&buf[..len]) {
+                self.handle(addr, packet);
+            }
+        }
+        let world = &self.world;
+        self.clients.retain(|client| {
+            if client.last_heard.elapsed() < TIMEOUT {
+                return true;
+            }
+            eprintln!("{} timed out", client.addr);
+            if let Some(slot) = client.slot {
+                world
+                    .write_resource::<PlayerInputs>()
+                    .set(slot, InputFrame::default());
+            }
+            false
+        });
+    }
+
+    fn handle(&mut self, addr: SocketAddr, packet: ToServer) {
+        let known = self.clients.iter().position(|client| client.addr == addr);
+        match (packet, known) {
+            (ToServer::Join, None) => {
+                let slot = (0..self.players)
+                    .find(|slot| !self.clients.iter().any(|c| c.slot == Some(*slot)));
+                match slot {
+                    Some(slot) => eprintln!("{} joined as player {}", addr, slot + 1),
+                    None => eprintln!("{} joined to watch", addr),
+                }
+                self.clients.push(Client {
+                    addr,
+                    slot,
+                    ack: None,
+                    seq: 0,
+                    last_heard: Instant::now(),
+                });
+                self.welcome(self.clients.len() - 1);
+            }
+            // the welcome got lost
+            (ToServer::Join, Some(i)) => self.welcome(i),
+            (ToServer::Input { seq, ack, frame }, Some(i)) => {
+                let client = &mut self.clients[i];
+                client.last_heard = Instant::now();
+                if seq < client.seq {
+                    return;
+                }
+                client.seq = seq;
+                client.ack = ack;
+                if let Some(slot) = client.slot {
+                    self.world.write_resource::<PlayerInputs>().set(slot, frame);
+                }
+            }
+            (ToServer::Leave, Some(i)) => {
+                let client = self.clients.remove(i);
+                eprintln!("{} left", client.addr);
+                if let Some(slot) = client.slot {
+                    self.world
+                        .write_resource::<PlayerInputs>()
+                        .set(slot, InputFrame::default());
+                }
+            }
+            (_, None) => {}
+        }
+    }
+
+    fn welcome(&mut self, i: usize) {
+        let client = &self.clients[i];
+        let packet = ToClient::Welcome {
+            slot: client.slot,
+            seed: self.seed,
+        };
+        let addr = client.addr;
+        self.send(addr, &packet.encode());
+    }
+
+    /// Plays one tick with the latest input of every player, then sends the
+    /// clients the new state. Once the run is over the last state keeps
+    /// being sent.
+    pub fn step(&mut self) {
+        if self.outcome.is_none() {
+            {
+                let mut gs = self.world.write_resource::<GameState>();
+                gs.paused = false;
+                gs.advance(STEP);
+            }
+            self.dispatcher.dispatch(&self.world);
+            sim::maintain(&mut self.world);
+            let state = WorldState::of(&self.world);
+            if state.over {
+                self.outcome = Some(Outcome::of(&self.world));
+            }
+            self.history.push_back(state);
+            while self.history.len() > HISTORY {
+                self.history.pop_front();
+            }
+        }
+        self.broadcast();
+    }
+
+    fn broadcast(&mut self) {
+        let state = match self.history.back() {
+            Some(state) => state,
+            None => return,
+        };
+        let mut packets = vec![];
+        for client in self.clients.iter() {
+            let base = client
+                .ack
+                .and_then(|ack| self.history.iter().find(|state| state.tick == ack));
+            let delta = StateDelta::between(base, state);
+            packets.push((client.addr, ToClient::State(delta).encode(), base.is_none()));
+        }
+        for (addr, packet, full) in packets {
+            if full {
+                self.stats.full_states += 1;
+            }
+            if packet.len() > MAX_DATAGRAM {
+                self.stats.dropped_states += 1;
+                continue;
+            }
+            self.send(addr, &packet);
+        }
+    }
+
+    fn send(&mut self, addr: SocketAddr, packet: &[u8]) {
+        // failed sends are nothing more than lost packets
+        if self.socket.send_to(packet, addr).is_ok() {
+            self.stats.packets_sent += 1;
+            self.stats.bytes_sent += packet.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn look(w: f32) -> Look {
+        Look {
+            color: [255, 128, 0, 255],
+            w,
+            h: 4.,
+            pivot_x: 0.5,
+            pivot_y: 0.5,
+        }
+    }
+
+    fn entity(x: f32, y: f32) -> EntityState {
+        EntityState {
+            x,
+            y,
+            look: look(4.),
+        }
+    }
+
+    fn state(tick: u32, entities: Vec<(u64, EntityState)>) -> WorldState {
+        WorldState {
+            tick,
+            score: u64::from(tick) * 10,
+            lives: 3,
+            over: false,
+            entities: entities.into_iter().collect(),
+        }
+    }
+
+    /// The delta as the client gets it, through the wire format.
+    fn sent(delta: StateDelta) -> StateDelta {
+        match ToClient::decode(&ToClient::State(delta).encode()).unwrap() {
+            ToClient::State(delta) => delta,
+            other => panic!("decoded as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn full_state_roundtrips() {
+        let full = state(5, vec![(1, entity(10., 20.)), (2, entity(30., 40.))]);
+        let delta = sent(StateDelta::between(None, &full));
+        assert_eq!(delta.base, None);
+        assert_eq!(delta.changed.len(), 2);
+        assert_eq!(delta.apply(None).unwrap(), full);
+    }
+
+    #[test]
+    fn delta_holds_only_what_changed() {
+        let base = state(5, vec![(1, entity(10., 20.)), (2, entity(30., 40.))]);
+        let mut moved = entity(11., 20.);
+        moved.look = look(8.);
+        let next = state(
+            6,
+            vec![(1, moved), (2, entity(30., 40.)), (3, entity(0., 0.))],
+        );
+        let delta = sent(StateDelta::between(Some(&base), &next));
+        assert_eq!(delta.base, Some(5));
+        assert!(delta.removed.is_empty());
+        assert_eq!(
+            delta.changed,
+            vec![
+                (1, Some((11., 20.)), Some(look(8.))),
+                (3, Some((0., 0.)), Some(look(4.))),
+            ]
+        );
+        assert_eq!(delta.apply(Some(&base)).unwrap(), next);
+    }
+
+    #[test]
+    fn removed_entities_are_gone() {
+        let base = state(5, vec![(1, entity(10., 20.)), (2, entity(30., 40.))]);
+        let next = state(6, vec![(2, entity(30., 40.))]);
+        let delta = sent(StateDelta::between(Some(&base), &next));
+        assert_eq!(delta.removed, vec![1]);
+        assert!(delta.changed.is_empty());
+        assert_eq!(delta.apply(Some(&base)).unwrap(), next);
+    }
+
+    #[test]
+    fn reused_id_is_a_new_entity() {
+        let mut world = game::create_world();
+        game::insert_resources(&mut world, &mut Assets::default()).unwrap();
+        let spawn = |world: &mut World| {
+            world
+                .create_entity()
+                .with(Position { x: 1., y: 2. })
+                .with(Sprite::default())
+                .build()
+        };
+        let first = spawn(&mut world);
+        let before = WorldState::of(&world);
+        world.delete_entity(first).unwrap();
+        world.maintain();
+        let second = spawn(&mut world);
+        assert_eq!(second.id(), first.id());
+        let after = WorldState::of(&world);
+        assert_eq!(before.entities.len(), 1);
+        assert_ne!(before.entities.keys().next(), after.entities.keys().next());
+
+        // sent whole, as it was never seen, even though it did not move
+        let delta = sent(StateDelta::between(Some(&before), &after));
+        assert_eq!(
+            delta.removed,
+            before.entities.keys().cloned().collect::<Vec<_>>()
+        );
+        assert!(matches!(delta.changed[..], [(_, Some(_), Some(_))]));
+        assert_eq!(delta.apply(Some(&before)).unwrap(), after);
+    }
+
+    #[test]
+    fn change_to_an_unknown_entity_is_an_error() {
+        let base = state(5, vec![(1, entity(10., 20.))]);
+        let next = state(6, vec![(1, entity(11., 20.))]);
+        let delta = StateDelta::between(Some(&base), &next);
+        assert!(delta.apply(None).is_err());
+    }
+
+    #[test]
+    fn client_packets_roundtrip() {
+        let frame = InputFrame {
+            buttons: 5,
+            x: 12.5,
+            y: 40.,
+        };
+        let packet = ToServer::Input {
+            seq: 7,
+            ack: Some(3),
+            frame,
+        };
+        match ToServer::decode(&packet.encode()).unwrap() {
+            ToServer::Input { seq, ack, frame: f } => {
+                assert_eq!((seq, ack, f), (7, Some(3), frame));
+            }
+            other => panic!("decoded as {:?}", other),
+        }
+        let welcome = ToClient::Welcome {
+            slot: None,
+            seed: 99,
+        };
+        match ToClient::decode(&welcome.encode()).unwrap() {
+            ToClient::Welcome { slot, seed } => assert_eq!((slot, seed), (None, 99)),
+            other => panic!("decoded as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated_packets_are_errors() {
+        let base = state(5, vec![(1, entity(10., 20.))]);
+        let next = state(6, vec![(1, entity(11., 20.)), (2, entity(0., 0.))]);
+        let packets = [
+            ToClient::State(StateDelta::between(Some(&base), &next)).encode(),
+            ToClient::Welcome {
+                slot: Some(1),
+                seed: 99,
+            }
+            .encode(),
+        ];
+        for bytes in packets.iter() {
+            for len in 0..bytes.len() {
+                assert!(ToClient::decode(&bytes[..len]).is_err(), "{} bytes", len);
+            }
+        }
+        let input = ToServer::Input {
+            seq: 1,
+            ack: None,
+            frame: InputFrame::default(),
+        }
+        .encode();
+        for len in 0..input.len() {
+            assert!(ToServer::decode(&input[..len]).is_err(), "{} bytes", len);
+        }
+    }
+}
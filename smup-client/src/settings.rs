@@ -0,0 +1,344 @@
+extern crate piston;
+
+use crate::audio::{Channel, Volumes};
+use crate::sim::UPS;
+use crate::types::{Difficulty, InputState};
+use piston::input::Key;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+/// Smallest and largest window sides.
+const MIN_SIZE: u32 = 320;
+const MAX_SIZE: u32 = 7680;
+/// Most updates per second the window loop is allowed.
+const MAX_UPS: u64 = 240;
+/// Longest name on the hi-score table.
+const MAX_NAME: usize = 12;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Video {
+    pub width: u32,
+    pub height: u32,
+    pub fullscreen: bool,
+    pub vsync: bool,
+    /// Updates per second of the window loop, a multiple of `sim::UPS`: the
+    /// game always steps `sim::UPS` times a second.
+    pub ups: u64,
+    /// Most frames drawn per second.
+    pub max_fps: u64,
+}
+
+impl Default for Video {
+    fn default() -> Video {
+        Video {
+            width: 640,
+            height: 480,
+            fullscreen: false,
+            vsync: false,
+            ups: UPS,
+            max_fps: 60,
+        }
+    }
+}
+
+/// Keys of every action, any of them triggering it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub focus: Vec<Key>,
+    pub fire: Vec<Key>,
+    pub bomb: Vec<Key>,
+    pub pause: Vec<Key>,
+    pub up: Vec<Key>,
+    pub down: Vec<Key>,
+    pub left: Vec<Key>,
+    pub right: Vec<Key>,
+    pub confirm: Vec<Key>,
+    pub cancel: Vec<Key>,
+    pub save_state: Vec<Key>,
+    pub load_state: Vec<Key>,
+    /// Shows or hides the debug overlay.
+    pub debug: Vec<Key>,
+    /// Shows or hides the inspector.
+    pub inspect: Vec<Key>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        KeyBindings {
+            focus: vec![Key::LShift, Key::RShift],
+            fire: vec![Key::Z],
+            bomb: vec![Key::X],
+            pause: vec![Key::Escape],
+            up: vec![Key::Up],
+            down: vec![Key::Down],
+            left: vec![Key::Left],
+            right: vec![Key::Right],
+            confirm: vec![Key::Z, Key::Return],
+            cancel: vec![Key::X, Key::Escape],
+            save_state: vec![Key::F5],
+            load_state: vec![Key::F9],
+            debug: vec![Key::F3],
+            inspect: vec![Key::F2],
+        }
+    }
+}
+
+impl KeyBindings {
+    /// The keys of every action, by name.
+    fn actions(&mut self) -> [(&'static str, &mut Vec<Key>); 14] {
+        [
+            ("focus", &mut self.focus),
+            ("fire", &mut self.fire),
+            ("bomb", &mut self.bomb),
+            ("pause", &mut self.pause),
+            ("up", &mut self.up),
+            ("down", &mut self.down),
+            ("left", &mut self.left),
+            ("right", &mut self.right),
+            ("confirm", &mut self.confirm),
+            ("cancel", &mut self.cancel),
+            ("save_state", &mut self.save_state),
+            ("load_state", &mut self.load_state),
+            ("debug", &mut self.debug),
+            ("inspect", &mut self.inspect),
+        ]
+    }
+
+    /// Holds or releases the actions bound to `key`.
+    pub fn press(&self, key: Key, held: bool, input: &mut InputState) {
+        let actions = [
+            (&self.focus, &mut input.focus),
+            (&self.fire, &mut input.fire),
+            (&self.bomb, &mut input.bomb),
+            (&self.pause, &mut input.pause),
+            (&self.up, &mut input.up),
+            (&self.down, &mut input.down),
+            (&self.left, &mut input.left),
+            (&self.right, &mut input.right),
+            (&self.confirm, &mut input.confirm),
+            (&self.cancel, &mut input.cancel),
+            (&self.save_state, &mut input.save_state),
+            (&self.load_state, &mut input.load_state),
+        ];
+        for (keys, action) in actions {
+            if keys.contains(&key) {
+                *action = held;
+            }
+        }
+    }
+}
+
+/// What the player can change, kept in `settings.ron` in the user data
+/// directory. The command line overrides it for one session.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub video: Video,
+    pub volumes: Volumes,
+    pub keys: KeyBindings,
+    pub difficulty: Difficulty,
+    /// Draws the hitbox on top of the player sprite while focused.
+    pub show_hitbox: bool,
+    /// Written on the hi-score table.
+    pub name: String,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            video: Video::default(),
+            volumes: Volumes::default(),
+            keys: KeyBindings::default(),
+            difficulty: Difficulty::default(),
+            show_hitbox: true,
+            name: "PLAYER".to_string(),
+        }
+    }
+}
+
+impl Settings {
+    /// Where the settings are stored, inside the user data directory.
+    pub fn path() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("smup").join("settings.ron"))
+    }
+
+    /// Loads the settings, the defaults if there are none yet.
+    pub fn load() -> Result<Settings, Box<dyn Error>> {
+        let path = match Settings::path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(Settings::default()),
+        };
+        let settings: Settings = ron::de::from_str(&fs::read_to_string(&path)?)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        settings
+            .validate()
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(settings)
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = Settings::path().ok_or("no data directory for settings")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Makes `change` to these settings and to the saved ones, which know
+    /// nothing of the command line, and saves those.
+    pub fn change(&mut self, change: impl Fn(&mut Settings)) -> Result<(), Box<dyn Error>> {
+        change(self);
+        let mut saved = Settings::load()?;
+        change(&mut saved);
+        saved.save()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let video = &self.video;
+        for (side, size) in [("width", video.width), ("height", video.height)] {
+            if !(MIN_SIZE..=MAX_SIZE).contains(&size) {
+                return Err(format!(
+                    "the {} must be between {} and {}, not {}",
+                    side, MIN_SIZE, MAX_SIZE, size
+                ));
+            }
+        }
+        if video.ups == 0 || video.ups % UPS != 0 || video.ups > MAX_UPS {
+            return Err(format!(
+                "ups must be a multiple of {} up to {}, not {}: the game steps {} times a second",
+                UPS, MAX_UPS, video.ups, UPS
+            ));
+        }
+        if video.max_fps == 0 {
+            return Err("max_fps must be at least 1".to_string());
+        }
+        let volumes = self.volumes;
+        for (name, channel) in [
+            ("master", Channel::Master),
+            ("music", Channel::Music),
+            ("sound", Channel::Sfx),
+        ] {
+            let volume = volumes.get(channel);
+            if !(0. ..=1.).contains(&volume) {
+                return Err(format!(
+                    "the {} volume must be between 0 and 1, not {}",
+                    name, volume
+                ));
+            }
+        }
+        let name = self.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME {
+            return Err(format!(
+                "the name must be 1 to {} characters, not {:?}",
+                MAX_NAME, self.name
+            ));
+        }
+        let mut keys = self.keys.clone();
+        for (action, keys) in keys.actions() {
+            let optional = ["save_state", "load_state", "debug", "inspect"].contains(&action);
+            if keys.is_empty() && !optional {
+                return Err(format!("no key is bound to {}", action));
+            }
+        }
+        Ok(())
+    }
+
+    /// Overrides the settings with the flags of the command line, which
+    /// are not saved:
+    ///
+    /// `[--resolution WxH] [--fullscreen|--windowed] [--vsync|--no-vsync]
+    /// [--ups N] [--fps N] [--volume master|music|sound=PERCENT]
+    /// [--bind ACTION=KEY[,KEY...]] [--difficulty easy|normal|hard|lunatic]
+    /// [--name NAME]`
+    ///
+    /// Other flags are left alone.
+    pub fn apply_args(&mut self, args: &[String]) -> Result<(), String> {
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .map(String::as_str)
+                    .ok_or_else(|| format!("{} takes a value", flag))
+            };
+            match flag.as_str() {
+                "--resolution" => {
+                    let resolution = value()?;
+                    let (width, height) = resolution
+                        .split_once('x')
+                        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                        .ok_or_else(|| {
+                            format!(
+                                "--resolution takes a size like 1280x960, not {}",
+                                resolution
+                            )
+                        })?;
+                    self.video.width = width;
+                    self.video.height = height;
+                }
+                "--fullscreen" => self.video.fullscreen = true,
+                "--windowed" => self.video.fullscreen = false,
+                "--vsync" => self.video.vsync = true,
+                "--no-vsync" => self.video.vsync = false,
+                "--ups" => self.video.ups = number(flag, value()?)?,
+                "--fps" => self.video.max_fps = number(flag, value()?)?,
+                "--volume" => {
+                    let (name, percent) = value()?
+                        .split_once('=')
+                        .ok_or("--volume takes a channel and a percentage, like music=70")?;
+                    let channel = match name {
+                        "master" => Channel::Master,
+                        "music" => Channel::Music,
+                        "sound" => Channel::Sfx,
+                        _ => return Err(format!("no volume named {}", name)),
+                    };
+                    let percent: f32 = number(flag, percent)?;
+                    if !(0. ..=100.).contains(&percent) {
+                        return Err(format!("--volume goes from 0 to 100, not {}", percent));
+                    }
+                    self.volumes.set(channel, percent / 100.);
+                }
+                "--bind" => {
+                    let (action, names) = value()?
+                        .split_once('=')
+                        .ok_or("--bind takes an action and its keys, like fire=Space,Z")?;
+                    let keys = names
+                        .split(',')
+                        .map(|name| {
+                            ron::de::from_str(name).map_err(|_| format!("no key named {}", name))
+                        })
+                        .collect::<Result<Vec<Key>, String>>()?;
+                    let mut actions = self.keys.actions();
+                    let bound = actions
+                        .iter_mut()
+                        .find(|(name, _)| *name == action)
+                        .ok_or_else(|| format!("no action named {}", action))?;
+                    *bound.1 = keys;
+                }
+                "--difficulty" => {
+                    self.difficulty = match value()? {
+                        "easy" => Difficulty::Easy,
+                        "normal" => Difficulty::Normal,
+                        "hard" => Difficulty::Hard,
+                        "lunatic" => Difficulty::Lunatic,
+                        other => return Err(format!("no difficulty named {}", other)),
+                    }
+                }
+                "--name" => self.name = value()?.to_string(),
+                _ => {}
+            }
+        }
+        self.validate()
+    }
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} takes a number, not {}", flag, value))
+}
@@ -0,0 +1,141 @@
+extern crate specs;
+
+use crate::audio::{Sfx, SfxEvent, SfxEvents};
+use crate::enemy::Enemy;
+use crate::graze::Player;
+use crate::phy::{Colliders, Sensor, SensorEvents};
+use crate::render::{Color, Pivot, Size, Sprite};
+use crate::types::{GameState, PlayField, PlayerInputs, Position, Velocity};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use specs::{Component, VecStorage};
+
+#[derive(Clone, Component, Debug, Default, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Shot {
+    pub damage: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShotConfig {
+    /// Seconds between two shots while fire is held.
+    pub period: f64,
+    pub speed: f32,
+    pub radius: f32,
+    pub damage: f32,
+    /// Extra damage for every 100 units of power.
+    pub damage_per_power: f32,
+}
+
+impl Default for ShotConfig {
+    fn default() -> ShotConfig {
+        ShotConfig {
+            period: 0.1,
+            speed: 600.,
+            radius: 4.,
+            damage: 2.,
+            damage_per_power: 1.,
+        }
+    }
+}
+
+/// Fires shots from every player while fire is held.
+pub struct ShotSystem {}
+
+impl<'a> System<'a> for ShotSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, PlayerInputs>,
+        Read<'a, ShotConfig>,
+        Read<'a, GameState>,
+        WriteStorage<'a, Player>,
+        ReadStorage<'a, Position>,
+        Read<'a, LazyUpdate>,
+        Write<'a, SfxEvents>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, inputs, cfg, gs, mut players, positions, lazy, mut sfx): Self::SystemData,
+    ) {
+        for (player, pos) in (&mut players, &positions).join() {
+            player.shot_cooldown = (player.shot_cooldown - gs.delta).max(0.);
+            if !inputs.get(player.slot).fire() || player.shot_cooldown > 0. || gs.delta <= 0. {
+                continue;
+            }
+            player.shot_cooldown = cfg.period;
+            sfx.single_write(SfxEvent::at(Sfx::PlayerShot, pos));
+            let shot = entities.create();
+            let damage = cfg.damage + cfg.damage_per_power * player.power as f32 / 100.;
+            lazy.insert(shot, Shot { damage });
+            lazy.insert(shot, Position { x: pos.x, y: pos.y });
+            lazy.insert(
+                shot,
+                Velocity {
+                    x: 0.,
+                    y: -cfg.speed,
+                },
+            );
+            lazy.insert(shot, Colliders::new(Sensor::Shot, cfg.radius));
+            lazy.insert(
+                shot,
+                Sprite {
+                    color: Color {
+                        g: 0.6,
+                        b: 1.,
+                        ..Default::default()
+                    },
+                    size: Size {
+                        w: cfg.radius,
+                        h: cfg.radius * 3.,
+                    },
+                    pivot: Pivot {
+                        x: cfg.radius / 2.,
+                        y: cfg.radius * 1.5,
+                    },
+                    region: None,
+                },
+            );
+        }
+    }
+}
+
+/// Applies shot damage to the enemies they touch and removes spent or
+/// offscreen shots.
+pub struct ShotHitSystem {}
+impl<'a> System<'a> for ShotHitSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, SensorEvents>,
+        Read<'a, PlayField>,
+        ReadStorage<'a, Shot>,
+        ReadStorage<'a, Position>,
+        WriteStorage<'a, Enemy>,
+        Write<'a, SfxEvents>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, events, field, shots, positions, mut enemies, mut sfx): Self::SystemData,
+    ) {
+        for ev in events.events.iter().filter(|ev| ev.started) {
+            if let Some((shot, enemy)) = ev.between(Sensor::Shot, Sensor::Enemy) {
+                if !entities.is_alive(shot) {
+                    continue;
+                }
+                if let (Some(s), Some(e)) = (shots.get(shot), enemies.get_mut(enemy)) {
+                    e.health -= s.damage;
+                    if let Some(pos) = positions.get(enemy) {
+                        sfx.single_write(SfxEvent::at(Sfx::Hit, pos));
+                    }
+                    let _ = entities.delete(shot);
+                }
+            }
+        }
+        for (shot, pos, _) in (&entities, &positions, &shots).join() {
+            if !field.contains(pos, 0.) {
+                let _ = entities.delete(shot);
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+extern crate specs;
+
+use crate::enemy::Enemy;
+use crate::graze::Player;
+use crate::score::Score;
+use crate::types::{GameState, Position, Velocity};
+use fnv::FnvHasher;
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use specs::world::EntitiesRes;
+use std::hash::Hasher;
+
+/// Updates per second. Every update moves the simulation by exactly `STEP`,
+/// whatever the wall clock says, so runs can be reproduced tick by tick.
+pub const UPS: u64 = 30;
+pub const STEP: f64 = 1. / UPS as f64;
+
+/// The only source of randomness of the simulation. Seeded at the start of
+/// every run so the same seed and input always give the same run.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Rng {
+    pub seed: u64,
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { seed, state: seed }
+    }
+
+    /// splitmix64
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Uniform in `[low, high)`.
+    pub fn range(&mut self, low: f32, high: f32) -> f32 {
+        low + (high - low) * self.next_f32()
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+}
+
+/// Ends the update tick: counts it and runs down the slow motion.
+pub struct ClockSystem {}
+impl<'a> System<'a> for ClockSystem {
+    type SystemData = Write<'a, GameState>;
+
+    fn run(&mut self, mut gs: Self::SystemData) {
+        gs.tick += 1;
+        if let Some((scale, left)) = gs.slow_motion {
+            let left = left - gs.delta / scale;
+            gs.slow_motion = if left > 0. { Some((scale, left)) } else { None };
+        }
+    }
+}
+
+/// Maintains the world at the end of an update tick. The ids freed so far
+/// are taken out of the allocator and freed again lowest last, so the id a
+/// new entity gets only depends on the ids in use and a snapshot can
+/// rebuild the allocator from them.
+pub fn maintain(world: &mut World) {
+    world.maintain();
+    let free = {
+        let entities = world.entities();
+        let alive = entities.join().count() as u32;
+        let mut free: Vec<Entity> = (alive..next_id(&entities))
+            .map(|_| entities.create())
+            .collect();
+        free.sort_by_key(|e| std::cmp::Reverse(e.id()));
+        free
+    };
+    world
+        .delete_entities(&free)
+        .expect("freed entities were just handed out");
+}
+
+/// One past the highest entity id handed out so far. Ids never handed out
+/// look alive, freed ones do not.
+pub fn next_id(entities: &EntitiesRes) -> u32 {
+    let mut next = entities.join().map(|e| e.id() + 1).max().unwrap_or(0);
+    while !entities.entity(next).gen().is_alive() {
+        next += 1;
+    }
+    next
+}
+
+/// Hash of the simulation state after the last tick.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Checksum {
+    pub tick: u64,
+    pub value: u64,
+}
+
+/// Hashes everything that decides how the run goes: positions, velocities,
+/// health, player state, score and the RNG. Storages are joined in entity
+/// order, which only depends on the order entities were created in.
+pub struct ChecksumSystem {}
+impl<'a> System<'a> for ChecksumSystem {
+    type SystemData = (
+        Entities<'a>,
+        Write<'a, Checksum>,
+        Read<'a, GameState>,
+        Read<'a, Score>,
+        Read<'a, Rng>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Velocity>,
+        ReadStorage<'a, Enemy>,
+        ReadStorage<'a, Player>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mut checksum, gs, score, rng, positions, velocities, enemies, players): Self::SystemData,
+    ) {
+        let mut h = FnvHasher::default();
+        for (entity, pos) in (&entities, &positions).join() {
+            h.write_u32(entity.id());
+            h.write_u32(pos.x.to_bits());
+            h.write_u32(pos.y.to_bits());
+        }
+        for (entity, vel) in (&entities, &velocities).join() {
+            h.write_u32(entity.id());
+            h.write_u32(vel.x.to_bits());
+            h.write_u32(vel.y.to_bits());
+        }
+        for (entity, enemy) in (&entities, &enemies).join() {
+            h.write_u32(entity.id());
+            h.write_u32(enemy.health.to_bits());
+        }
+        for player in players.join() {
+            h.write_u32(player.graze);
+            h.write_u32(player.meter.to_bits());
+            h.write_u32(player.power);
+            h.write_u64(player.invulnerable.to_bits());
+            h.write_u64(player.bombing.to_bits());
+            h.write_u32(player.dying.map_or(u32::MAX, |frames| frames));
+            h.write_u64(player.shot_cooldown.to_bits());
+            h.write_u8(player.bomb_held as u8);
+        }
+        h.write_u32(gs.lives);
+        h.write_u32(gs.bombs);
+        h.write_u64(score.total);
+        h.write_u64(rng.state());
+
+        checksum.tick += 1;
+        checksum.value = h.finish();
+    }
+}
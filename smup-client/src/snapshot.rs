@@ -0,0 +1,244 @@
+extern crate specs;
+
+use crate::phy::PhysicsBodies;
+use crate::registry::with_components;
+use crate::replay::Recording;
+use crate::score::Score;
+use crate::sim::{next_id, Checksum, Rng};
+use crate::stage::Stage;
+use crate::types::{GameState, InputState};
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+use specs::world::EntitiesRes;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Bumped whenever snapshots saved by older builds can not be read anymore.
+const FORMAT: u32 = 3;
+
+/// Entity ids handed out and the ones free again, in the order
+/// `sim::maintain` leaves them. New entities have to get the same ids as
+/// they would have without the snapshot, ids decide the order entities are
+/// processed in.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Allocation {
+    /// Ids below this one have been handed out.
+    next: u32,
+    /// Freed ids, the next one to be reused last.
+    free: Vec<u32>,
+}
+
+impl Allocation {
+    fn take(world: &World) -> Allocation {
+        let entities = world.entities();
+        let next = next_id(&entities);
+        let free = (0..next)
+            .rev()
+            .filter(|&id| !entities.entity(id).gen().is_alive())
+            .collect();
+        Allocation { next, free }
+    }
+
+    /// Starts the allocator over like a new run does, with every id in use
+    /// and the free ones given back in the saved order.
+    fn restore(&self, world: &mut World) {
+        *world.write_resource::<EntitiesRes>() = EntitiesRes::default();
+        let all: Vec<Entity> = (0..self.next)
+            .map(|_| world.create_entity().build())
+            .collect();
+        world.maintain();
+        let free: Vec<Entity> = self.free.iter().map(|&id| all[id as usize]).collect();
+        world
+            .delete_entities(&free)
+            .expect("freed entities were just created");
+    }
+}
+
+fn save_storage<T: Component + Clone>(world: &World) -> Vec<(u32, T)> {
+    let entities = world.entities();
+    let storage = world.read_storage::<T>();
+    (&entities, &storage)
+        .join()
+        .map(|(entity, component)| (entity.id(), component.clone()))
+        .collect()
+}
+
+fn restore_storage<T: Component>(world: &World, saved: Vec<(u32, T)>) {
+    let entities = world.entities();
+    let mut storage = world.write_storage::<T>();
+    for (id, component) in saved {
+        storage
+            .insert(entities.entity(id), component)
+            .expect("entity restored with the allocator");
+    }
+}
+
+macro_rules! components {
+    ($($field:ident: $component:ty,)*) => {
+        /// The components of every live entity, with the id of their entity.
+        #[derive(Clone, Debug, Serialize, Deserialize)]
+        struct Components {
+            $($field: Vec<(u32, $component)>,)*
+        }
+
+        impl Components {
+            fn take(world: &World) -> Components {
+                Components {
+                    $($field: save_storage::<$component>(world),)*
+                }
+            }
+
+            fn restore(self, world: &World) {
+                $(restore_storage(world, self.$field);)*
+            }
+        }
+    };
+}
+
+with_components!(components);
+
+/// The whole state of a run at the end of a tick: entities, their
+/// components, the resources the gameplay systems keep between ticks and
+/// the physics bodies. Restoring it carries on exactly like the saved run.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    format: u32,
+    /// Version of the game that took it.
+    pub version: String,
+    entities: Allocation,
+    components: Components,
+    game_state: GameState,
+    input: InputState,
+    score: Score,
+    stage: Stage,
+    rng: Rng,
+    checksum: Checksum,
+    recording: Recording,
+    bodies: PhysicsBodies,
+}
+
+impl Snapshot {
+    /// Saves the world as it is after the last tick.
+    pub fn take(world: &World) -> Snapshot {
+        Snapshot {
+            format: FORMAT,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            entities: Allocation::take(world),
+            components: Components::take(world),
+            game_state: GameState::clone(&world.fetch()),
+            input: InputState::clone(&world.fetch()),
+            score: Score::clone(&world.fetch()),
+            stage: Stage::clone(&world.fetch()),
+            rng: Rng::clone(&world.fetch()),
+            checksum: Checksum::clone(&world.fetch()),
+            recording: Recording::clone(&world.fetch()),
+            bodies: PhysicsBodies::clone(&world.fetch()),
+        }
+    }
+
+    /// Replaces the run being played with the saved one. It counts as a new
+    /// run, so systems drop what they kept from the current one.
+    pub fn restore(self, world: &mut World) {
+        world.delete_all();
+        world.maintain();
+        self.entities.restore(world);
+        self.components.restore(world);
+
+        let (exit, run) = {
+            let gs = world.fetch::<GameState>();
+            (gs.exit, gs.run)
+        };
+        world.insert(GameState {
+            exit,
+            run: run + 1,
+            ..self.game_state
+        });
+        world.insert(self.input);
+        world.insert(self.score);
+        world.insert(self.stage);
+        world.insert(self.rng);
+        world.insert(self.checksum);
+        world.insert(self.recording);
+        world.insert(PhysicsBodies {
+            restored: true,
+            ..self.bodies
+        });
+    }
+
+    /// Where save states are kept, inside the user data directory.
+    pub fn dir() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("smup").join("states"))
+    }
+
+    /// The save state used by the quick save and load keys.
+    pub fn quick_path() -> Option<PathBuf> {
+        Snapshot::dir().map(|dir| dir.join("quick.ron"))
+    }
+
+    /// Saves over the quick save state.
+    pub fn quick_save(&self) -> Result<PathBuf, Box<dyn Error>> {
+        let dir = Snapshot::dir().ok_or("no data directory for save states")?;
+        fs::create_dir_all(&dir)?;
+        let path = Snapshot::quick_path().ok_or("no data directory for save states")?;
+        self.write(&path)?;
+        Ok(path)
+    }
+
+    pub fn quick_load() -> Result<Snapshot, Box<dyn Error>> {
+        Snapshot::load(&Snapshot::quick_path().ok_or("no data directory for save states")?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, self.to_ron()?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Snapshot, Box<dyn Error>> {
+        Snapshot::from_ron(&fs::read_to_string(path)?)
+    }
+
+    pub fn to_ron(&self) -> Result<String, Box<dyn Error>> {
+        Ok(ron::ser::to_string(self)?)
+    }
+
+    pub fn from_ron(text: &str) -> Result<Snapshot, Box<dyn Error>> {
+        let snapshot: Snapshot = ron::de::from_str(text)?;
+        if snapshot.format != FORMAT {
+            return Err(format!("unsupported snapshot format {}", snapshot.format).into());
+        }
+        Ok(snapshot)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateRequest {
+    Save,
+    Load,
+}
+
+/// Save state asked for by the player, carried out between frames since it
+/// needs the whole world.
+#[derive(Debug, Default)]
+pub struct SaveStateControl {
+    pub pending: Option<StateRequest>,
+}
+
+/// Turns presses of the save and load state keys into requests.
+#[derive(Default)]
+pub struct QuickSaveSystem {
+    last: InputState,
+}
+
+impl<'a> System<'a> for QuickSaveSystem {
+    type SystemData = (Write<'a, SaveStateControl>, Read<'a, InputState>);
+
+    fn run(&mut self, (mut ctl, input): Self::SystemData) {
+        if input.save_state && !self.last.save_state {
+            ctl.pending = Some(StateRequest::Save);
+        } else if input.load_state && !self.last.load_state {
+            ctl.pending = Some(StateRequest::Load);
+        }
+        self.last = input.clone();
+    }
+}
@@ -0,0 +1,33 @@
+extern crate specs;
+
+use crate::enemy::Enemy;
+use serde::{Deserialize, Serialize};
+use specs::prelude::*;
+
+/// Progress through the stage being played.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Stage {
+    pub number: u32,
+    /// Set once every enemy of the stage has been destroyed.
+    pub cleared: bool,
+}
+
+impl Default for Stage {
+    fn default() -> Stage {
+        Stage {
+            number: 1,
+            cleared: false,
+        }
+    }
+}
+
+pub struct StageSystem {}
+impl<'a> System<'a> for StageSystem {
+    type SystemData = (Write<'a, Stage>, ReadStorage<'a, Enemy>);
+
+    fn run(&mut self, (mut stage, enemies): Self::SystemData) {
+        if !stage.cleared && enemies.is_empty() {
+            stage.cleared = true;
+        }
+    }
+}
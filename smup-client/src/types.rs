@@ -1,17 +1,228 @@
 extern crate specs;
 
+use serde::{Deserialize, Serialize};
 use specs::{Component, VecStorage};
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GameState {
     pub exit: bool,
+    /// Seconds of game time since the last update, zero while paused. Drives
+    /// everything that happens in the play field.
     pub delta: f64,
+    /// Seconds of wall clock time since the last update, for menus, fades
+    /// and anything else that keeps going while the game is paused.
+    pub real_delta: f64,
+    /// Speed of game time, 1 being normal speed.
+    pub time_scale: f64,
+    pub paused: bool,
+    /// Temporary time scale and the real seconds it lasts for, counted
+    /// down by the update ticks.
+    pub slow_motion: Option<(f64, f64)>,
+    /// Bumped by every new run, so systems can drop what they kept from the
+    /// previous one.
+    pub run: u32,
+    /// Update ticks played since the start of the run.
+    pub tick: u32,
     pub mouse_position: Position,
+    pub lives: u32,
+    pub bombs: u32,
+    pub bomb_pieces: u32,
+    pub life_pieces: u32,
+    pub difficulty: Difficulty,
 }
 
-#[derive(Default, Component, Debug)]
+impl Default for GameState {
+    fn default() -> GameState {
+        GameState {
+            exit: false,
+            delta: 0.,
+            real_delta: 0.,
+            time_scale: 1.,
+            paused: false,
+            slow_motion: None,
+            run: 0,
+            tick: 0,
+            mouse_position: Position::default(),
+            lives: 0,
+            bombs: 0,
+            bomb_pieces: 0,
+            life_pieces: 0,
+            difficulty: Difficulty::default(),
+        }
+    }
+}
+
+impl GameState {
+    /// Moves both clocks forward by `real` seconds of wall clock time.
+    pub fn advance(&mut self, real: f64) {
+        self.real_delta = real;
+        self.delta = if self.paused { 0. } else { real * self.scale() };
+    }
+
+    /// Current speed of game time.
+    pub fn scale(&self) -> f64 {
+        match self.slow_motion {
+            Some((scale, _)) => scale,
+            None => self.time_scale,
+        }
+    }
+
+    /// Runs game time at `scale` for the next `seconds` of real time.
+    pub fn slow_motion(&mut self, scale: f64, seconds: f64) {
+        self.slow_motion = Some((scale, seconds));
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Difficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+    Lunatic,
+}
+
+/// A setting that takes a different value for each difficulty.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct PerDifficulty<T> {
+    pub easy: T,
+    pub normal: T,
+    pub hard: T,
+    pub lunatic: T,
+}
+
+impl<T: Copy> PerDifficulty<T> {
+    pub fn get(&self, difficulty: Difficulty) -> T {
+        match difficulty {
+            Difficulty::Easy => self.easy,
+            Difficulty::Normal => self.normal,
+            Difficulty::Hard => self.hard,
+            Difficulty::Lunatic => self.lunatic,
+        }
+    }
+}
+
+/// Actions currently held down by the player, independent of the device
+/// that produced them.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputState {
+    pub focus: bool,
+    pub fire: bool,
+    pub bomb: bool,
+    pub pause: bool,
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+    pub confirm: bool,
+    pub cancel: bool,
+    pub save_state: bool,
+    pub load_state: bool,
+}
+
+const FOCUS: u8 = 1;
+const FIRE: u8 = 1 << 1;
+const BOMB: u8 = 1 << 2;
+
+/// The input one player gives to a tick: the buttons that matter to the
+/// gameplay and where the player moves to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct InputFrame {
+    pub buttons: u8,
+    pub x: f32,
+    pub y: f32,
+}
+
+impl InputFrame {
+    pub fn capture(input: &InputState, at: &Position) -> InputFrame {
+        let mut buttons = 0;
+        if input.focus {
+            buttons |= FOCUS;
+        }
+        if input.fire {
+            buttons |= FIRE;
+        }
+        if input.bomb {
+            buttons |= BOMB;
+        }
+        InputFrame {
+            buttons,
+            x: at.x,
+            y: at.y,
+        }
+    }
+
+    pub fn apply(&self, input: &mut InputState, at: &mut Position) {
+        input.focus = self.focus();
+        input.fire = self.fire();
+        input.bomb = self.bomb();
+        at.x = self.x;
+        at.y = self.y;
+    }
+
+    pub fn focus(&self) -> bool {
+        self.buttons & FOCUS != 0
+    }
+
+    pub fn fire(&self) -> bool {
+        self.buttons & FIRE != 0
+    }
+
+    pub fn bomb(&self) -> bool {
+        self.buttons & BOMB != 0
+    }
+}
+
+/// Input of every player for the tick being played, by player slot.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PlayerInputs {
+    pub frames: Vec<InputFrame>,
+}
+
+impl PlayerInputs {
+    pub fn get(&self, slot: usize) -> InputFrame {
+        self.frames.get(slot).cloned().unwrap_or_default()
+    }
+
+    pub fn set(&mut self, slot: usize, frame: InputFrame) {
+        if self.frames.len() <= slot {
+            self.frames.resize(slot + 1, InputFrame::default());
+        }
+        self.frames[slot] = frame;
+    }
+}
+
+/// Size of the area where the game happens, in window coordinates.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlayField {
+    pub w: f32,
+    pub h: f32,
+}
+
+impl Default for PlayField {
+    fn default() -> PlayField {
+        PlayField { w: 640., h: 480. }
+    }
+}
+
+impl PlayField {
+    pub fn contains(&self, pos: &Position, margin: f32) -> bool {
+        pos.x >= -margin && pos.y >= -margin && pos.x <= self.w + margin && pos.y <= self.h + margin
+    }
+}
+
+#[derive(Clone, Default, Component, Debug, Serialize, Deserialize)]
 #[storage(VecStorage)]
 pub struct Position {
     pub x: f32,
     pub y: f32,
 }
+
+/// Displacement per second.
+#[derive(Clone, Default, Component, Debug, Serialize, Deserialize)]
+#[storage(VecStorage)]
+pub struct Velocity {
+    pub x: f32,
+    pub y: f32,
+}